@@ -0,0 +1,140 @@
+//! Compares how requests are spread across the workers of the pool.
+//!
+//! The workers are simulated by threads sleeping for the duration of the
+//! policy evaluation: most evaluations are fast, some of them are slow.
+//! With round-robin dispatch the requests queued behind a slow evaluation
+//! have to wait for it, even when other workers are idle. With a shared
+//! queue they are picked up by the first idle worker.
+//!
+//! The latencies are reported only for the fast evaluations, these are the
+//! ones affected by the dispatch strategy.
+//!
+//! Run with `cargo bench --bench dispatch`
+
+use crossbeam_channel::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKERS: usize = 4;
+const REQUESTS: usize = 1000;
+/// One request out of `SLOW_EVERY` hits a slow policy
+const SLOW_EVERY: usize = 13;
+const FAST_EVALUATION: Duration = Duration::from_millis(1);
+const SLOW_EVALUATION: Duration = Duration::from_millis(50);
+const ARRIVAL_INTERVAL: Duration = Duration::from_millis(2);
+
+struct Request {
+    submitted_at: Instant,
+    evaluation: Duration,
+}
+
+fn evaluation(n: usize) -> Duration {
+    if n % SLOW_EVERY == 0 {
+        SLOW_EVALUATION
+    } else {
+        FAST_EVALUATION
+    }
+}
+
+fn spawn_worker(rx: Receiver<Request>, latencies_tx: Sender<Duration>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(req) = rx.recv() {
+            thread::sleep(req.evaluation);
+            if req.evaluation == FAST_EVALUATION {
+                let _ = latencies_tx.send(req.submitted_at.elapsed());
+            }
+        }
+    })
+}
+
+/// Submits the requests at a steady pace. `dispatch` receives the number
+/// of the request and returns the queue it must be sent to
+fn run(queues: Vec<Sender<Request>>, dispatch: impl Fn(usize) -> usize) {
+    let mut next_arrival = Instant::now();
+    for n in 0..REQUESTS {
+        let now = Instant::now();
+        if next_arrival > now {
+            thread::sleep(next_arrival - now);
+        }
+        next_arrival += ARRIVAL_INTERVAL;
+
+        queues[dispatch(n)]
+            .send(Request {
+                submitted_at: Instant::now(),
+                evaluation: evaluation(n),
+            })
+            .unwrap();
+    }
+}
+
+fn round_robin() -> Vec<Duration> {
+    let (latencies_tx, latencies_rx) = crossbeam_channel::unbounded();
+    let mut queues = Vec::new();
+    let mut workers = Vec::new();
+    for _ in 0..WORKERS {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        queues.push(tx);
+        workers.push(spawn_worker(rx, latencies_tx.clone()));
+    }
+    drop(latencies_tx);
+
+    run(queues, |n| n % WORKERS);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    latencies_rx.iter().collect()
+}
+
+fn shared_queue() -> Vec<Duration> {
+    let (latencies_tx, latencies_rx) = crossbeam_channel::unbounded();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| spawn_worker(rx.clone(), latencies_tx.clone()))
+        .collect();
+    drop(latencies_tx);
+
+    run(vec![tx], |_| 0);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    latencies_rx.iter().collect()
+}
+
+fn percentile(sorted_latencies: &[Duration], percentile: f64) -> Duration {
+    let index = ((sorted_latencies.len() as f64 * percentile).ceil() as usize)
+        .saturating_sub(1)
+        .min(sorted_latencies.len() - 1);
+    sorted_latencies[index]
+}
+
+/// Prints the latency percentiles and returns the p99 one
+fn report(name: &str, mut latencies: Vec<Duration>) -> Duration {
+    latencies.sort();
+    println!(
+        "{:<14} p50: {:>8.2?}  p90: {:>8.2?}  p99: {:>8.2?}  max: {:>8.2?}",
+        name,
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.90),
+        percentile(&latencies, 0.99),
+        latencies.last().unwrap(),
+    );
+    percentile(&latencies, 0.99)
+}
+
+fn main() {
+    println!(
+        "{} workers, {} requests every {:?}, 1 out of {} takes {:?} instead of {:?}",
+        WORKERS,
+        REQUESTS,
+        ARRIVAL_INTERVAL,
+        SLOW_EVERY,
+        SLOW_EVALUATION,
+        FAST_EVALUATION
+    );
+    let round_robin_p99 = report("round-robin", round_robin());
+    let shared_queue_p99 = report("shared queue", shared_queue());
+    println!(
+        "p99 latency of the shared queue: {:.1}x lower than round-robin",
+        round_robin_p99.as_secs_f64() / shared_queue_p99.as_secs_f64()
+    );
+}
@@ -1,4 +1,7 @@
+use anyhow::{anyhow, Result};
+use k8s_openapi::apimachinery::pkg::runtime::RawExtension;
 use policy_evaluator::admission_response::AdmissionResponse;
+use serde::Deserialize;
 
 #[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub(crate) struct GroupVersionKind {
@@ -30,6 +33,10 @@ pub(crate) struct AdmissionReview {
     pub response: Option<AdmissionResponse>,
 }
 
+/// The versions of the AdmissionReview API the server speaks
+pub(crate) const ADMISSION_REVIEW_VERSIONS: [&str; 2] =
+    ["admission.k8s.io/v1", "admission.k8s.io/v1beta1"];
+
 impl AdmissionReview {
     pub fn new_with_response(response: AdmissionResponse) -> Self {
         AdmissionReview {
@@ -37,12 +44,51 @@ impl AdmissionReview {
             ..Default::default()
         }
     }
+
+    /// Builds the review answering a request received using the given
+    /// version of the API. The uid of the request is echoed back, as the
+    /// API server requires
+    pub fn new_with_response_to(
+        api_version: &str,
+        request_uid: &str,
+        mut response: AdmissionResponse,
+    ) -> Self {
+        response.uid = request_uid.to_string();
+        AdmissionReview {
+            api_version: Some(api_version.to_string()),
+            ..AdmissionReview::new_with_response(response)
+        }
+    }
+
+    /// Returns the version of the API the review has been sent with.
+    /// Reviews without version are considered `admission.k8s.io/v1` ones
+    pub fn version(&self) -> Result<&str> {
+        if let Some(kind) = self.kind.as_deref() {
+            if kind != "AdmissionReview" {
+                return Err(anyhow!(
+                    "unsupported kind {}, expected AdmissionReview",
+                    kind
+                ));
+            }
+        }
+        match self.api_version.as_deref() {
+            None => Ok(ADMISSION_REVIEW_VERSIONS[0]),
+            Some(api_version) if ADMISSION_REVIEW_VERSIONS.contains(&api_version) => {
+                Ok(api_version)
+            }
+            Some(api_version) => Err(anyhow!(
+                "unsupported AdmissionReview version {}, supported versions are {}",
+                api_version,
+                ADMISSION_REVIEW_VERSIONS.join(", ")
+            )),
+        }
+    }
 }
 
 impl Default for AdmissionReview {
     fn default() -> Self {
         AdmissionReview {
-            api_version: Some(String::from("admission.k8s.io/v1")),
+            api_version: Some(String::from(ADMISSION_REVIEW_VERSIONS[0])),
             kind: Some(String::from("AdmissionReview")),
             request: None,
             response: None,
@@ -80,11 +126,212 @@ pub(crate) struct AdmissionRequest {
     pub options: Option<k8s_openapi::apimachinery::pkg::runtime::RawExtension>,
 }
 
+impl AdmissionRequest {
+    /// Builds the request creating the given Kubernetes object, as the API
+    /// server would send it. The resource is derived from the kind of the
+    /// object
+    pub fn create(uid: String, object: serde_json::Value) -> Result<AdmissionRequest> {
+        let api_version = object
+            .get("apiVersion")
+            .and_then(|api_version| api_version.as_str())
+            .ok_or_else(|| anyhow!("the object has no apiVersion"))?;
+        let kind = object
+            .get("kind")
+            .and_then(|kind| kind.as_str())
+            .ok_or_else(|| anyhow!("the object has no kind"))?;
+        let (group, version) = match api_version.split_once('/') {
+            Some((group, version)) => (group, version),
+            None => ("", api_version),
+        };
+        let metadata_field = |field: &str| {
+            object
+                .get("metadata")
+                .and_then(|metadata| metadata.get(field))
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string())
+        };
+        let kind = GroupVersionKind {
+            group: group.to_string(),
+            version: version.to_string(),
+            kind: kind.to_string(),
+        };
+        let resource = GroupVersionResource {
+            group: group.to_string(),
+            version: version.to_string(),
+            resource: resource_of_kind(&kind.kind),
+        };
+
+        Ok(AdmissionRequest {
+            uid,
+            request_kind: Some(kind.clone()),
+            request_resource: Some(resource.clone()),
+            kind,
+            resource,
+            sub_resource: None,
+            request_sub_resource: None,
+            name: metadata_field("name"),
+            namespace: metadata_field("namespace"),
+            operation: String::from("CREATE"),
+            user_info: Default::default(),
+            object: Some(RawExtension(object)),
+            old_object: None,
+            dry_run: None,
+            options: None,
+        })
+    }
+}
+
+/// Guesses the resource of a kind, like `Ingress` to `ingresses` or
+/// `NetworkPolicy` to `networkpolicies`
+fn resource_of_kind(kind: &str) -> String {
+    let kind = kind.to_lowercase();
+    if kind == "endpoints" {
+        kind
+    } else if kind.ends_with('s')
+        || kind.ends_with('x')
+        || kind.ends_with("ch")
+        || kind.ends_with("sh")
+    {
+        format!("{}es", kind)
+    } else if kind.ends_with('y')
+        && !kind.ends_with("ay")
+        && !kind.ends_with("ey")
+        && !kind.ends_with("oy")
+    {
+        format!("{}ies", kind.trim_end_matches('y'))
+    } else {
+        format!("{}s", kind)
+    }
+}
+
+/// Builds the requests creating the objects of a Kubernetes manifest, made
+/// of one or more YAML documents. The items of `List` objects are expanded
+pub(crate) fn requests_from_manifests(manifests: &str) -> Result<Vec<AdmissionRequest>> {
+    let mut objects = Vec::new();
+    for (index, document) in serde_yaml::Deserializer::from_str(manifests).enumerate() {
+        let object = serde_json::Value::deserialize(document)
+            .map_err(|e| anyhow!("document #{}: {}", index + 1, e))?;
+        match object.get("items").and_then(|items| items.as_array()) {
+            Some(items) if object.get("kind").and_then(|kind| kind.as_str()) == Some("List") => {
+                objects.extend(items.iter().cloned())
+            }
+            _ if object.is_null() => {}
+            _ => objects.push(object),
+        }
+    }
+
+    objects
+        .into_iter()
+        .enumerate()
+        .map(|(index, object)| {
+            AdmissionRequest::create(format!("manifest-{}", index), object)
+                .map_err(|e| anyhow!("object #{}: {}", index + 1, e))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::BTreeMap;
 
+    #[test]
+    fn requests_from_kubernetes_manifests() {
+        let manifests = r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: nginx
+  namespace: web
+---
+---
+apiVersion: v1
+kind: List
+items:
+  - apiVersion: networking.k8s.io/v1
+    kind: Ingress
+    metadata:
+      name: nginx
+  - apiVersion: networking.k8s.io/v1
+    kind: NetworkPolicy
+    metadata:
+      name: deny-all
+"#;
+        let requests = requests_from_manifests(manifests).unwrap();
+        assert_eq!(requests.len(), 3);
+
+        let deployment = &requests[0];
+        assert_eq!(deployment.uid, "manifest-0");
+        assert_eq!(deployment.operation, "CREATE");
+        assert_eq!(deployment.kind.group, "apps");
+        assert_eq!(deployment.kind.version, "v1");
+        assert_eq!(deployment.kind.kind, "Deployment");
+        assert_eq!(deployment.resource.resource, "deployments");
+        assert_eq!(deployment.name.as_deref(), Some("nginx"));
+        assert_eq!(deployment.namespace.as_deref(), Some("web"));
+        assert_eq!(
+            deployment.object.as_ref().unwrap().0["metadata"]["name"],
+            "nginx"
+        );
+
+        assert_eq!(requests[1].resource.resource, "ingresses");
+        assert!(requests[1].namespace.is_none());
+        assert_eq!(requests[2].resource.resource, "networkpolicies");
+
+        assert!(requests_from_manifests("kind: Pod").is_err());
+        assert!(requests_from_manifests("kind: [").is_err());
+    }
+
+    #[test]
+    fn admission_review_versions() {
+        let review = |api_version: &str| -> AdmissionReview {
+            serde_json::from_value(serde_json::json!({
+                "apiVersion": api_version,
+                "kind": "AdmissionReview",
+            }))
+            .unwrap()
+        };
+        assert_eq!(
+            review("admission.k8s.io/v1").version().unwrap(),
+            "admission.k8s.io/v1"
+        );
+        assert_eq!(
+            review("admission.k8s.io/v1beta1").version().unwrap(),
+            "admission.k8s.io/v1beta1"
+        );
+        assert!(review("admission.k8s.io/v2").version().is_err());
+        assert_eq!(
+            serde_json::from_str::<AdmissionReview>("{}")
+                .unwrap()
+                .version()
+                .unwrap(),
+            "admission.k8s.io/v1"
+        );
+        let mut review = review("admission.k8s.io/v1");
+        review.kind = Some(String::from("TokenReview"));
+        assert!(review.version().is_err());
+    }
+
+    #[test]
+    fn admission_review_responses() {
+        for api_version in ADMISSION_REVIEW_VERSIONS {
+            let review = AdmissionReview::new_with_response_to(
+                api_version,
+                "hello",
+                AdmissionResponse {
+                    allowed: true,
+                    ..Default::default()
+                },
+            );
+            let review = serde_json::to_value(&review).unwrap();
+            assert_eq!(review["apiVersion"], api_version);
+            assert_eq!(review["kind"], "AdmissionReview");
+            assert_eq!(review["response"]["uid"], "hello");
+            assert_eq!(review["response"]["allowed"], true);
+            assert!(review.get("request").is_none());
+        }
+    }
+
     #[test]
     fn good_input() {
         let input = r#"
@@ -1,11 +1,29 @@
+use itertools::Itertools;
 use policy_evaluator::admission_response::AdmissionResponse;
+use policy_evaluator::policy_evaluator::PolicyExecutionMode;
+use std::collections::HashMap;
 use std::convert::Infallible;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, span::Span, warn};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, span::Span, warn, Instrument};
 use warp::http::StatusCode;
+use warp::hyper::{body::Bytes, Body};
+use warp::Reply;
 
-use crate::admission_review::{AdmissionRequest, AdmissionReview};
-use crate::communication::EvalRequest;
+use crate::admission_review::{
+    requests_from_manifests, AdmissionRequest, AdmissionReview, ADMISSION_REVIEW_VERSIONS,
+};
+use crate::cli::LogLevelHandle;
+use crate::communication::{shed_response, EvalRequest, ValidationRequest};
+use crate::eval::parse_json_documents;
+use crate::metrics;
+use crate::policy_downloader::{FetchedPolicies, FetchedPolicy};
+use crate::server::ApiState;
+use crate::settings::{member_id, shadow_id, FailurePolicy, Policy, PolicyEntry};
 
 fn populate_span_with_admission_request_data(adm_req: &AdmissionRequest) {
     Span::current().record("kind", &adm_req.kind.kind.as_str());
@@ -76,8 +94,21 @@ struct ServerErrorResponse {
 pub(crate) async fn validation(
     policy_id: String,
     admission_review: AdmissionReview,
-    tx: mpsc::Sender<EvalRequest>,
+    state: ApiState,
 ) -> Result<impl warp::Reply, Infallible> {
+    let api_version = match admission_review.version() {
+        Ok(api_version) => api_version.to_string(),
+        Err(e) => {
+            let message = e.to_string();
+            warn!(error = message.as_str(), "Bad AdmissionReview request");
+            let error_reply = ServerErrorResponse { message };
+
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&error_reply),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
     let adm_req = match admission_review.request {
         Some(ar) => {
             debug!(admission_review = %serde_json::to_string(&ar).unwrap().as_str());
@@ -95,66 +126,697 @@ pub(crate) async fn validation(
         }
     };
     populate_span_with_admission_request_data(&adm_req);
+    let request_uid = adm_req.uid.clone();
+
+    match evaluate(
+        &policy_id,
+        ValidationRequest::AdmissionRequest(Box::new(adm_req)),
+        &state,
+    )
+    .await
+    {
+        Evaluation::Response(vr) => {
+            populate_span_with_policy_evaluation_results(&vr);
+            let admission_review =
+                AdmissionReview::new_with_response_to(&api_version, &request_uid, vr);
+            debug!(response =? admission_review, "policy evaluated");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&admission_review),
+                StatusCode::OK,
+            ))
+        }
+        Evaluation::UnknownPolicy => {
+            let message = String::from("requested policy not known");
+            warn!("{}", message);
+
+            let error_reply = ServerErrorResponse { message };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&error_reply),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    }
+}
+
+/// The decision of a policy about a raw JSON document
+#[derive(Clone, Debug, serde::Serialize)]
+struct RawValidationResponse {
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u16>,
+    /// The decoded JSONPatch mutating the document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: Option<serde_json::Value>,
+}
+
+impl From<AdmissionResponse> for RawValidationResponse {
+    fn from(response: AdmissionResponse) -> RawValidationResponse {
+        RawValidationResponse {
+            allowed: response.allowed,
+            message: response
+                .status
+                .as_ref()
+                .and_then(|status| status.message.clone()),
+            code: response.status.as_ref().and_then(|status| status.code),
+            patch: response.patch.as_ref().map(|patch| {
+                base64::decode(patch)
+                    .ok()
+                    .and_then(|patch| serde_json::from_slice(&patch).ok())
+                    .unwrap_or_else(|| serde_json::Value::String(patch.clone()))
+            }),
+        }
+    }
+}
+
+/// Evaluates an arbitrary JSON document, like a CI manifest or a Terraform
+/// plan, against a single policy. The document is given to the policy as
+/// it is
+#[tracing::instrument(
+    name = "validation_raw",
+    fields(
+        host=crate::cli::HOSTNAME.as_str(),
+        policy_id=policy_id.as_str(),
+        allowed=tracing::field::Empty,
+        mutated=tracing::field::Empty,
+        response_code=tracing::field::Empty,
+        response_message=tracing::field::Empty,
+    ),
+    skip_all)]
+pub(crate) async fn raw_validation(
+    policy_id: String,
+    document: serde_json::Value,
+    state: ApiState,
+) -> Result<impl warp::Reply, Infallible> {
+    debug!(document = %document, "raw validation request");
+
+    match evaluate(&policy_id, ValidationRequest::Raw(document), &state).await {
+        Evaluation::Response(vr) => {
+            populate_span_with_policy_evaluation_results(&vr);
+            let response = RawValidationResponse::from(vr);
+            debug!(response =? response, "policy evaluated");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::OK,
+            ))
+        }
+        Evaluation::UnknownPolicy => {
+            let message = String::from("requested policy not known");
+            warn!("{}", message);
+
+            let error_reply = ServerErrorResponse { message };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&error_reply),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    }
+}
 
+/// The outcome of the evaluation of a request
+enum Evaluation {
+    Response(AdmissionResponse),
+    UnknownPolicy,
+}
+
+/// Sends the request to the worker pool and waits for its evaluation.
+/// Requests that cannot be evaluated are answered according to the failure
+/// policy of the requested policy
+async fn evaluate(policy_id: &str, req: ValidationRequest, state: &ApiState) -> Evaluation {
     let (resp_tx, resp_rx) = oneshot::channel();
+    let deadline = state.max_queue_wait.map(|wait| Instant::now() + wait);
     let eval_req = EvalRequest {
-        policy_id,
-        req: adm_req,
+        policy_id: policy_id.to_string(),
+        req,
         resp_chan: resp_tx,
         parent_span: Span::current(),
+        deadline,
     };
-    if tx.send(eval_req).await.is_err() {
-        let message = String::from("error while sending request from API to Worker pool");
-        error!("{}", message);
-
-        let error_reply = ServerErrorResponse { message };
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&error_reply),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ));
-    }
-    let res = resp_rx.await;
-
-    match res {
-        Ok(r) => match r {
-            Some(vr) => {
-                populate_span_with_policy_evaluation_results(&vr);
-                let admission_review = AdmissionReview::new_with_response(vr);
-                debug!(response =? admission_review, "policy evaluated");
-
-                Ok(warp::reply::with_status(
-                    warp::reply::json(&admission_review),
-                    StatusCode::OK,
-                ))
+    let request_uid = eval_req.req.uid().to_string();
+    metrics::record_enqueued_request(metrics::Queue::Api);
+    let sent = match deadline {
+        Some(deadline) => {
+            match tokio::time::timeout_at(deadline.into(), state.api_tx.send(eval_req)).await {
+                Ok(sent) => sent,
+                Err(_) => {
+                    metrics::record_dequeued_request(metrics::Queue::Api);
+                    return failure_policy_response(policy_id, &state.policies, |failure_policy| {
+                        warn!(
+                            failure_policy = ?failure_policy,
+                            "evaluation queue wait exceeded, request shed"
+                        );
+                        metrics::add_shed_request(policy_id, &String::from(failure_policy.clone()));
+                        shed_response(&request_uid, policy_id, failure_policy)
+                    });
+                }
             }
-            None => {
-                let message = String::from("requested policy not known");
-                warn!("{}", message);
-
-                let error_reply = ServerErrorResponse { message };
-                Ok(warp::reply::with_status(
-                    warp::reply::json(&error_reply),
-                    StatusCode::NOT_FOUND,
-                ))
-            }
-        },
+        }
+        None => state.api_tx.send(eval_req).await,
+    };
+    if sent.is_err() {
+        metrics::record_dequeued_request(metrics::Queue::Api);
+        error!("error while sending request from API to Worker pool");
+
+        return failure_policy_response(policy_id, &state.policies, |failure_policy| {
+            failure_policy.response(
+                &request_uid,
+                policy_id,
+                "The policy server cannot evaluate requests.",
+                StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            )
+        });
+    }
+
+    match resp_rx.await {
+        Ok(Some(vr)) => Evaluation::Response(vr),
+        Ok(None) => Evaluation::UnknownPolicy,
         Err(e) => {
             error!(
                 error = e.to_string().as_str(),
                 "cannot get wasm response from channel"
             );
 
+            failure_policy_response(policy_id, &state.policies, |failure_policy| {
+                failure_policy.response(
+                    &request_uid,
+                    policy_id,
+                    "The policy evaluation did not complete.",
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                )
+            })
+        }
+    }
+}
+
+/// Builds the response of a request that has not been evaluated by the
+/// workers, according to the failure policy of the requested policy
+fn failure_policy_response(
+    policy_id: &str,
+    policies: &RwLock<HashMap<String, PolicyEntry>>,
+    build_response: impl FnOnce(&FailurePolicy) -> AdmissionResponse,
+) -> Evaluation {
+    let failure_policy = match policies.read().unwrap().get(policy_id) {
+        Some(policy) => policy.failure_policy().clone(),
+        None => return Evaluation::UnknownPolicy,
+    };
+
+    Evaluation::Response(build_response(&failure_policy))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct BatchValidationQuery {
+    /// Comma separated list of the policies evaluating the requests, all
+    /// the policies are used when not set
+    policies: Option<String>,
+}
+
+/// The result of the evaluation of one of the requests of a batch by one
+/// of the policies
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchValidationResult<'a> {
+    /// The position of the request inside of the batch
+    index: usize,
+    uid: &'a str,
+    policy_id: &'a str,
+    /// Uses the format of the responses printed by the `eval` subcommand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<AdmissionReview>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Evaluates a list of requests against one or more policies. The body is
+/// either a stream of `AdmissionReview` JSON documents or, when its content
+/// type is YAML, a Kubernetes manifest whose objects are turned into
+/// `CREATE` requests.
+///
+/// The results are streamed back as JSON lines, one for each request and
+/// policy. The evaluations go through the same queue of the admission
+/// requests, one at a time, to not starve them.
+#[tracing::instrument(
+    name = "batch_validation",
+    fields(host=crate::cli::HOSTNAME.as_str()),
+    skip_all)]
+pub(crate) async fn batch_validation(
+    query: BatchValidationQuery,
+    content_type: Option<String>,
+    body: Bytes,
+    state: ApiState,
+) -> Result<warp::reply::Response, Infallible> {
+    let policy_ids: Vec<String> = match &query.policies {
+        Some(policies) => policies
+            .split(',')
+            .map(|policy_id| policy_id.trim().to_string())
+            .filter(|policy_id| !policy_id.is_empty())
+            .collect(),
+        None => state
+            .policies
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .sorted()
+            .collect(),
+    };
+    let unknown_policies: Vec<&String> = {
+        let policies = state.policies.read().unwrap();
+        policy_ids
+            .iter()
+            .filter(|policy_id| !policies.contains_key(*policy_id))
+            .collect()
+    };
+    if !unknown_policies.is_empty() {
+        let message = format!(
+            "requested policies not known: {}",
+            unknown_policies.iter().join(", ")
+        );
+        return Ok(error_reply(message, StatusCode::NOT_FOUND));
+    }
+
+    let requests = match batch_requests(content_type.as_deref(), &body) {
+        Ok(requests) => requests,
+        Err(e) => {
+            return Ok(error_reply(
+                format!("cannot read the requests: {}", e),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    info!(
+        requests = requests.len(),
+        policies = policy_ids.len(),
+        "batch validation started"
+    );
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(
+        async move {
+            for (index, (api_version, adm_req)) in requests.into_iter().enumerate() {
+                for policy_id in &policy_ids {
+                    let (response, error) = match evaluate(
+                        policy_id,
+                        ValidationRequest::AdmissionRequest(Box::new(adm_req.clone())),
+                        &state,
+                    )
+                    .await
+                    {
+                        Evaluation::Response(response) => (
+                            Some(AdmissionReview::new_with_response_to(
+                                &api_version,
+                                &adm_req.uid,
+                                response,
+                            )),
+                            None,
+                        ),
+                        Evaluation::UnknownPolicy => {
+                            (None, Some(String::from("requested policy not known")))
+                        }
+                    };
+                    let result = BatchValidationResult {
+                        index,
+                        uid: &adm_req.uid,
+                        policy_id,
+                        response,
+                        error,
+                    };
+                    let mut line = serde_json::to_vec(&result).unwrap();
+                    line.push(b'\n');
+                    if sender.send_data(line.into()).await.is_err() {
+                        warn!("batch validation client gone, evaluation stopped");
+                        return;
+                    }
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, "application/x-ndjson")
+        .status(StatusCode::OK)
+        .body(body)
+        .unwrap())
+}
+
+/// Reads the requests of a batch validation, together with the version of
+/// the AdmissionReview they have been sent with. The requests built from
+/// Kubernetes manifests are answered using the latest version
+fn batch_requests(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> anyhow::Result<Vec<(String, AdmissionRequest)>> {
+    let body = std::str::from_utf8(body)?;
+    if content_type.map_or(false, |content_type| content_type.contains("yaml")) {
+        return Ok(requests_from_manifests(body)?
+            .into_iter()
+            .map(|req| (ADMISSION_REVIEW_VERSIONS[0].to_string(), req))
+            .collect());
+    }
+
+    parse_json_documents::<AdmissionReview>(body)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, admission_review)| {
+            let api_version = admission_review
+                .version()
+                .map_err(|e| anyhow::anyhow!("document #{}: {}", index + 1, e))?
+                .to_string();
+            let req = admission_review.request.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "document #{}: no Request object defined inside AdmissionReview object",
+                    index + 1
+                )
+            })?;
+            Ok((api_version, req))
+        })
+        .collect()
+}
+
+fn error_reply(message: String, status: StatusCode) -> warp::reply::Response {
+    warn!(error = message.as_str(), "bad batch validation request");
+    warp::reply::with_status(warp::reply::json(&ServerErrorResponse { message }), status)
+        .into_response()
+}
+
+/// Reports the server as not ready once the shutdown started, this way no
+/// new requests are sent to it while the in-flight ones are drained
+pub(crate) async fn readiness(ready: Arc<AtomicBool>) -> Result<impl warp::Reply, Infallible> {
+    if ready.load(Ordering::SeqCst) {
+        Ok(StatusCode::OK)
+    } else {
+        Ok(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+pub(crate) async fn prometheus_metrics() -> Result<impl warp::Reply, Infallible> {
+    match metrics::gather() {
+        Ok((content_type, body)) => Ok(warp::http::Response::builder()
+            .header(warp::http::header::CONTENT_TYPE, content_type)
+            .status(StatusCode::OK)
+            .body(body)
+            .unwrap()),
+        Err(e) => {
+            error!(error = e.to_string().as_str(), "cannot gather metrics");
+            Ok(warp::http::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Vec::new())
+                .unwrap())
+        }
+    }
+}
+
+pub(crate) async fn liveness() -> Result<impl warp::Reply, Infallible> {
+    Ok(StatusCode::OK)
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicyInventoryEntry {
+    /// The ids of the members of policy groups, of the steps of pipelines
+    /// and of the shadow policies are prefixed by the id of their parent
+    id: String,
+    /// Not set for policy groups and pipelines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    policy_mode: String,
+    allowed_to_mutate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_eval_seconds: Option<u64>,
+    /// Set only for shadow policies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_eval_milliseconds: Option<u64>,
+    failure_policy: String,
+    /// Set only for policy groups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expression: Option<String>,
+    /// The members of the policy groups, or the steps of the pipelines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members: Option<Vec<PolicyInventoryEntry>>,
+    /// The shadow policy evaluated alongside the policy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow: Option<Box<PolicyInventoryEntry>>,
+    /// The settings the policy is evaluated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settings: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The execution mode of the policy, read from the metadata of its
+    /// module like the workers do
+    #[serde(skip_serializing_if = "Option::is_none")]
+    execution_mode: Option<PolicyExecutionMode>,
+    /// The local path, the digest, the metadata and the verification of
+    /// the WebAssembly module
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    module: Option<FetchedPolicy>,
+}
+
+impl PolicyInventoryEntry {
+    fn new(id: &str, entry: &PolicyEntry, modules: &FetchedPolicies) -> Self {
+        match entry {
+            PolicyEntry::Policy(policy) => PolicyInventoryEntry::from_policy(id, policy, modules),
+            PolicyEntry::PolicyGroup(group) => PolicyInventoryEntry {
+                id: id.to_string(),
+                url: None,
+                policy_mode: group.policy_mode.clone().into(),
+                allowed_to_mutate: false,
+                timeout_eval_seconds: None,
+                timeout_eval_milliseconds: None,
+                failure_policy: group.failure_policy.clone().into(),
+                expression: Some(group.expression.to_string()),
+                members: Some(
+                    group
+                        .policies
+                        .iter()
+                        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                        .map(|(member, policy)| {
+                            PolicyInventoryEntry::from_policy(
+                                &member_id(id, member),
+                                policy,
+                                modules,
+                            )
+                        })
+                        .collect(),
+                ),
+                shadow: None,
+                settings: None,
+                execution_mode: None,
+                module: None,
+            },
+            PolicyEntry::PolicyPipeline(pipeline) => PolicyInventoryEntry {
+                id: id.to_string(),
+                url: None,
+                policy_mode: pipeline.policy_mode.clone().into(),
+                allowed_to_mutate: pipeline
+                    .pipeline
+                    .iter()
+                    .any(|step| step.policy.allowed_to_mutate.unwrap_or(false)),
+                timeout_eval_seconds: None,
+                timeout_eval_milliseconds: None,
+                failure_policy: pipeline.failure_policy.clone().into(),
+                expression: None,
+                members: Some(
+                    pipeline
+                        .pipeline
+                        .iter()
+                        .map(|step| {
+                            PolicyInventoryEntry::from_policy(
+                                &member_id(id, &step.name),
+                                &step.policy,
+                                modules,
+                            )
+                        })
+                        .collect(),
+                ),
+                shadow: None,
+                settings: None,
+                execution_mode: None,
+                module: None,
+            },
+        }
+    }
+
+    // Describes a single policy, together with its shadow if any
+    fn from_policy(id: &str, policy: &Policy, modules: &FetchedPolicies) -> Self {
+        let module = modules.get(&policy.url).cloned();
+        let shadow = policy.shadow.as_ref().zip(policy.shadow_policy());
+        PolicyInventoryEntry {
+            id: id.to_string(),
+            url: Some(policy.url.clone()),
+            policy_mode: policy.policy_mode.clone().into(),
+            allowed_to_mutate: policy.allowed_to_mutate.unwrap_or(false),
+            timeout_eval_seconds: policy.timeout_eval_seconds,
+            timeout_eval_milliseconds: None,
+            failure_policy: policy.failure_policy.clone().into(),
+            expression: None,
+            members: None,
+            shadow: shadow.map(|(shadow, shadow_policy)| {
+                let mut entry =
+                    PolicyInventoryEntry::from_policy(&shadow_id(id), &shadow_policy, modules);
+                entry.timeout_eval_milliseconds = Some(shadow.time_budget().as_millis() as u64);
+                Box::new(entry)
+            }),
+            settings: policy.settings_to_json().ok().flatten(),
+            execution_mode: module
+                .as_ref()
+                .map(|module| module.metadata.clone().unwrap_or_default().execution_mode),
+            module,
+        }
+    }
+}
+
+pub(crate) async fn policies_inventory(
+    policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+    modules: Arc<RwLock<FetchedPolicies>>,
+) -> Result<impl warp::Reply, Infallible> {
+    let modules = modules.read().unwrap();
+    let mut inventory: Vec<PolicyInventoryEntry> = policies
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(id, entry)| PolicyInventoryEntry::new(id, entry, &modules))
+        .collect();
+    inventory.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(warp::reply::json(&inventory))
+}
+
+pub(crate) async fn policy_inventory(
+    policy_id: String,
+    policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+    modules: Arc<RwLock<FetchedPolicies>>,
+) -> Result<impl warp::Reply, Infallible> {
+    match policies.read().unwrap().get(&policy_id) {
+        Some(entry) => {
+            let entry = PolicyInventoryEntry::new(&policy_id, entry, &modules.read().unwrap());
+            Ok(warp::reply::with_status(
+                warp::reply::json(&entry),
+                StatusCode::OK,
+            ))
+        }
+        None => {
             let error_reply = ServerErrorResponse {
-                message: String::from("broken channel"),
+                message: String::from("requested policy not known"),
             };
             Ok(warp::reply::with_status(
                 warp::reply::json(&error_reply),
-                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::NOT_FOUND,
             ))
         }
     }
 }
 
-pub(crate) async fn readiness() -> Result<impl warp::Reply, Infallible> {
-    Ok(StatusCode::OK)
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct LogLevel {
+    pub level: String,
+}
+
+pub(crate) async fn get_log_level(
+    log_level_handle: LogLevelHandle,
+) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&LogLevel {
+        level: log_level_handle.level(),
+    }))
+}
+
+pub(crate) async fn set_log_level(
+    log_level: LogLevel,
+    log_level_handle: LogLevelHandle,
+) -> Result<impl warp::Reply, Infallible> {
+    match log_level_handle.set_level(&log_level.level) {
+        Ok(_) => {
+            info!(level = log_level.level.as_str(), "log level changed");
+            Ok(warp::reply::with_status(
+                warp::reply::json(&log_level),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            let error_reply = ServerErrorResponse {
+                message: e.to_string(),
+            };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&error_reply),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn policy_inventory_entries() {
+        let input = r#"
+---
+psp-capabilities:
+  url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.1.7
+  shadow:
+    url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0
+    timeoutEvalMilliseconds: 250
+group:
+  policies:
+    privileged:
+      url: registry://ghcr.io/kubewarden/policies/pod-privileged:v0.2.2
+    capabilities:
+      url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.1.7
+  expression: "privileged() && capabilities()"
+pipeline:
+  pipeline:
+    - name: capabilities
+      url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(input).unwrap();
+        let modules: FetchedPolicies = [
+            ("pod-privileged:v0.2.2", "digest-privileged"),
+            ("psp-capabilities:v0.1.7", "digest-v0.1.7"),
+            ("psp-capabilities:v0.2.0", "digest-v0.2.0"),
+        ]
+        .iter()
+        .map(|(policy, digest)| {
+            (
+                format!("registry://ghcr.io/kubewarden/policies/{}", policy),
+                FetchedPolicy {
+                    local_path: PathBuf::from(policy),
+                    digest: Some(digest.to_string()),
+                    metadata: None,
+                    verification: None,
+                },
+            )
+        })
+        .collect();
+        let inventory = |id: &str| {
+            serde_json::to_value(PolicyInventoryEntry::new(id, &policies[id], &modules)).unwrap()
+        };
+
+        let policy = inventory("psp-capabilities");
+        assert_eq!(policy["digest"], "digest-v0.1.7");
+        let shadow = &policy["shadow"];
+        assert_eq!(shadow["id"], "psp-capabilities/shadow");
+        assert_eq!(
+            shadow["url"],
+            "registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0"
+        );
+        assert_eq!(shadow["digest"], "digest-v0.2.0");
+        assert_eq!(shadow["timeoutEvalMilliseconds"], 250);
+        assert!(shadow["executionMode"].is_string());
+
+        let group = inventory("group");
+        assert!(group.get("url").is_none());
+        let members = group["members"].as_array().unwrap();
+        let ids: Vec<&str> = members.iter().map(|m| m["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["group/capabilities", "group/privileged"]);
+        assert_eq!(members[1]["digest"], "digest-privileged");
+        assert_eq!(members[1]["localPath"], "pod-privileged:v0.2.2");
+
+        let pipeline = inventory("pipeline");
+        let step = &pipeline["members"][0];
+        assert_eq!(step["id"], "pipeline/capabilities");
+        assert_eq!(step["digest"], "digest-v0.2.0");
+        assert!(step["executionMode"].is_string());
+    }
 }
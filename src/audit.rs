@@ -0,0 +1,744 @@
+//! Structured audit records of the decisions taken by the policies.
+//!
+//! The workers push the records matching the audit filter into a bounded
+//! queue, without waiting. The queue is drained by a tokio task writing the
+//! records to the configured sink as JSON lines. Records are dropped when
+//! the queue is full or when the sink cannot be written.
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::authentication::v1::UserInfo;
+use lazy_static::lazy_static;
+use policy_evaluator::admission_response::AdmissionResponse;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{error, info, warn};
+use warp::hyper::{self, client::conn::SendRequest, Body, Request, Uri};
+
+use crate::admission_review::{AdmissionRequest, GroupVersionKind};
+use crate::exemptions::Pattern;
+use crate::settings::PolicyMode;
+
+/// Maximum number of records written to the sink at once
+const MAX_BATCH_SIZE: usize = 100;
+
+lazy_static! {
+    /// Set when auditing is enabled
+    static ref AUDIT_QUEUE: RwLock<Option<Arc<AuditQueue>>> = RwLock::new(None);
+}
+
+struct AuditQueue {
+    filter: Vec<AuditRule>,
+    tx: mpsc::Sender<AuditRecord>,
+    /// Number of records dropped because the queue was full, since the
+    /// writer last reported them
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditQueue {
+    /// Only the first record dropped is logged, the following ones are
+    /// counted and reported by the writer
+    fn send(&self, record: AuditRecord) {
+        if self.tx.try_send(record).is_err() && self.dropped.fetch_add(1, Ordering::SeqCst) == 0 {
+            warn!("audit queue full, records are being dropped");
+        }
+    }
+}
+
+fn default_queue_size() -> usize {
+    1024
+}
+
+fn default_max_size_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+fn default_webhook_timeout_seconds() -> u64 {
+    10
+}
+
+/// By default the decisions of the policies in monitor mode and the
+/// rejections are recorded
+fn default_filter() -> Vec<AuditRule> {
+    vec![
+        AuditRule {
+            policy_modes: vec![PolicyMode::Monitor],
+            ..Default::default()
+        },
+        AuditRule {
+            verdicts: vec![Verdict::Rejected],
+            ..Default::default()
+        },
+    ]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub sink: AuditSinkConfig,
+    /// A decision is recorded when it matches one of these rules
+    #[serde(default = "default_filter")]
+    pub filter: Vec<AuditRule>,
+    /// Number of records waiting to be written, the new ones are dropped
+    /// when the queue is full
+    #[serde(default = "default_queue_size", rename = "queueSize")]
+    pub queue_size: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuditSinkConfig {
+    Stdout,
+    /// The file is rotated once it reaches `max_size_bytes`: `path` is
+    /// renamed to `path.1`, `path.1` to `path.2` and so on, up to
+    /// `max_files` rotated files
+    File {
+        path: PathBuf,
+        #[serde(default = "default_max_size_bytes", rename = "maxSizeBytes")]
+        max_size_bytes: u64,
+        #[serde(default = "default_max_files", rename = "maxFiles")]
+        max_files: usize,
+    },
+    /// The records are sent with POST requests, as JSON lines. The
+    /// certificate of `https` endpoints is verified using `ca_file`. A
+    /// request taking more than `timeout_seconds`, connection included,
+    /// is abandoned and its records are dropped
+    Webhook {
+        url: String,
+        #[serde(rename = "caFile")]
+        ca_file: Option<String>,
+        #[serde(default = "default_webhook_timeout_seconds", rename = "timeoutSeconds")]
+        timeout_seconds: u64,
+    },
+}
+
+/// The verdict of a policy, `mutated` requests are also `accepted`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Accepted,
+    Rejected,
+    Mutated,
+}
+
+/// Decisions matching all the criteria set by a rule are recorded. Each
+/// criteria is matched when one of its values matches
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuditRule {
+    #[serde(default)]
+    pub policies: Vec<Pattern>,
+    #[serde(default, rename = "policyModes")]
+    pub policy_modes: Vec<PolicyMode>,
+    #[serde(default)]
+    pub verdicts: Vec<Verdict>,
+}
+
+impl AuditRule {
+    fn matches(
+        &self,
+        policy_id: &str,
+        policy_mode: &PolicyMode,
+        response: &AdmissionResponse,
+    ) -> bool {
+        (self.policies.is_empty() || self.policies.iter().any(|p| p.matches(policy_id)))
+            && (self.policy_modes.is_empty() || self.policy_modes.contains(policy_mode))
+            && (self.verdicts.is_empty()
+                || self.verdicts.iter().any(|verdict| match verdict {
+                    Verdict::Accepted => response.allowed,
+                    Verdict::Rejected => !response.allowed,
+                    Verdict::Mutated => response.patch.is_some(),
+                }))
+    }
+}
+
+/// A decision taken by a policy
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AuditRecord {
+    /// Milliseconds elapsed since the Unix epoch
+    timestamp: u128,
+    uid: String,
+    user_info: UserInfo,
+    kind: GroupVersionKind,
+    namespace: Option<String>,
+    name: Option<String>,
+    operation: String,
+    policy_id: String,
+    policy_mode: String,
+    #[serde(flatten)]
+    decision: Decision,
+    /// The decision of the shadow of the policy, set only when the two
+    /// policies disagree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow: Option<Decision>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Decision {
+    allowed: bool,
+    message: Option<String>,
+    /// The decoded JSONPatch, or the raw one when it cannot be decoded
+    patch: Option<serde_json::Value>,
+}
+
+impl From<&AdmissionResponse> for Decision {
+    fn from(response: &AdmissionResponse) -> Decision {
+        Decision {
+            allowed: response.allowed,
+            message: response
+                .status
+                .as_ref()
+                .and_then(|status| status.message.clone()),
+            patch: response.patch.as_ref().map(|patch| {
+                base64::decode(patch)
+                    .ok()
+                    .and_then(|patch| serde_json::from_slice(&patch).ok())
+                    .unwrap_or_else(|| serde_json::Value::String(patch.clone()))
+            }),
+        }
+    }
+}
+
+impl AuditRecord {
+    fn new(
+        policy_id: &str,
+        policy_mode: &PolicyMode,
+        req: &AdmissionRequest,
+        response: &AdmissionResponse,
+    ) -> AuditRecord {
+        AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis()),
+            uid: req.uid.clone(),
+            user_info: req.user_info.clone(),
+            kind: req.kind.clone(),
+            namespace: req.namespace.clone(),
+            name: req.name.clone(),
+            operation: req.operation.clone(),
+            policy_id: policy_id.to_string(),
+            policy_mode: policy_mode.clone().into(),
+            decision: response.into(),
+            shadow: None,
+        }
+    }
+}
+
+/// Reads the audit configuration file
+pub fn read_audit_config(path: &Path) -> Result<AuditConfig> {
+    let config_file = File::open(path)?;
+    let config: AuditConfig = serde_yaml::from_reader(&config_file)?;
+    if config.queue_size == 0 {
+        return Err(anyhow!("queueSize must be greater than 0"));
+    }
+    match &config.sink {
+        AuditSinkConfig::Stdout => {}
+        AuditSinkConfig::File {
+            max_size_bytes,
+            max_files,
+            ..
+        } => {
+            if *max_size_bytes == 0 || *max_files == 0 {
+                return Err(anyhow!("maxSizeBytes and maxFiles must be greater than 0"));
+            }
+        }
+        AuditSinkConfig::Webhook {
+            url,
+            ca_file,
+            timeout_seconds,
+        } => {
+            if *timeout_seconds == 0 {
+                return Err(anyhow!("timeoutSeconds must be greater than 0"));
+            }
+            Webhook::new(url, ca_file.as_deref(), *timeout_seconds)?;
+        }
+    }
+
+    Ok(config)
+}
+
+/// Enables auditing. The returned writer must be run to drain the queue
+/// of the records
+pub(crate) fn init(config: AuditConfig) -> AuditWriter {
+    let (tx, rx) = mpsc::channel(config.queue_size);
+    let dropped = Arc::new(AtomicU64::new(0));
+    *AUDIT_QUEUE.write().unwrap() = Some(Arc::new(AuditQueue {
+        filter: config.filter,
+        tx,
+        dropped: dropped.clone(),
+    }));
+
+    AuditWriter {
+        sink: config.sink,
+        rx,
+        dropped,
+    }
+}
+
+/// Records the decision of the policy when it matches the audit filter.
+/// The response is the one of the policy, before the policy mode is applied
+pub(crate) fn record(
+    policy_id: &str,
+    policy_mode: &PolicyMode,
+    req: &AdmissionRequest,
+    response: &AdmissionResponse,
+) {
+    let queue = match AUDIT_QUEUE.read().unwrap().as_ref() {
+        Some(queue) => queue.clone(),
+        None => return,
+    };
+    if !queue
+        .filter
+        .iter()
+        .any(|rule| rule.matches(policy_id, policy_mode, response))
+    {
+        return;
+    }
+    queue.send(AuditRecord::new(policy_id, policy_mode, req, response));
+}
+
+/// Records the decisions of a policy and of its shadow, which disagree.
+/// These records are not subject to the audit filter
+pub(crate) fn record_shadow_disagreement(
+    policy_id: &str,
+    policy_mode: &PolicyMode,
+    req: &AdmissionRequest,
+    response: &AdmissionResponse,
+    shadow_response: &AdmissionResponse,
+) {
+    let queue = match AUDIT_QUEUE.read().unwrap().as_ref() {
+        Some(queue) => queue.clone(),
+        None => return,
+    };
+    queue.send(AuditRecord {
+        shadow: Some(shadow_response.into()),
+        ..AuditRecord::new(policy_id, policy_mode, req, response)
+    });
+}
+
+pub(crate) struct AuditWriter {
+    sink: AuditSinkConfig,
+    rx: mpsc::Receiver<AuditRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditWriter {
+    pub(crate) async fn run(mut self) {
+        let mut sink = match Sink::new(&self.sink).await {
+            Ok(sink) => sink,
+            Err(e) => {
+                error!(error = e.to_string().as_str(), "cannot open audit sink");
+                return;
+            }
+        };
+        info!(status = "init", "audit writer");
+
+        while let Some(record) = self.rx.recv().await {
+            let mut batch = vec![record];
+            while batch.len() < MAX_BATCH_SIZE {
+                match self.rx.try_recv() {
+                    Ok(record) => batch.push(record),
+                    Err(_) => break,
+                }
+            }
+
+            let mut lines = Vec::new();
+            for record in &batch {
+                match serde_json::to_vec(record) {
+                    Ok(line) => {
+                        lines.extend(line);
+                        lines.push(b'\n');
+                    }
+                    Err(e) => error!(
+                        error = e.to_string().as_str(),
+                        "cannot serialize audit record"
+                    ),
+                }
+            }
+            if let Err(e) = sink.write(lines).await {
+                error!(
+                    error = e.to_string().as_str(),
+                    records = batch.len(),
+                    "cannot write audit records, records dropped"
+                );
+            }
+            let dropped = self.dropped.swap(0, Ordering::SeqCst);
+            if dropped > 0 {
+                warn!(records = dropped, "audit queue full, records dropped");
+            }
+        }
+        info!(status = "exit", "audit writer");
+    }
+}
+
+enum Sink {
+    Stdout(tokio::io::Stdout),
+    File(FileSink),
+    Webhook(Webhook),
+}
+
+impl Sink {
+    async fn new(config: &AuditSinkConfig) -> Result<Sink> {
+        Ok(match config {
+            AuditSinkConfig::Stdout => Sink::Stdout(tokio::io::stdout()),
+            AuditSinkConfig::File {
+                path,
+                max_size_bytes,
+                max_files,
+            } => Sink::File(FileSink::open(path.clone(), *max_size_bytes, *max_files).await?),
+            AuditSinkConfig::Webhook {
+                url,
+                ca_file,
+                timeout_seconds,
+            } => Sink::Webhook(Webhook::new(url, ca_file.as_deref(), *timeout_seconds)?),
+        })
+    }
+
+    async fn write(&mut self, lines: Vec<u8>) -> Result<()> {
+        match self {
+            Sink::Stdout(stdout) => {
+                stdout.write_all(&lines).await?;
+                stdout.flush().await?;
+            }
+            Sink::File(file) => file.write(&lines).await?,
+            Sink::Webhook(webhook) => webhook.post(lines).await?,
+        }
+
+        Ok(())
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    max_size_bytes: u64,
+    max_files: usize,
+    file: tokio::fs::File,
+    size: u64,
+}
+
+impl FileSink {
+    async fn open(path: PathBuf, max_size_bytes: u64, max_files: usize) -> Result<FileSink> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(|e| anyhow!("cannot open {:?}: {}", path, e))?;
+        let size = file.metadata().await?.len();
+
+        Ok(FileSink {
+            path,
+            max_size_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if tokio::fs::metadata(&from).await.is_ok() {
+                tokio::fs::rename(&from, self.rotated_path(index + 1)).await?;
+            }
+        }
+        tokio::fs::rename(&self.path, self.rotated_path(1)).await?;
+        *self = FileSink::open(self.path.clone(), self.max_size_bytes, self.max_files).await?;
+
+        Ok(())
+    }
+
+    async fn write(&mut self, lines: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + lines.len() as u64 > self.max_size_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(lines).await?;
+        self.file.flush().await?;
+        self.size += lines.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Posts the records to the webhook endpoint. The connection is kept open
+/// across the batches, a new one is opened only once it is closed or after
+/// a failure
+struct Webhook {
+    uri: Uri,
+    host: String,
+    port: u16,
+    tls: Option<(TlsConnector, ServerName)>,
+    timeout: Duration,
+    sender: Option<SendRequest<Body>>,
+}
+
+impl Webhook {
+    fn new(url: &str, ca_file: Option<&str>, timeout_seconds: u64) -> Result<Webhook> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|e| anyhow!("invalid webhook url {}: {}", url, e))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("the webhook url {} has no host", url))?
+            .to_string();
+        let tls = match uri.scheme_str() {
+            Some("http") => None,
+            Some("https") => {
+                let ca_file = ca_file
+                    .ok_or_else(|| anyhow!("the caFile of the https webhook {} is not set", url))?;
+                let mut roots = RootCertStore::empty();
+                for certificate in crate::tls::load_certificates(ca_file)? {
+                    roots.add(&certificate)?;
+                }
+                let config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let server_name = ServerName::try_from(host.as_str())
+                    .map_err(|e| anyhow!("invalid webhook host {}: {}", host, e))?;
+                Some((TlsConnector::from(Arc::new(config)), server_name))
+            }
+            _ => return Err(anyhow!("the webhook url {} must be http or https", url)),
+        };
+        let port = uri
+            .port_u16()
+            .unwrap_or(if tls.is_some() { 443 } else { 80 });
+
+        Ok(Webhook {
+            uri,
+            host,
+            port,
+            tls,
+            timeout: Duration::from_secs(timeout_seconds),
+            sender: None,
+        })
+    }
+
+    async fn post(&mut self, lines: Vec<u8>) -> Result<()> {
+        let timeout = self.timeout;
+        let status = match tokio::time::timeout(timeout, self.send(lines)).await {
+            Ok(Ok(status)) => status,
+            Ok(Err(e)) => {
+                self.sender = None;
+                return Err(e);
+            }
+            Err(_) => {
+                self.sender = None;
+                return Err(anyhow!(
+                    "the webhook did not answer within {} seconds",
+                    timeout.as_secs()
+                ));
+            }
+        };
+        if !status.is_success() {
+            return Err(anyhow!("the webhook answered with {}", status));
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, lines: Vec<u8>) -> Result<hyper::StatusCode> {
+        let request = Request::post(self.uri.clone())
+            .header(hyper::header::HOST, self.host.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(lines))?;
+
+        // The connection kept open could have been closed by the endpoint
+        let reusable = match self.sender.as_mut() {
+            Some(sender) => std::future::poll_fn(|cx| sender.poll_ready(cx))
+                .await
+                .is_ok(),
+            None => false,
+        };
+        if !reusable {
+            self.sender = Some(self.connect().await?);
+        }
+        let sender = self.sender.as_mut().unwrap();
+        let response = sender.send_request(request).await?;
+
+        Ok(response.status())
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        match &self.tls {
+            Some((connector, server_name)) => {
+                handshake(connector.connect(server_name.clone(), tcp).await?).await
+            }
+            None => handshake(tcp).await,
+        }
+    }
+}
+
+async fn handshake<T>(io: T) -> Result<SendRequest<Body>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::handshake(io).await?;
+    tokio::spawn(connection);
+
+    Ok(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> AdmissionRequest {
+        serde_json::from_value(serde_json::json!({
+            "uid": "uid",
+            "kind": {"group": "", "version": "v1", "kind": "Pod"},
+            "resource": {"group": "", "version": "v1", "resource": "pods"},
+            "name": "nginx",
+            "namespace": "default",
+            "operation": "CREATE",
+            "userInfo": {"username": "admin"},
+        }))
+        .unwrap()
+    }
+
+    fn rejection() -> AdmissionResponse {
+        AdmissionResponse::reject("uid".to_string(), "not allowed".to_string(), 400)
+    }
+
+    #[test]
+    fn audit_filter() {
+        let accepted = AdmissionResponse {
+            uid: "uid".to_string(),
+            allowed: true,
+            ..Default::default()
+        };
+        let matches = |filter: &[AuditRule], mode: PolicyMode, response: &AdmissionResponse| {
+            filter
+                .iter()
+                .any(|rule| rule.matches("psp-capabilities", &mode, response))
+        };
+
+        let filter = default_filter();
+        assert!(matches(&filter, PolicyMode::Monitor, &accepted));
+        assert!(matches(&filter, PolicyMode::Protect, &rejection()));
+        assert!(!matches(&filter, PolicyMode::Protect, &accepted));
+
+        let filter: Vec<AuditRule> =
+            serde_yaml::from_str("[{policies: ['psp-*'], verdicts: [accepted]}]").unwrap();
+        assert!(matches(&filter, PolicyMode::Protect, &accepted));
+        assert!(!matches(&filter, PolicyMode::Protect, &rejection()));
+        let filter: Vec<AuditRule> = serde_yaml::from_str("[{policies: [namespace-*]}]").unwrap();
+        assert!(!matches(&filter, PolicyMode::Protect, &accepted));
+    }
+
+    #[test]
+    fn audit_record() {
+        let response = AdmissionResponse {
+            uid: "uid".to_string(),
+            allowed: true,
+            patch: Some(base64::encode(
+                r#"[{"op": "add", "path": "/metadata/labels", "value": {}}]"#,
+            )),
+            patch_type: Some("JSONPatch".to_string()),
+            ..Default::default()
+        };
+        let record = serde_json::to_value(AuditRecord::new(
+            "psp-capabilities",
+            &PolicyMode::Monitor,
+            &request(),
+            &response,
+        ))
+        .unwrap();
+        assert_eq!(record["uid"], "uid");
+        assert_eq!(record["userInfo"]["username"], "admin");
+        assert_eq!(record["kind"]["kind"], "Pod");
+        assert_eq!(record["namespace"], "default");
+        assert_eq!(record["name"], "nginx");
+        assert_eq!(record["policyId"], "psp-capabilities");
+        assert_eq!(record["policyMode"], "monitor");
+        assert_eq!(record["allowed"], true);
+        assert_eq!(record["patch"][0]["op"], "add");
+
+        let record = serde_json::to_value(AuditRecord::new(
+            "psp-capabilities",
+            &PolicyMode::Protect,
+            &request(),
+            &rejection(),
+        ))
+        .unwrap();
+        assert_eq!(record["allowed"], false);
+        assert_eq!(record["message"], "not allowed");
+        assert!(record["patch"].is_null());
+        assert!(record.get("shadow").is_none());
+    }
+
+    #[test]
+    fn audit_config() {
+        let config: AuditConfig =
+            serde_yaml::from_str("sink: {type: file, path: /tmp/audit.jsonl}").unwrap();
+        assert_eq!(
+            config.sink,
+            AuditSinkConfig::File {
+                path: PathBuf::from("/tmp/audit.jsonl"),
+                max_size_bytes: default_max_size_bytes(),
+                max_files: default_max_files(),
+            }
+        );
+        assert_eq!(config.filter.len(), 2);
+
+        assert!(Webhook::new("http://audit.kubewarden:8080/records", None, 10).is_ok());
+        assert!(Webhook::new("https://audit.kubewarden/records", None, 10).is_err());
+        assert!(Webhook::new("ftp://audit.kubewarden/records", None, 10).is_err());
+
+        let config: AuditConfig =
+            serde_yaml::from_str("sink: {type: webhook, url: 'http://audit.kubewarden/records'}")
+                .unwrap();
+        assert_eq!(
+            config.sink,
+            AuditSinkConfig::Webhook {
+                url: "http://audit.kubewarden/records".to_string(),
+                ca_file: None,
+                timeout_seconds: default_webhook_timeout_seconds(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn file_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut sink = FileSink::open(path.clone(), 10, 2).await.unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            sink.write(line.as_bytes()).await.unwrap();
+        }
+
+        let read = |suffix: &str| {
+            let mut path = path.clone().into_os_string();
+            path.push(suffix);
+            std::fs::read_to_string(path).ok()
+        };
+        assert_eq!(read("").as_deref(), Some("fourth\n"));
+        assert_eq!(read(".1").as_deref(), Some("third\n"));
+        assert_eq!(read(".2").as_deref(), Some("second\n"));
+        assert_eq!(read(".3"), None);
+    }
+}
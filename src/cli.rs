@@ -1,7 +1,11 @@
-use crate::settings::{read_policies_file, Policy};
+use crate::audit::{read_audit_config, AuditConfig};
+use crate::exemptions::{read_exemptions_file, Exemption};
+use crate::settings::{read_policies_file, PolicyEntry};
 use anyhow::{anyhow, Result};
 use clap::builder::PossibleValue;
-use clap::{crate_authors, crate_description, crate_name, crate_version, Arg, Command};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, Arg, ArgAction, ArgGroup, Command,
+};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use policy_evaluator::burrego;
@@ -9,12 +13,20 @@ use policy_evaluator::policy_fetcher::{
     sources::{read_sources_file, Sources},
     verify::config::{read_verification_file, LatestVerificationConfig},
 };
-use std::{collections::HashMap, env, net::SocketAddr, path::Path};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
 
 static SERVICE_NAME: &str = "kubewarden-policy-server";
 const DOCKER_CONFIG_ENV_VAR: &str = "DOCKER_CONFIG";
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
 
 lazy_static! {
     static ref VERSION_AND_BUILTINS: String = {
@@ -45,13 +57,8 @@ pub(crate) fn build_cli() -> Command {
                 .value_name("LOG_LEVEL")
                 .env("KUBEWARDEN_LOG_LEVEL")
                 .default_value("info")
-                .value_parser([
-                    PossibleValue::new("trace"),
-                    PossibleValue::new("debug"),
-                    PossibleValue::new("info"),
-                    PossibleValue::new("warn"),
-                    PossibleValue::new("error"),
-                ])
+                .value_parser(LOG_LEVELS)
+                .global(true)
                 .help("Log level"),
         )
         .arg(
@@ -72,6 +79,7 @@ pub(crate) fn build_cli() -> Command {
                 .long("log-no-color")
                 .env("NO_COLOR")
                 .required(false)
+                .global(true)
                 .help("Disable colored output for logs"),
         )
         .arg(
@@ -90,6 +98,21 @@ pub(crate) fn build_cli() -> Command {
                 .env("KUBEWARDEN_PORT")
                 .help("Listen on PORT"),
         )
+        .arg(
+            Arg::new("admin-address")
+                .long("admin-addr")
+                .value_name("BIND_ADDRESS")
+                .default_value("0.0.0.0")
+                .env("KUBEWARDEN_ADMIN_BIND_ADDRESS")
+                .help("Bind the admin endpoints against ADDRESS"),
+        )
+        .arg(
+            Arg::new("admin-port")
+                .long("admin-port")
+                .value_name("PORT")
+                .env("KUBEWARDEN_ADMIN_PORT")
+                .help("Serve the admin endpoints (readiness, liveness, metrics, policies and log level) over plain HTTP on PORT. Disabled when not provided"),
+        )
         .arg(
             Arg::new("workers")
                 .long("workers")
@@ -97,6 +120,29 @@ pub(crate) fn build_cli() -> Command {
                 .env("KUBEWARDEN_WORKERS")
                 .help("Number of workers thread to create"),
         )
+        .arg(
+            Arg::new("api-queue-size")
+                .long("api-queue-size")
+                .value_name("REQUESTS")
+                .env("KUBEWARDEN_API_QUEUE_SIZE")
+                .default_value("32")
+                .help("Number of requests received by the API that can wait to be dispatched to the workers"),
+        )
+        .arg(
+            Arg::new("worker-queue-size")
+                .long("worker-queue-size")
+                .value_name("REQUESTS")
+                .env("KUBEWARDEN_WORKER_QUEUE_SIZE")
+                .default_value("32")
+                .help("Number of requests that can wait for an idle worker, for each worker"),
+        )
+        .arg(
+            Arg::new("max-queue-wait")
+                .long("max-queue-wait")
+                .value_name("MILLISECONDS")
+                .env("KUBEWARDEN_MAX_QUEUE_WAIT")
+                .help("How long a request can wait for a worker. Once exceeded, the request is rejected or accepted according to the failurePolicy of the policy. Requests wait indefinitely when not provided"),
+        )
         .arg(
             Arg::new("cert-file")
                 .long("cert-file")
@@ -113,20 +159,62 @@ pub(crate) fn build_cli() -> Command {
                 .env("KUBEWARDEN_KEY_FILE")
                 .help("Path to an X.509 private key file for HTTPS"),
         )
+        .arg(
+            Arg::new("client-ca-file")
+                .long("client-ca-file")
+                .value_name("CLIENT_CA_FILE")
+                .env("KUBEWARDEN_CLIENT_CA_FILE")
+                .help("Path to a PEM bundle of CA certificates. When provided, HTTPS clients must present a certificate signed by one of them"),
+        )
+        .arg(
+            Arg::new("client-allowed-names")
+                .long("client-allowed-names")
+                .value_name("NAMES")
+                .env("KUBEWARDEN_CLIENT_ALLOWED_NAMES")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Comma separated list of client certificate subjects and alternative names allowed to use the server. Requires --client-ca-file"),
+        )
         .arg(
             Arg::new("policies")
                 .long("policies")
                 .value_name("POLICIES_FILE")
                 .env("KUBEWARDEN_POLICIES")
                 .default_value("policies.yml")
+                .global(true)
                 .help("YAML file holding the policies to be loaded and their settings"),
         )
+        .arg(
+            Arg::new("policies-watch-interval")
+                .long("policies-watch-interval")
+                .value_name("SECONDS")
+                .env("KUBEWARDEN_POLICIES_WATCH_INTERVAL")
+                .default_value("5")
+                .help("How often the policies file is checked for changes. Use 0 to reload policies only on SIGHUP"),
+        )
+        .arg(
+            Arg::new("shutdown-grace-period")
+                .long("shutdown-grace-period")
+                .value_name("SECONDS")
+                .env("KUBEWARDEN_SHUTDOWN_GRACE_PERIOD")
+                .default_value("20")
+                .help("How long in-flight requests are given to complete once SIGTERM or SIGINT is received"),
+        )
+        .arg(
+            Arg::new("shutdown-readiness-delay")
+                .long("shutdown-readiness-delay")
+                .value_name("SECONDS")
+                .env("KUBEWARDEN_SHUTDOWN_READINESS_DELAY")
+                .default_value("5")
+                .help("How long new connections are still accepted, with the readiness probe failing, once SIGTERM or SIGINT is received"),
+        )
         .arg(
             Arg::new("policies-download-dir")
                 .long("policies-download-dir")
                 .value_name("POLICIES_DOWNLOAD_DIR")
                 .default_value(".")
                 .env("KUBEWARDEN_POLICIES_DOWNLOAD_DIR")
+                .global(true)
                 .help("Download path for the policies"),
         )
         .arg(
@@ -135,6 +223,7 @@ pub(crate) fn build_cli() -> Command {
                 .value_name("SIGSTORE_CACHE_DIR")
                 .default_value("sigstore-data")
                 .env("KUBEWARDEN_SIGSTORE_CACHE_DIR")
+                .global(true)
                 .help("Directory used to cache sigstore data"),
         )
         .arg(
@@ -142,6 +231,7 @@ pub(crate) fn build_cli() -> Command {
                 .long("sources-path")
                 .value_name("SOURCES_PATH")
                 .env("KUBEWARDEN_SOURCES_PATH")
+                .global(true)
                 .help("YAML file holding source information (https, registry insecure hosts, custom CA's...)"),
         )
         .arg(
@@ -149,6 +239,7 @@ pub(crate) fn build_cli() -> Command {
                 .long("verification-path")
                 .value_name("VERIFICATION_CONFIG_PATH")
                 .env("KUBEWARDEN_VERIFICATION_CONFIG_PATH")
+                .global(true)
                 .help("YAML file holding verification information (URIs, keys, annotations...)"),
         )
         .arg(
@@ -156,6 +247,7 @@ pub(crate) fn build_cli() -> Command {
                 .long("docker-config-json-path")
                 .value_name("DOCKER_CONFIG")
                 .env("KUBEWARDEN_DOCKER_CONFIG_JSON_PATH")
+                .global(true)
                 .help("Path to a Docker config.json-like path. Can be used to indicate registry authentication details"),
         )
         .arg(
@@ -165,6 +257,33 @@ pub(crate) fn build_cli() -> Command {
                 .required(false)
                 .help("Enable metrics"),
         )
+        .arg(
+            Arg::new("metrics-exporter")
+                .long("metrics-exporter")
+                .value_name("EXPORTER")
+                .env("KUBEWARDEN_METRICS_EXPORTER")
+                .default_value("otlp")
+                .value_parser([PossibleValue::new("otlp"), PossibleValue::new("prometheus")])
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("How metrics are exported: pushed to an OpenTelemetry collector (otlp), scraped by Prometheus (prometheus), or both"),
+        )
+        .arg(
+            Arg::new("metrics-address")
+                .long("metrics-addr")
+                .value_name("BIND_ADDRESS")
+                .default_value("0.0.0.0")
+                .env("KUBEWARDEN_METRICS_BIND_ADDRESS")
+                .help("Serve the Prometheus metrics on ADDRESS"),
+        )
+        .arg(
+            Arg::new("metrics-port")
+                .long("metrics-port")
+                .value_name("PORT")
+                .default_value("8080")
+                .env("KUBEWARDEN_METRICS_PORT")
+                .help("Serve the Prometheus metrics on PORT"),
+        )
         .arg(
             Arg::new("enable-verification")
                 .long("enable-verification")
@@ -180,6 +299,94 @@ pub(crate) fn build_cli() -> Command {
                 .required(false)
                 .help("Always accept AdmissionReviews that target the given namespace"),
         )
+        .arg(
+            Arg::new("exemptions")
+                .long("exemptions")
+                .value_name("EXEMPTIONS_FILE")
+                .env("KUBEWARDEN_EXEMPTIONS")
+                .required(false)
+                .help("YAML file holding the exemptions applying to all the policies"),
+        )
+        .arg(
+            Arg::new("audit-config")
+                .long("audit-config")
+                .value_name("AUDIT_CONFIG_FILE")
+                .env("KUBEWARDEN_AUDIT_CONFIG")
+                .required(false)
+                .help("YAML file holding the configuration of the audit records"),
+        )
+        .arg(
+            Arg::new("audit-annotations-prefix")
+                .long("audit-annotations-prefix")
+                .value_name("PREFIX")
+                .env("KUBEWARDEN_AUDIT_ANNOTATIONS_PREFIX")
+                .default_value("kubewarden")
+                .help("Prefix of the audit annotations telling which policy made the decision, an empty prefix disables them"),
+        )
+        .arg(
+            Arg::new("policy-timeout")
+                .long("policy-timeout")
+                .value_name("MAXIMUM_EXECUTION_TIME_SECONDS")
+                .env("KUBEWARDEN_POLICY_TIMEOUT")
+                .default_value("2")
+                .global(true)
+                .help("Interrupt policy evaluation after the given time"),
+        )
+        .arg(
+            Arg::new("disable-timeout-protection")
+                .long("disable-timeout-protection")
+                .env("KUBEWARDEN_DISABLE_TIMEOUT_PROTECTION")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Disable policy timeout protection"),
+        )
+        .subcommand(
+            Command::new("eval")
+                .about("Evaluate AdmissionReview objects against the configured policies, without starting the server")
+                .arg(
+                    Arg::new("request-path")
+                        .long("request-path")
+                        .short('r')
+                        .value_name("REQUEST_FILE")
+                        .default_value("-")
+                        .help("File holding the AdmissionReview objects to evaluate, either as JSON documents or as JSON lines. Use '-' to read from stdin"),
+                )
+                .arg(
+                    Arg::new("policy-id")
+                        .long("policy-id")
+                        .value_name("POLICY_ID")
+                        .action(ArgAction::Append)
+                        .help("Evaluate only the given policy, can be repeated. All the policies are evaluated by default"),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Replay recorded AdmissionReview objects and report the responses that changed")
+                .arg(
+                    Arg::new("corpus")
+                        .long("corpus")
+                        .value_name("CORPUS_FILE")
+                        .required(true)
+                        .help("JSON lines file holding the recorded AdmissionReview objects. Use '-' to read from stdin"),
+                )
+                .arg(
+                    Arg::new("candidate-policies")
+                        .long("candidate-policies")
+                        .value_name("POLICIES_FILE")
+                        .help("YAML file holding the policies to compare against the ones loaded via --policies"),
+                )
+                .arg(
+                    Arg::new("expected-responses")
+                        .long("expected-responses")
+                        .value_name("RESPONSES_FILE")
+                        .help("JSON lines file, in the format produced by the eval subcommand, holding the responses the policies are expected to give"),
+                )
+                .group(
+                    ArgGroup::new("baseline")
+                        .args(["candidate-policies", "expected-responses"])
+                        .required(true),
+                ),
+        )
         .long_version(VERSION_AND_BUILTINS.as_str())
 }
 
@@ -193,6 +400,32 @@ pub(crate) fn api_bind_address(matches: &clap::ArgMatches) -> Result<SocketAddr>
     .map_err(|e| anyhow!("error parsing arguments: {}", e))
 }
 
+pub(crate) fn metrics_bind_address(matches: &clap::ArgMatches) -> Result<SocketAddr> {
+    format!(
+        "{}:{}",
+        matches.get_one::<String>("metrics-address").unwrap(),
+        matches.get_one::<String>("metrics-port").unwrap()
+    )
+    .parse()
+    .map_err(|e| anyhow!("error parsing arguments: {}", e))
+}
+
+/// Returns the address of the admin endpoints, `None` when they are
+/// disabled
+pub(crate) fn admin_bind_address(matches: &clap::ArgMatches) -> Result<Option<SocketAddr>> {
+    match matches.get_one::<String>("admin-port") {
+        None => Ok(None),
+        Some(port) => format!(
+            "{}:{}",
+            matches.get_one::<String>("admin-address").unwrap(),
+            port
+        )
+        .parse()
+        .map(Some)
+        .map_err(|e| anyhow!("error parsing arguments: {}", e)),
+    }
+}
+
 pub(crate) fn tls_files(matches: &clap::ArgMatches) -> Result<(String, String)> {
     let cert_file = matches.get_one::<String>("cert-file").unwrap().to_owned();
     let key_file = matches.get_one::<String>("key-file").unwrap().to_owned();
@@ -203,7 +436,112 @@ pub(crate) fn tls_files(matches: &clap::ArgMatches) -> Result<(String, String)>
     }
 }
 
-pub(crate) fn policies(matches: &clap::ArgMatches) -> Result<HashMap<String, Policy>> {
+/// Returns the client CA bundle and the allowed client names used to
+/// authenticate HTTPS clients
+pub(crate) fn client_auth(matches: &clap::ArgMatches) -> Result<(Option<String>, Vec<String>)> {
+    let client_ca_file = matches.get_one::<String>("client-ca-file").cloned();
+    let allowed_client_names: Vec<String> = matches
+        .get_many::<String>("client-allowed-names")
+        .map(|names| names.cloned().collect())
+        .unwrap_or_default();
+
+    if client_ca_file.is_some() && matches.get_one::<String>("cert-file").unwrap().is_empty() {
+        return Err(anyhow!(
+            "error parsing arguments: --client-ca-file requires --cert-file and --key-file"
+        ));
+    }
+    if client_ca_file.is_none() && !allowed_client_names.is_empty() {
+        return Err(anyhow!(
+            "error parsing arguments: --client-allowed-names requires --client-ca-file"
+        ));
+    }
+
+    Ok((client_ca_file, allowed_client_names))
+}
+
+/// Returns the maximum number of seconds a policy evaluation can take,
+/// `None` when timeout protection is disabled
+pub(crate) fn policy_evaluation_limit_seconds(matches: &clap::ArgMatches) -> Result<Option<u64>> {
+    if matches.get_flag("disable-timeout-protection") {
+        return Ok(None);
+    }
+
+    matches
+        .get_one::<String>("policy-timeout")
+        .unwrap()
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|e| anyhow!("error parsing policy-timeout: {}", e))
+}
+
+/// Returns how often the policies file must be checked for changes,
+/// `None` when this check is disabled
+pub(crate) fn policies_watch_interval(matches: &clap::ArgMatches) -> Result<Option<Duration>> {
+    let seconds = matches
+        .get_one::<String>("policies-watch-interval")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|e| anyhow!("error parsing policies-watch-interval: {}", e))?;
+
+    Ok(if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    })
+}
+
+/// Returns the sizes of the API queue and of the workers queue, the latter
+/// is given for each worker
+pub(crate) fn queue_sizes(matches: &clap::ArgMatches) -> Result<(usize, usize)> {
+    let size = |arg: &str| -> Result<usize> {
+        matches
+            .get_one::<String>(arg)
+            .unwrap()
+            .parse::<usize>()
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| anyhow!("{} must be a positive number", arg))
+    };
+
+    Ok((size("api-queue-size")?, size("worker-queue-size")?))
+}
+
+/// Returns how long a request can wait for a worker, `None` when requests
+/// are never shed
+pub(crate) fn max_queue_wait(matches: &clap::ArgMatches) -> Result<Option<Duration>> {
+    matches
+        .get_one::<String>("max-queue-wait")
+        .map(|millis| {
+            millis
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|e| anyhow!("error parsing max-queue-wait: {}", e))
+        })
+        .transpose()
+}
+
+/// Returns how long the in-flight requests are waited for when shutting down
+pub(crate) fn shutdown_grace_period(matches: &clap::ArgMatches) -> Result<Duration> {
+    matches
+        .get_one::<String>("shutdown-grace-period")
+        .unwrap()
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| anyhow!("error parsing shutdown-grace-period: {}", e))
+}
+
+/// Returns how long new connections are still accepted once the readiness
+/// probe started failing, when shutting down
+pub(crate) fn shutdown_readiness_delay(matches: &clap::ArgMatches) -> Result<Duration> {
+    matches
+        .get_one::<String>("shutdown-readiness-delay")
+        .unwrap()
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| anyhow!("error parsing shutdown-readiness-delay: {}", e))
+}
+
+pub(crate) fn policies(matches: &clap::ArgMatches) -> Result<HashMap<String, PolicyEntry>> {
     let policies_file = Path::new(matches.get_one::<String>("policies").unwrap());
     read_policies_file(policies_file).map_err(|e| {
         anyhow!(
@@ -214,6 +552,71 @@ pub(crate) fn policies(matches: &clap::ArgMatches) -> Result<HashMap<String, Pol
     })
 }
 
+/// The exemptions applying to all the policies, including the one of the
+/// `always-accept-admission-reviews-on-namespace` flag
+pub(crate) fn exemptions(matches: &clap::ArgMatches) -> Result<Vec<Exemption>> {
+    let mut exemptions = match matches.get_one::<String>("exemptions") {
+        None => Vec::new(),
+        Some(path) => {
+            let exemptions_file = Path::new(path);
+            read_exemptions_file(exemptions_file).map_err(|e| {
+                anyhow!(
+                    "error while loading exemptions from {:?}: {}",
+                    exemptions_file,
+                    e
+                )
+            })?
+        }
+    };
+    if let Some(namespace) =
+        matches.get_one::<String>("always-accept-admission-reviews-on-namespace")
+    {
+        exemptions.push(Exemption::namespace(namespace)?);
+    }
+
+    Ok(exemptions)
+}
+
+/// The prefix of the audit annotations added to the responses, `None` when
+/// they are disabled. The prefix must be usable inside of the name of an
+/// annotation
+pub(crate) fn audit_annotations_prefix(matches: &clap::ArgMatches) -> Result<Option<String>> {
+    let prefix = matches
+        .get_one::<String>("audit-annotations-prefix")
+        .cloned()
+        .unwrap_or_default();
+    if prefix.is_empty() {
+        return Ok(None);
+    }
+    if !prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(anyhow!(
+            "invalid audit annotations prefix {}: only alphanumeric characters, '-', '_' and '.' are allowed",
+            prefix
+        ));
+    }
+
+    Ok(Some(prefix))
+}
+
+pub(crate) fn audit_config(matches: &clap::ArgMatches) -> Result<Option<AuditConfig>> {
+    match matches.get_one::<String>("audit-config") {
+        None => Ok(None),
+        Some(path) => {
+            let config_file = Path::new(path);
+            read_audit_config(config_file).map(Some).map_err(|e| {
+                anyhow!(
+                    "error while loading the audit configuration from {:?}: {}",
+                    config_file,
+                    e
+                )
+            })
+        }
+    }
+}
+
 pub(crate) fn verification_config(
     matches: &clap::ArgMatches,
 ) -> Result<Option<LatestVerificationConfig>> {
@@ -226,20 +629,45 @@ pub(crate) fn verification_config(
     }
 }
 
+/// Changes the log level of the tracing system at runtime
+#[derive(Clone)]
+pub(crate) struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+    level: Arc<RwLock<String>>,
+}
+
+impl LogLevelHandle {
+    pub fn level(&self) -> String {
+        self.level.read().unwrap().clone()
+    }
+
+    pub fn set_level(&self, level: &str) -> Result<()> {
+        if !LOG_LEVELS.contains(&level) {
+            return Err(anyhow!(
+                "unknown log level {}, valid values are: {}",
+                level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        self.handle
+            .reload(tracing_filter(level))
+            .map_err(|e| anyhow!("cannot change log level: {}", e))?;
+        *self.level.write().unwrap() = level.to_string();
+
+        Ok(())
+    }
+}
+
 // Setup the tracing system. This MUST be done inside of a tokio Runtime
 // because some collectors rely on it and would panic otherwise.
-pub(crate) fn setup_tracing(matches: &clap::ArgMatches) -> Result<()> {
+pub(crate) fn setup_tracing(matches: &clap::ArgMatches) -> Result<LogLevelHandle> {
     // setup logging
-    let filter_layer = EnvFilter::new(matches.get_one::<String>("log-level").unwrap())
-        // some of our dependencies generate trace events too, but we don't care about them ->
-        // let's filter them
-        .add_directive("cranelift_codegen=off".parse().unwrap())
-        .add_directive("cranelift_wasm=off".parse().unwrap())
-        .add_directive("wasmtime_cranelift=off".parse().unwrap())
-        .add_directive("regalloc=off".parse().unwrap())
-        .add_directive("hyper=off".parse().unwrap())
-        .add_directive("h2=off".parse().unwrap())
-        .add_directive("tower=off".parse().unwrap());
+    let level = matches.get_one::<String>("log-level").unwrap();
+    let (filter_layer, handle) = reload::Layer::new(tracing_filter(level));
+    let log_level_handle = LogLevelHandle {
+        handle,
+        level: Arc::new(RwLock::new(level.to_owned())),
+    };
 
     match matches.get_one::<String>("log-fmt").unwrap().as_str() {
         "json" => tracing_subscriber::registry()
@@ -283,7 +711,36 @@ pub(crate) fn setup_tracing(matches: &clap::ArgMatches) -> Result<()> {
         _ => return Err(anyhow!("Unknown log message format")),
     };
 
-    Ok(())
+    Ok(log_level_handle)
+}
+
+/// Setup a tracing system that writes plain text logs to the standard
+/// error. Used by the subcommands that print their results to the
+/// standard output
+pub(crate) fn setup_stderr_tracing(matches: &clap::ArgMatches) {
+    tracing_subscriber::registry()
+        .with(tracing_filter(
+            matches.get_one::<String>("log-level").unwrap(),
+        ))
+        .with(
+            fmt::layer()
+                .with_ansi(!matches.contains_id("log-no-color"))
+                .with_writer(std::io::stderr),
+        )
+        .init()
+}
+
+fn tracing_filter(level: &str) -> EnvFilter {
+    EnvFilter::new(level)
+        // some of our dependencies generate trace events too, but we don't care about them ->
+        // let's filter them
+        .add_directive("cranelift_codegen=off".parse().unwrap())
+        .add_directive("cranelift_wasm=off".parse().unwrap())
+        .add_directive("wasmtime_cranelift=off".parse().unwrap())
+        .add_directive("regalloc=off".parse().unwrap())
+        .add_directive("hyper=off".parse().unwrap())
+        .add_directive("h2=off".parse().unwrap())
+        .add_directive("tower=off".parse().unwrap())
 }
 
 pub(crate) fn remote_server_options(matches: &clap::ArgMatches) -> Result<Option<Sources>> {
@@ -1,24 +1,74 @@
 use anyhow::Result;
 use policy_evaluator::admission_response::AdmissionResponse;
 use std::collections::HashMap;
+use std::time::Instant;
 use tokio::sync::oneshot;
 
 use crate::admission_review::AdmissionRequest;
 use crate::policy_downloader::FetchedPolicies;
-use crate::settings::Policy;
+use crate::settings::{FailurePolicy, PolicyEntry};
+
+/// The request evaluated by the policies
+#[derive(Debug)]
+pub(crate) enum ValidationRequest {
+    /// A request sent by the Kubernetes API server
+    AdmissionRequest(Box<AdmissionRequest>),
+    /// An arbitrary JSON document, given to the policy as it is
+    Raw(serde_json::Value),
+}
+
+impl ValidationRequest {
+    /// The uid of the request. Raw requests have one only when the
+    /// document has a top level `uid` string
+    pub fn uid(&self) -> &str {
+        match self {
+            ValidationRequest::AdmissionRequest(req) => &req.uid,
+            ValidationRequest::Raw(json) => json
+                .get("uid")
+                .and_then(|uid| uid.as_str())
+                .unwrap_or_default(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct EvalRequest {
     pub policy_id: String,
-    pub req: AdmissionRequest,
+    pub req: ValidationRequest,
     pub resp_chan: oneshot::Sender<Option<AdmissionResponse>>,
     pub parent_span: tracing::Span,
+    /// When set, the request is shed if its evaluation did not start
+    /// before this instant
+    pub deadline: Option<Instant>,
+}
+
+impl EvalRequest {
+    /// Returns true when the request waited for a worker longer than allowed
+    pub fn expired(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Builds the response of a request that has been shed because the
+/// evaluation queue is full
+pub(crate) fn shed_response(
+    request_uid: &str,
+    policy_id: &str,
+    failure_policy: &FailurePolicy,
+) -> AdmissionResponse {
+    failure_policy.response(
+        request_uid,
+        policy_id,
+        "The policy server is overloaded.",
+        warp::http::StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+    )
 }
 
 /// Holds the bootstrap parameters of a worker pool
 pub(crate) struct WorkerPoolBootRequest {
     /// list of policies to load into each worker
-    pub policies: HashMap<String, Policy>,
+    pub policies: HashMap<String, PolicyEntry>,
 
     /// Locations of the WebAssembly modules on the local disk
     pub fetched_policies: FetchedPolicies,
@@ -26,12 +76,32 @@ pub(crate) struct WorkerPoolBootRequest {
     /// size of the worker pool
     pub pool_size: usize,
 
+    /// number of requests that can wait for an idle worker, for each
+    /// worker of the pool
+    pub queue_size_per_worker: usize,
+
     /// channel used to send back bootstrap status:
     /// * Ok(()) -> all good
     /// * Err(e) -> one or more workers couldn't bootstrap
     pub resp_chan: oneshot::Sender<Result<()>>,
 }
 
+/// Holds the data required by the worker pool to replace the policies
+/// currently loaded
+pub(crate) struct WorkerPoolReloadRequest {
+    /// new list of policies to load into each worker
+    pub policies: HashMap<String, PolicyEntry>,
+
+    /// Locations of the WebAssembly modules on the local disk
+    pub fetched_policies: FetchedPolicies,
+
+    /// channel used to send back reload status:
+    /// * Ok(()) -> all good, workers are going to use the new policies
+    /// * Err(e) -> the new policies cannot be loaded, the previous ones
+    ///   are still in use
+    pub resp_chan: oneshot::Sender<Result<()>>,
+}
+
 // Holds the bootstrap parameters of a kube pooler
 pub(crate) struct KubePollerBootRequest {
     // channel used to send back bootstrap status:
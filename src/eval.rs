@@ -0,0 +1,382 @@
+use anyhow::{anyhow, Result};
+use policy_evaluator::admission_response::AdmissionResponse;
+use policy_evaluator::callback_handler::CallbackHandlerBuilder;
+use policy_evaluator::policy_fetcher::{
+    sigstore, sources::Sources, verify::config::LatestVerificationConfig,
+    verify::FulcioAndRekorData,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    thread,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot},
+    task::{spawn_blocking, JoinHandle},
+};
+use tracing::{info, span::Span, warn};
+
+use crate::admission_review::{AdmissionRequest, AdmissionReview};
+use crate::cli;
+use crate::communication::{
+    EvalRequest, ValidationRequest, WorkerPoolBootRequest, WorkerPoolReloadRequest,
+};
+use crate::policy_downloader::Downloader;
+use crate::settings::{self, PolicyEntry};
+use crate::worker_pool::WorkerPool;
+
+/// The response given by a policy to an AdmissionReview. The `eval`
+/// subcommand prints one of these objects per line
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EvaluationResult {
+    pub policy_id: String,
+    pub response: AdmissionReview,
+}
+
+/// The settings required to download and evaluate policies outside of
+/// the server
+pub(crate) struct EvaluatorSettings {
+    pub sources: Option<Sources>,
+    pub verification_config: Option<LatestVerificationConfig>,
+    pub sigstore_cache_dir: PathBuf,
+    pub policies_download_dir: String,
+    pub policy_evaluation_limit_seconds: Option<u64>,
+}
+
+impl EvaluatorSettings {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<EvaluatorSettings> {
+        Ok(EvaluatorSettings {
+            sources: cli::remote_server_options(matches)?,
+            verification_config: cli::verification_config(matches)
+                .map_err(|e| anyhow!("Cannot create sigstore verification config: {:?}", e))?,
+            sigstore_cache_dir: matches
+                .get_one::<String>("sigstore-cache-dir")
+                .map(PathBuf::from)
+                .expect("This should not happen, there's a default value for sigstore-cache-dir"),
+            policies_download_dir: matches
+                .get_one::<String>("policies-download-dir")
+                .unwrap()
+                .to_owned(),
+            policy_evaluation_limit_seconds: cli::policy_evaluation_limit_seconds(matches)?,
+        })
+    }
+}
+
+/// Evaluates AdmissionRequests without starting the web server.
+///
+/// The requests are handled by the same worker pool used by the server,
+/// hence the responses are exactly the ones the server would give. The
+/// Kubernetes poller is not started: context aware policies do not have
+/// access to the cluster resources.
+pub(crate) struct OfflineEvaluator {
+    api_tx: mpsc::Sender<EvalRequest>,
+    worker_pool_thread: thread::JoinHandle<()>,
+    callback_handler_shutdown_tx: oneshot::Sender<()>,
+    callback_handler_task: JoinHandle<()>,
+}
+
+impl OfflineEvaluator {
+    /// Downloads the given policies and bootstraps a worker pool
+    /// that evaluates them
+    pub async fn new(
+        settings: &EvaluatorSettings,
+        policies: HashMap<String, PolicyEntry>,
+    ) -> Result<OfflineEvaluator> {
+        let mut downloader = Downloader::new(
+            settings.sources.clone(),
+            settings.verification_config.is_some(),
+            Some(settings.sigstore_cache_dir.clone()),
+        )
+        .await?;
+        let fetched_policies = downloader
+            .download_policies(
+                &settings::policies_to_fetch(&policies),
+                &settings.policies_download_dir,
+                settings.verification_config.as_ref(),
+            )
+            .await?;
+
+        let fulcio_and_rekor_data =
+            match spawn_blocking(|| sigstore::tuf::SigstoreRepository::fetch(None)).await? {
+                Ok(repo) => Some(FulcioAndRekorData::FromTufRepository { repo }),
+                Err(e) => {
+                    warn!(
+                        error = ?e,
+                        "cannot fetch TUF repository, sigstore capabilities are going to be limited"
+                    );
+                    None
+                }
+            };
+        let (callback_handler_shutdown_tx, callback_handler_shutdown_rx) = oneshot::channel();
+        let mut callback_handler = CallbackHandlerBuilder::default()
+            .registry_config(settings.sources.clone())
+            .shutdown_channel(callback_handler_shutdown_rx)
+            .fulcio_and_rekor_data(fulcio_and_rekor_data.as_ref())
+            .build()?;
+        let callback_handler_tx = callback_handler.sender_channel();
+        let callback_handler_task = tokio::spawn(async move {
+            callback_handler.loop_eval().await;
+        });
+
+        let (api_tx, api_rx) = mpsc::channel::<EvalRequest>(32);
+        let (bootstrap_req_tx, bootstrap_req_rx) = oneshot::channel::<WorkerPoolBootRequest>();
+        // Policies are never reloaded, dropping the sender right away
+        // lets the worker pool know about that
+        let (_, reload_req_rx) = mpsc::channel::<WorkerPoolReloadRequest>(1);
+        let policy_evaluation_limit_seconds = settings.policy_evaluation_limit_seconds;
+        let worker_pool_thread = thread::spawn(move || {
+            let worker_pool = WorkerPool::new(
+                bootstrap_req_rx,
+                reload_req_rx,
+                api_rx,
+                callback_handler_tx,
+                Vec::new(),
+                None,
+                policy_evaluation_limit_seconds,
+            );
+            worker_pool.run();
+        });
+
+        info!(status = "init", "worker pool bootstrap");
+        let (bootstrap_res_tx, bootstrap_res_rx) = oneshot::channel::<Result<()>>();
+        bootstrap_req_tx
+            .send(WorkerPoolBootRequest {
+                policies,
+                fetched_policies,
+                pool_size: 1,
+                queue_size_per_worker: 32,
+                resp_chan: bootstrap_res_tx,
+            })
+            .map_err(|_| anyhow!("Cannot send bootstrap data to worker pool"))?;
+        bootstrap_res_rx
+            .await
+            .map_err(|_| anyhow!("Cannot receive worker pool bootstrap result"))??;
+        info!(status = "done", "worker pool bootstrap");
+
+        Ok(OfflineEvaluator {
+            api_tx,
+            worker_pool_thread,
+            callback_handler_shutdown_tx,
+            callback_handler_task,
+        })
+    }
+
+    /// Evaluates the request against the given policy
+    pub async fn evaluate(
+        &self,
+        policy_id: &str,
+        req: AdmissionRequest,
+    ) -> Result<AdmissionResponse> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.api_tx
+            .send(EvalRequest {
+                policy_id: policy_id.to_owned(),
+                req: ValidationRequest::AdmissionRequest(Box::new(req)),
+                resp_chan: resp_tx,
+                parent_span: Span::current(),
+                deadline: None,
+            })
+            .await
+            .map_err(|_| anyhow!("Cannot send request to worker pool"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| anyhow!("Cannot receive evaluation result from worker pool"))?
+            .ok_or_else(|| anyhow!("Cannot find policy with ID {}", policy_id))
+    }
+
+    /// Stops the worker pool and the CallbackHandler task
+    pub async fn shutdown(self) -> Result<()> {
+        drop(self.api_tx);
+        let worker_pool_thread = self.worker_pool_thread;
+        spawn_blocking(move || worker_pool_thread.join())
+            .await?
+            .map_err(|e| anyhow!("error while waiting for worker threads: {:?}", e))?;
+
+        if self.callback_handler_shutdown_tx.send(()).is_err() {
+            return Err(anyhow!("Cannot shut down the CallbackHandler task"));
+        }
+        self.callback_handler_task.await?;
+
+        Ok(())
+    }
+}
+
+/// Runs the `eval` subcommand: the AdmissionReviews are evaluated against
+/// the policies and the responses are printed to the standard output, one
+/// JSON object per line.
+///
+/// Returns `true` when all the requests have been accepted by all the
+/// policies.
+pub(crate) fn run(matches: &clap::ArgMatches) -> Result<bool> {
+    cli::setup_stderr_tracing(matches);
+
+    let settings = EvaluatorSettings::from_matches(matches)?;
+    let policies = select_policies(
+        cli::policies(matches)?,
+        matches
+            .get_many::<String>("policy-id")
+            .map(|ids| ids.cloned().collect()),
+    )?;
+    let admission_reviews: Vec<AdmissionReview> =
+        read_json_documents(matches.get_one::<String>("request-path").unwrap())?;
+
+    let mut policy_ids: Vec<String> = policies.keys().cloned().collect();
+    policy_ids.sort();
+
+    let rt = Runtime::new()?;
+    rt.block_on(async {
+        let evaluator = OfflineEvaluator::new(&settings, policies).await?;
+
+        let mut all_allowed = true;
+        for admission_review in admission_reviews {
+            // Answer using the version of the review, like the server does
+            let api_version = admission_review.version()?.to_string();
+            let req = admission_review.request.ok_or_else(|| {
+                anyhow!("No Request object defined inside AdmissionReview object")
+            })?;
+            for policy_id in &policy_ids {
+                let response = evaluator.evaluate(policy_id, req.clone()).await?;
+                all_allowed &= response.allowed;
+
+                let result = EvaluationResult {
+                    policy_id: policy_id.clone(),
+                    response: AdmissionReview::new_with_response_to(
+                        &api_version,
+                        &req.uid,
+                        response,
+                    ),
+                };
+                println!("{}", serde_json::to_string(&result)?);
+            }
+        }
+
+        evaluator.shutdown().await?;
+        Ok(all_allowed)
+    })
+}
+
+/// Keeps only the policies with the given IDs, all of them must exist.
+/// All the policies are kept when no ID is given
+fn select_policies(
+    mut policies: HashMap<String, PolicyEntry>,
+    policy_ids: Option<Vec<String>>,
+) -> Result<HashMap<String, PolicyEntry>> {
+    let policy_ids = match policy_ids {
+        Some(ids) => ids,
+        None => return Ok(policies),
+    };
+
+    policy_ids
+        .into_iter()
+        .map(|id| {
+            policies
+                .remove_entry(&id)
+                .ok_or_else(|| anyhow!("Cannot find policy with ID {}", id))
+        })
+        .collect()
+}
+
+/// Reads a stream of JSON documents from the given file, `-` stands for
+/// the standard input
+pub(crate) fn read_json_documents<T: DeserializeOwned>(path: &str) -> Result<Vec<T>> {
+    let contents = if path == "-" {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|e| anyhow!("Cannot read from stdin: {}", e))?;
+        contents
+    } else {
+        fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {}", path, e))?
+    };
+
+    parse_json_documents(&contents).map_err(|e| {
+        anyhow!(
+            "Cannot parse {}: {}",
+            if path == "-" { "stdin" } else { path },
+            e
+        )
+    })
+}
+
+/// Parses a stream of JSON documents. This can be a single JSON document,
+/// JSON lines or any sequence of whitespace separated JSON documents
+pub(crate) fn parse_json_documents<T: DeserializeOwned>(contents: &str) -> Result<Vec<T>> {
+    serde_json::Deserializer::from_str(contents)
+        .into_iter::<T>()
+        .enumerate()
+        .map(|(index, document)| document.map_err(|e| anyhow!("document #{}: {}", index + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMISSION_REVIEW: &str = r#"{"request":{"uid":"UID","kind":{"group":"","version":"v1","kind":"Pod"},"resource":{"group":"","version":"v1","resource":"pods"},"operation":"CREATE","userInfo":{"username":"admin"}}}"#;
+
+    #[test]
+    fn parse_json_documents_from_json_lines() {
+        let contents = format!(
+            "{}\n{}\n",
+            ADMISSION_REVIEW,
+            ADMISSION_REVIEW.replace("UID", "another-UID")
+        );
+
+        let admission_reviews: Vec<AdmissionReview> = parse_json_documents(&contents).unwrap();
+        let uids: Vec<String> = admission_reviews
+            .into_iter()
+            .map(|ar| ar.request.unwrap().uid)
+            .collect();
+        assert_eq!(uids, vec!["UID", "another-UID"]);
+    }
+
+    #[test]
+    fn parse_json_documents_from_json_document() {
+        let contents = serde_json::to_string_pretty(
+            &serde_json::from_str::<serde_json::Value>(ADMISSION_REVIEW).unwrap(),
+        )
+        .unwrap();
+
+        let admission_reviews: Vec<AdmissionReview> = parse_json_documents(&contents).unwrap();
+        assert_eq!(admission_reviews.len(), 1);
+
+        let error = parse_json_documents::<AdmissionReview>(&format!("{}\n{{", ADMISSION_REVIEW))
+            .unwrap_err();
+        assert!(error.to_string().contains("document #2"));
+    }
+
+    #[test]
+    fn select_policies_by_id() {
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(
+            r#"
+---
+psp-capabilities:
+  url: file:///tmp/psp-capabilities.wasm
+namespace-validate:
+  url: file:///tmp/namespace-validate-policy.wasm
+"#,
+        )
+        .unwrap();
+
+        let all = select_policies(policies.clone(), None).unwrap();
+        assert_eq!(all, policies);
+
+        let selected = select_policies(
+            policies.clone(),
+            Some(vec!["namespace-validate".to_string()]),
+        )
+        .unwrap();
+        assert_eq!(
+            selected.keys().collect::<Vec<_>>(),
+            vec!["namespace-validate"]
+        );
+
+        assert!(select_policies(policies, Some(vec!["unknown".to_string()])).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Exemptions make the policies accept requests they would reject, based on
+//! the namespace of the request and on the user doing it.
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use crate::admission_review::AdmissionRequest;
+
+/// Prefix of the usernames of the service accounts
+const SERVICE_ACCOUNT_PREFIX: &str = "system:serviceaccount:";
+
+/// A glob pattern, where `*` matches any sequence of characters and `?`
+/// a single character. Patterns surrounded by slashes, like `/^ci-[0-9]+$/`,
+/// are regular expressions
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    /// A pattern matching only the given value
+    pub fn literal(value: &str) -> Result<Pattern> {
+        Ok(Pattern {
+            source: value.to_string(),
+            regex: Regex::new(&format!("^{}$", regex::escape(value)))?,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Pattern> {
+        let regex = match source.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex),
+            None => {
+                let glob = regex::escape(&source)
+                    .replace("\\*", ".*")
+                    .replace("\\?", ".");
+                Regex::new(&format!("^{}$", glob))
+            }
+        }
+        .map_err(|e| anyhow!("invalid pattern {}: {}", source, e))?;
+
+        Ok(Pattern { source, regex })
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+/// Requests matching all the criteria set by an exemption are accepted.
+/// Each criteria is matched when one of its patterns matches
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Exemption {
+    #[serde(default)]
+    pub namespaces: Vec<Pattern>,
+    #[serde(default)]
+    pub usernames: Vec<Pattern>,
+    #[serde(default)]
+    pub groups: Vec<Pattern>,
+    /// Matched against `<namespace>:<name>` of the service account doing
+    /// the request
+    #[serde(default, rename = "serviceAccounts")]
+    pub service_accounts: Vec<Pattern>,
+}
+
+impl Exemption {
+    /// An exemption accepting the requests made inside of the given namespace
+    pub fn namespace(namespace: &str) -> Result<Exemption> {
+        Ok(Exemption {
+            namespaces: vec![Pattern::literal(namespace)?],
+            ..Default::default()
+        })
+    }
+
+    /// Returns an error when the exemption matches all the requests
+    pub fn validate(&self) -> Result<()> {
+        if self.namespaces.is_empty()
+            && self.usernames.is_empty()
+            && self.groups.is_empty()
+            && self.service_accounts.is_empty()
+        {
+            return Err(anyhow!(
+                "an exemption must set at least one of namespaces, usernames, groups and serviceAccounts"
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, req: &AdmissionRequest) -> bool {
+        let username = req.user_info.username.as_deref().unwrap_or_default();
+        let groups = req.user_info.groups.as_deref().unwrap_or_default();
+        let service_account = username.strip_prefix(SERVICE_ACCOUNT_PREFIX);
+
+        criteria_matches(&self.namespaces, req.namespace.as_deref())
+            && criteria_matches(&self.usernames, Some(username))
+            && (self.groups.is_empty()
+                || groups
+                    .iter()
+                    .any(|group| criteria_matches(&self.groups, Some(group))))
+            && criteria_matches(&self.service_accounts, service_account)
+    }
+}
+
+/// Criteria without patterns match everything, the other ones do not match
+/// missing values
+fn criteria_matches(patterns: &[Pattern], value: Option<&str>) -> bool {
+    patterns.is_empty()
+        || value.map_or(false, |value| {
+            patterns.iter().any(|pattern| pattern.matches(value))
+        })
+}
+
+/// Returns the first exemption matching the request
+pub(crate) fn matching_exemption<'a>(
+    exemptions: &'a [Exemption],
+    req: &AdmissionRequest,
+) -> Option<&'a Exemption> {
+    exemptions.iter().find(|exemption| exemption.matches(req))
+}
+
+/// Reads the list of exemptions applying to all the policies
+pub fn read_exemptions_file(path: &Path) -> Result<Vec<Exemption>> {
+    let exemptions_file = File::open(path)?;
+    let exemptions: Vec<Exemption> = serde_yaml::from_reader(&exemptions_file)?;
+    for exemption in &exemptions {
+        exemption.validate()?;
+    }
+    Ok(exemptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(namespace: &str, username: &str, groups: &[&str]) -> AdmissionRequest {
+        serde_json::from_value(serde_json::json!({
+            "uid": "uid",
+            "kind": {"group": "", "version": "v1", "kind": "Pod"},
+            "resource": {"group": "", "version": "v1", "resource": "pods"},
+            "namespace": namespace,
+            "operation": "CREATE",
+            "userInfo": {"username": username, "groups": groups},
+        }))
+        .unwrap()
+    }
+
+    fn exemption(yaml: &str) -> Exemption {
+        let exemption: Exemption = serde_yaml::from_str(yaml).unwrap();
+        exemption.validate().unwrap();
+        exemption
+    }
+
+    #[test]
+    fn patterns() {
+        let glob = Pattern::try_from("kube-*".to_string()).unwrap();
+        assert!(glob.matches("kube-system"));
+        assert!(!glob.matches("my-kube-system"));
+
+        let glob = Pattern::try_from("team-?.prod".to_string()).unwrap();
+        assert!(glob.matches("team-a.prod"));
+        assert!(!glob.matches("team-ab.prod"));
+        assert!(!glob.matches("team-a-prod"));
+
+        let regex = Pattern::try_from("/^ci-[0-9]+$/".to_string()).unwrap();
+        assert!(regex.matches("ci-42"));
+        assert!(!regex.matches("ci-latest"));
+
+        assert!(Pattern::try_from("/ci-[0-9+$/".to_string()).is_err());
+    }
+
+    #[test]
+    fn exemptions() {
+        let req = request("kube-system", "admin", &["system:masters"]);
+        assert!(exemption("namespaces: [default, kube-*]").matches(&req));
+        assert!(!exemption("namespaces: [default]").matches(&req));
+        assert!(exemption("usernames: [admin]").matches(&req));
+        assert!(exemption("groups: ['system:*']").matches(&req));
+        assert!(!exemption("groups: [developers]").matches(&req));
+
+        // All the criteria must match
+        assert!(exemption("{namespaces: [kube-system], usernames: [admin]}").matches(&req));
+        assert!(!exemption("{namespaces: [kube-system], usernames: [alice]}").matches(&req));
+
+        let req = request(
+            "ci",
+            "system:serviceaccount:ci:builder",
+            &["system:serviceaccounts"],
+        );
+        assert!(exemption("serviceAccounts: ['ci:*']").matches(&req));
+        assert!(!exemption("serviceAccounts: ['kube-system:*']").matches(&req));
+        assert!(!exemption("serviceAccounts: ['ci:*']").matches(&request(
+            "ci",
+            "admin",
+            &["system:masters"]
+        )));
+
+        assert!(Exemption::namespace("kube-system")
+            .unwrap()
+            .matches(&request("kube-system", "admin", &[])));
+        assert!(!Exemption::namespace("kube-system")
+            .unwrap()
+            .matches(&request("kube-systems", "admin", &[])));
+    }
+
+    #[test]
+    fn invalid_exemptions() {
+        assert!(Exemption::default().validate().is_err());
+        assert!(serde_yaml::from_str::<Exemption>("namespace: [default]").is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Boolean expressions combining the verdicts of the members of a policy
+//! group, e.g. `privileged() && (capabilities() || host_namespaces())`.
+//!
+//! A member is referenced by calling it, its value is `true` when it accepts
+//! the request. Expressions support `&&`, `||`, `!` and parentheses, `&&`
+//! binds tighter than `||`. Members are evaluated lazily: `a() || b()` does
+//! not evaluate `b` when `a` accepts the request.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Member(String),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn evaluate(&self, verdict: &mut impl FnMut(&str) -> bool) -> bool {
+        match self {
+            Node::Member(name) => verdict(name),
+            Node::Not(node) => !node.evaluate(verdict),
+            Node::And(left, right) => left.evaluate(verdict) && right.evaluate(verdict),
+            Node::Or(left, right) => left.evaluate(verdict) || right.evaluate(verdict),
+        }
+    }
+
+    fn members<'a>(&'a self, members: &mut BTreeSet<&'a str>) {
+        match self {
+            Node::Member(name) => {
+                members.insert(name);
+            }
+            Node::Not(node) => node.members(members),
+            Node::And(left, right) | Node::Or(left, right) => {
+                left.members(members);
+                right.members(members);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        let root = parser.or()?;
+        if let Some((offset, token)) = parser.peek() {
+            return Err(anyhow!(
+                "unexpected {} at position {} of expression `{}`",
+                token,
+                offset,
+                source
+            ));
+        }
+
+        Ok(Expression {
+            source: source.to_string(),
+            root,
+        })
+    }
+
+    /// The names of the members referenced by the expression
+    pub fn members(&self) -> BTreeSet<&str> {
+        let mut members = BTreeSet::new();
+        self.root.members(&mut members);
+        members
+    }
+
+    /// Evaluates the expression. `verdict` is invoked with the name of each
+    /// member whose verdict is required, and returns whether the member
+    /// accepts the request
+    pub fn evaluate(&self, mut verdict: impl FnMut(&str) -> bool) -> bool {
+        self.root.evaluate(&mut verdict)
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Expression> {
+        Expression::parse(&source)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Member(String),
+    And,
+    Or,
+    Not,
+    LeftParen,
+    RightParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Member(name) => write!(f, "`{}()`", name),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Not => write!(f, "`!`"),
+            Token::LeftParen => write!(f, "`(`"),
+            Token::RightParen => write!(f, "`)`"),
+        }
+    }
+}
+
+fn is_member_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn skip_whitespaces(chars: &mut Peekable<CharIndices>) {
+    while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+}
+
+/// Splits the expression into tokens, together with their offset
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '!' => Token::Not,
+            '&' | '|' => {
+                if chars.next_if(|(_, next)| *next == c).is_none() {
+                    return Err(anyhow!(
+                        "expected `{}{}` at position {} of expression `{}`",
+                        c,
+                        c,
+                        offset,
+                        source
+                    ));
+                }
+                if c == '&' {
+                    Token::And
+                } else {
+                    Token::Or
+                }
+            }
+            c if is_member_name_char(c) => {
+                let mut name = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_member_name_char(*c)) {
+                    name.push(c);
+                }
+                skip_whitespaces(&mut chars);
+                let open = chars.next_if(|(_, c)| *c == '(').is_some();
+                skip_whitespaces(&mut chars);
+                if !open || chars.next_if(|(_, c)| *c == ')').is_none() {
+                    return Err(anyhow!(
+                        "policy `{}` must be referenced as `{}()` at position {} of expression `{}`",
+                        name,
+                        name,
+                        offset,
+                        source
+                    ));
+                }
+                Token::Member(name)
+            }
+            c => {
+                return Err(anyhow!(
+                    "unexpected character `{}` at position {} of expression `{}`",
+                    c,
+                    offset,
+                    source
+                ))
+            }
+        };
+        tokens.push((offset, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser of the grammar:
+///
+/// ```text
+/// or      := and ( "||" and )*
+/// and     := unary ( "&&" unary )*
+/// unary   := "!" unary | primary
+/// primary := member "(" ")" | "(" or ")"
+/// ```
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a (usize, Token)> {
+        self.tokens.get(self.position)
+    }
+
+    fn next_if(&mut self, token: &Token) -> bool {
+        match self.peek() {
+            Some((_, next)) if next == token => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Node> {
+        let mut node = self.and()?;
+        while self.next_if(&Token::Or) {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        while self.next_if(&Token::And) {
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        if self.next_if(&Token::Not) {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node> {
+        match self.peek() {
+            Some((_, Token::Member(name))) => {
+                self.position += 1;
+                Ok(Node::Member(name.clone()))
+            }
+            Some((offset, Token::LeftParen)) => {
+                self.position += 1;
+                let node = self.or()?;
+                if !self.next_if(&Token::RightParen) {
+                    return Err(anyhow!(
+                        "unclosed `(` at position {} of the expression",
+                        offset
+                    ));
+                }
+                Ok(node)
+            }
+            Some((offset, token)) => Err(anyhow!(
+                "unexpected {} at position {} of the expression",
+                token,
+                offset
+            )),
+            None => Err(anyhow!("unexpected end of the expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(expression: &str, accepting: &[&str]) -> (bool, Vec<String>) {
+        let mut evaluated = Vec::new();
+        let allowed = Expression::parse(expression).unwrap().evaluate(|member| {
+            evaluated.push(member.to_string());
+            accepting.contains(&member)
+        });
+        (allowed, evaluated)
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        assert_eq!(evaluate("a()", &["a"]), (true, vec!["a".to_string()]));
+        assert_eq!(evaluate("!a()", &["a"]), (false, vec!["a".to_string()]));
+        assert_eq!(
+            evaluate("a() && (b() || c())", &["a", "c"]),
+            (
+                true,
+                vec!["a".to_string(), "b".to_string(), "c".to_string()]
+            )
+        );
+        // `&&` binds tighter than `||`
+        assert_eq!(
+            evaluate("a() || b() && c()", &["a"]),
+            (true, vec!["a".to_string()])
+        );
+        assert_eq!(
+            evaluate("(a() || b()) && c()", &["a"]),
+            (false, vec!["a".to_string(), "c".to_string()])
+        );
+        // members are evaluated lazily
+        assert_eq!(
+            evaluate("psp-privileged() && host_namespaces ( )", &[]),
+            (false, vec!["psp-privileged".to_string()])
+        );
+    }
+
+    #[test]
+    fn expression_members() {
+        let expression = Expression::parse("c() && !(a() || c()) || b()").unwrap();
+        assert_eq!(
+            expression.members().into_iter().collect::<Vec<&str>>(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(expression.to_string(), "c() && !(a() || c()) || b()");
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expression in [
+            "",
+            "a",
+            "a(",
+            "a() &",
+            "a() && ",
+            "(a() || b()",
+            "a() b()",
+            "a() || )",
+            "a() + b()",
+        ] {
+            assert!(
+                Expression::parse(expression).is_err(),
+                "`{}` should not be valid",
+                expression
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::communication::KubePollerBootRequest;
+use crate::selectors;
 use anyhow::{anyhow, Result};
 use policy_evaluator::cluster_context::ClusterContext;
 use policy_evaluator::kube::Client;
@@ -10,16 +11,21 @@ use tracing::{error, info, warn};
 
 pub(crate) struct Poller {
     bootstrap_rx: oneshot::Receiver<KubePollerBootRequest>,
+    shutdown_rx: oneshot::Receiver<()>,
     runtime: tokio::runtime::Runtime,
 }
 
 impl Poller {
-    pub(crate) fn new(bootstrap_rx: oneshot::Receiver<KubePollerBootRequest>) -> Result<Poller> {
+    pub(crate) fn new(
+        bootstrap_rx: oneshot::Receiver<KubePollerBootRequest>,
+        shutdown_rx: oneshot::Receiver<()>,
+    ) -> Result<Poller> {
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()?;
         Ok(Poller {
             bootstrap_rx,
+            shutdown_rx,
             runtime,
         })
     }
@@ -46,33 +52,50 @@ impl Poller {
             }
         }
 
+        let shutdown_rx = self.shutdown_rx;
         self.runtime.block_on(async {
+            tokio::select! {
+                _ = refresh_loop() => {}
+                _ = shutdown_rx => info!(status = "exit", "cluster context refresh loop"),
+            }
+        });
+    }
+}
 
-            info!("spawning cluster context refresh loop");
-            loop {
-                let kubernetes_client = Client::try_default()
-                    .await
-                    .map_err(|e| anyhow!("could not initialize a cluster context because a Kubernetes client could not be created: {}", e));
+async fn refresh_loop() {
+    info!("spawning cluster context refresh loop");
+    loop {
+        let kubernetes_client = Client::try_default()
+            .await
+            .map_err(|e| anyhow!("could not initialize a cluster context because a Kubernetes client could not be created: {}", e));
 
-                match kubernetes_client {
-                    Ok(kubernetes_client) => loop {
-                        let refresh = ClusterContext::get().refresh(&kubernetes_client).await;
+        match kubernetes_client {
+            Ok(kubernetes_client) => loop {
+                let refresh = ClusterContext::get().refresh(&kubernetes_client).await;
 
-                        if let Err(err) = refresh {
-                            warn!("error when refreshing the cluster context: {}", err);
+                match refresh {
+                    Ok(()) => {
+                        if let Err(err) =
+                            selectors::refresh_namespace_labels(&ClusterContext::get().namespaces())
+                        {
+                            warn!(
+                                "error when refreshing the labels of the namespaces: {}",
+                                err
+                            );
                         }
-                        sleep(Duration::from_secs(5)).await;
-                    },
-                    Err(err) => {
-                        warn!(
-                            "error when initializing the cluster context client: {}",
-                            err
-                        );
-                        sleep(Duration::from_secs(5)).await;
-                        continue;
                     }
+                    Err(err) => warn!("error when refreshing the cluster context: {}", err),
                 }
+                sleep(Duration::from_secs(5)).await;
+            },
+            Err(err) => {
+                warn!(
+                    "error when initializing the cluster context client: {}",
+                    err
+                );
+                sleep(Duration::from_secs(5)).await;
+                continue;
             }
-    });
+        }
     }
 }
@@ -7,17 +7,37 @@ use opentelemetry::global::shutdown_tracer_provider;
 use policy_evaluator::callback_handler::CallbackHandlerBuilder;
 use policy_evaluator::policy_fetcher::sigstore;
 use policy_evaluator::policy_fetcher::verify::FulcioAndRekorData;
-use std::{path::PathBuf, process, sync::RwLock, thread};
-use tokio::{runtime::Runtime, sync::mpsc, sync::oneshot};
-use tracing::{debug, error, info};
+use std::{
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot, watch},
+};
+use tracing::{debug, error, info, warn};
 
 mod admission_review;
 mod api;
+mod audit;
 mod cli;
+mod eval;
+mod exemptions;
+mod expression;
 mod kube_poller;
 mod metrics;
+mod policies_watcher;
+mod replay;
+mod selectors;
 mod server;
 mod settings;
+mod shadow_evaluator;
+mod tls;
 mod worker;
 
 mod policy_downloader;
@@ -27,7 +47,9 @@ mod worker_pool;
 use worker_pool::WorkerPool;
 
 mod communication;
-use communication::{EvalRequest, KubePollerBootRequest, WorkerPoolBootRequest};
+use communication::{
+    EvalRequest, KubePollerBootRequest, WorkerPoolBootRequest, WorkerPoolReloadRequest,
+};
 
 lazy_static! {
     static ref TRACE_SYSTEM_INITIALIZED: RwLock<bool> = RwLock::new(false);
@@ -35,11 +57,26 @@ lazy_static! {
 
 fn main() -> Result<()> {
     let matches = cli::build_cli().get_matches();
+    // The subcommands exit with 2 when at least one request has been
+    // rejected (eval), or when responses changed (replay). Errors make the
+    // process exit with 1
+    let subcommand_outcome = match matches.subcommand() {
+        Some(("eval", eval_matches)) => Some(eval::run(eval_matches)?),
+        Some(("replay", replay_matches)) => Some(replay::run(replay_matches)?),
+        _ => None,
+    };
+    match subcommand_outcome {
+        Some(true) => return Ok(()),
+        Some(false) => process::exit(2),
+        None => {}
+    }
 
     // init some variables based on the cli parameters
     let addr = cli::api_bind_address(&matches)?;
     let (cert_file, key_file) = cli::tls_files(&matches)?;
+    let (client_ca_file, allowed_client_names) = cli::client_auth(&matches)?;
     let policies = cli::policies(&matches)?;
+    let policies_watch_interval = cli::policies_watch_interval(&matches)?;
     let sources = cli::remote_server_options(&matches)?;
     let pool_size = matches
         .get_one::<String>("workers")
@@ -47,11 +84,23 @@ fn main() -> Result<()> {
             v.parse::<usize>()
                 .expect("error parsing the number of workers")
         });
-    let always_accept_admission_reviews_on_namespace = matches
-        .get_one::<String>("always-accept-admission-reviews-on-namespace")
-        .map(|s| s.to_owned());
+    let exemptions = cli::exemptions(&matches)?;
+    let audit_annotations_prefix = cli::audit_annotations_prefix(&matches)?;
+    let audit_writer = cli::audit_config(&matches)?.map(audit::init);
+    let policy_evaluation_limit_seconds = cli::policy_evaluation_limit_seconds(&matches)?;
+    let shutdown_grace_period = cli::shutdown_grace_period(&matches)?;
+    let shutdown_readiness_delay = cli::shutdown_readiness_delay(&matches)?;
+    let (api_queue_size, worker_queue_size) = cli::queue_sizes(&matches)?;
+    let max_queue_wait = cli::max_queue_wait(&matches)?;
 
     let metrics_enabled = matches.contains_id("enable-metrics");
+    let metrics_exporters: Vec<String> = matches
+        .get_many::<String>("metrics-exporter")
+        .unwrap()
+        .cloned()
+        .collect();
+    let metrics_addr = cli::metrics_bind_address(&matches)?;
+    let admin_addr = cli::admin_bind_address(&matches)?;
     let verification_config = cli::verification_config(&matches).unwrap_or_else(|e| {
         fatal_error(format!(
             "Cannot create sigstore verification config: {:?}",
@@ -119,7 +168,7 @@ fn main() -> Result<()> {
 
     // This is the channel used by the http server to communicate with the
     // Wasm workers
-    let (api_tx, api_rx) = mpsc::channel::<EvalRequest>(32);
+    let (api_tx, api_rx) = mpsc::channel::<EvalRequest>(api_queue_size);
 
     // This is the channel used to have the asynchronous code trigger the
     // bootstrap of the worker pool. The bootstrap must be triggered
@@ -128,13 +177,21 @@ fn main() -> Result<()> {
     let (worker_pool_bootstrap_req_tx, worker_pool_bootstrap_req_rx) =
         oneshot::channel::<WorkerPoolBootRequest>();
 
+    // This is the channel used to ask the worker pool to load a new set
+    // of policies
+    let (worker_pool_reload_req_tx, worker_pool_reload_req_rx) =
+        mpsc::channel::<WorkerPoolReloadRequest>(1);
+
     // Spawn the system thread that runs the main loop of the worker pool manager
     let wasm_thread = thread::spawn(move || {
         let worker_pool = WorkerPool::new(
             worker_pool_bootstrap_req_rx,
+            worker_pool_reload_req_rx,
             api_rx,
             callback_sender_channel,
-            always_accept_admission_reviews_on_namespace,
+            exemptions,
+            audit_annotations_prefix,
+            policy_evaluation_limit_seconds,
         );
         worker_pool.run();
     });
@@ -152,18 +209,22 @@ fn main() -> Result<()> {
     let (kube_poller_bootstrap_req_tx, kube_poller_bootstrap_req_rx) =
         oneshot::channel::<KubePollerBootRequest>();
 
+    // This is the channel used to stop the kubernetes poller
+    let (kube_poller_shutdown_tx, kube_poller_shutdown_rx) = oneshot::channel::<()>();
+
     // Spawn the system thread that runs the main loop of the worker pool manager
     let kube_poller_thread = thread::spawn(move || {
-        let poller = match kube_poller::Poller::new(kube_poller_bootstrap_req_rx) {
-            Ok(p) => p,
-            Err(e) => {
-                fatal_error(format!(
-                    "Cannot init dedicated tokio runtime for the Kubernetes poller: {:?}",
-                    e
-                ));
-                unreachable!()
-            }
-        };
+        let poller =
+            match kube_poller::Poller::new(kube_poller_bootstrap_req_rx, kube_poller_shutdown_rx) {
+                Ok(p) => p,
+                Err(e) => {
+                    fatal_error(format!(
+                        "Cannot init dedicated tokio runtime for the Kubernetes poller: {:?}",
+                        e
+                    ));
+                    unreachable!()
+                }
+            };
         poller.run();
     });
 
@@ -184,28 +245,39 @@ fn main() -> Result<()> {
             unreachable!();
         }
     };
-    rt.block_on(async {
+    let drained = rt.block_on(async {
         // Setup the tracing system. This MUST be done inside of a tokio Runtime
         // because some collectors rely on it and would panic otherwise.
-        match cli::setup_tracing(&matches) {
+        let log_level_handle = match cli::setup_tracing(&matches) {
             Err(err) => {
                 fatal_error(err.to_string());
                 unreachable!();
             }
-            Ok(_) => {
+            Ok(handle) => {
                 debug!("tracing system ready");
                 let mut w = TRACE_SYSTEM_INITIALIZED.write().unwrap();
                 *w = true;
+                handle
             }
         };
 
-        // The unused variable is required so the meter is not dropped early and
-        // lives for the whole block lifetime, exporting metrics
-        let _meter = if metrics_enabled {
+        // The meter must not be dropped early, it lives until the shutdown,
+        // exporting metrics
+        let meter = if metrics_enabled && metrics_exporters.iter().any(|e| e == "otlp") {
             Some(metrics::init_meter())
         } else {
             None
         };
+        if metrics_enabled && metrics_exporters.iter().any(|e| e == "prometheus") {
+            if let Err(e) = metrics::init_prometheus_exporter() {
+                fatal_error(format!("Cannot init Prometheus exporter: {}", e));
+            }
+            info!(address = %metrics_addr, "serving Prometheus metrics");
+            tokio::spawn(server::run_metrics_server(metrics_addr));
+        }
+        if let Some(audit_writer) = audit_writer {
+            tokio::spawn(audit_writer.run());
+        }
 
         // Download policies
         let mut downloader = match Downloader::new(
@@ -225,7 +297,7 @@ fn main() -> Result<()> {
         let policies_download_dir = matches.get_one::<String>("policies-download-dir").unwrap();
         let fetched_policies = match downloader
             .download_policies(
-                &policies,
+                &settings::policies_to_fetch(&policies),
                 policies_download_dir,
                 verification_config.as_ref(),
             )
@@ -267,7 +339,7 @@ fn main() -> Result<()> {
                 }
                 _ => {
                     fatal_error("Cannot receive kubernetes poller bootstrap result".to_string());
-                    return;
+                    unreachable!();
                 }
             }
         }
@@ -285,9 +357,10 @@ fn main() -> Result<()> {
         let (worker_pool_bootstrap_res_tx, mut worker_pool_bootstrap_res_rx) =
             oneshot::channel::<Result<()>>();
         let bootstrap_data = WorkerPoolBootRequest {
-            policies,
-            fetched_policies,
+            policies: policies.clone(),
+            fetched_policies: fetched_policies.clone(),
             pool_size,
+            queue_size_per_worker: worker_queue_size,
             resp_chan: worker_pool_bootstrap_res_tx,
         };
         if worker_pool_bootstrap_req_tx.send(bootstrap_data).is_err() {
@@ -313,12 +386,50 @@ fn main() -> Result<()> {
                 }
                 _ => {
                     fatal_error("Cannot receive worker pool bootstrap result".to_string());
-                    return;
+                    unreachable!();
                 }
             }
         }
         info!(status = "done", "worker pool bootstrap");
 
+        // The policies currently loaded by the worker pool, these are
+        // exposed by the admin endpoints
+        let loaded_policies = Arc::new(RwLock::new(policies.clone()));
+        let loaded_modules = Arc::new(RwLock::new(fetched_policies.clone()));
+
+        // Cleared once the shutdown starts, this makes the readiness probe
+        // fail so that no new requests are routed to this instance
+        let ready = Arc::new(AtomicBool::new(true));
+
+        // Keep an eye on the policies file, the worker pool is asked to
+        // load the new policies whenever they change
+        let policies_watcher = policies_watcher::PoliciesWatcher {
+            policies_file: PathBuf::from(matches.get_one::<String>("policies").unwrap()),
+            policies,
+            fetched_policies,
+            downloader,
+            policies_download_dir: policies_download_dir.to_owned(),
+            verification_config,
+            reload_tx: worker_pool_reload_req_tx,
+            watch_interval: policies_watch_interval,
+            loaded_policies: loaded_policies.clone(),
+            loaded_modules: loaded_modules.clone(),
+        };
+        tokio::spawn(policies_watcher.run());
+
+        if let Some(admin_addr) = admin_addr {
+            info!(address = %admin_addr, "serving admin endpoints");
+            tokio::spawn(server::run_admin_server(
+                admin_addr,
+                server::AdminState {
+                    policies: loaded_policies.clone(),
+                    modules: loaded_modules,
+                    log_level: log_level_handle,
+                    ready: ready.clone(),
+                },
+            ));
+        }
+
         // All is good, we can start listening for incoming requests through the
         // web server
         let tls_config = if cert_file.is_empty() {
@@ -327,9 +438,68 @@ fn main() -> Result<()> {
             Some(crate::server::TlsConfig {
                 cert_file: cert_file.to_string(),
                 key_file: key_file.to_string(),
+                client_ca_file: client_ca_file.clone(),
+                allowed_client_names: allowed_client_names.clone(),
             })
         };
-        server::run_server(&addr, tls_config, api_tx).await;
+        let api_state = server::ApiState {
+            api_tx,
+            policies: loaded_policies,
+            max_queue_wait,
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut server_task = tokio::spawn({
+            let ready = ready.clone();
+            async move { server::run_server(&addr, tls_config, api_state, ready, shutdown_rx).await }
+        });
+
+        tokio::select! {
+            res = &mut server_task => {
+                match res {
+                    Ok(Err(e)) => fatal_error(e.to_string()),
+                    Ok(Ok(_)) => fatal_error("server stopped unexpectedly".to_string()),
+                    Err(e) => fatal_error(format!("server task failed: {}", e)),
+                }
+            }
+            res = server::shutdown_signal() => {
+                if let Err(e) = res {
+                    fatal_error(e.to_string());
+                }
+            }
+        }
+
+        // Make the readiness probe fail and keep accepting new connections
+        // until Kubernetes stops routing requests to this instance
+        info!(
+            readiness_delay = ?shutdown_readiness_delay,
+            "shutting down, readiness probe failing"
+        );
+        ready.store(false, Ordering::SeqCst);
+        tokio::time::sleep(shutdown_readiness_delay).await;
+
+        // Stop accepting new connections and let the in-flight requests
+        // complete. Once the server is done, the channel used to talk with
+        // the worker pool is closed: the workers are then going to exit
+        info!(
+            grace_period = ?shutdown_grace_period,
+            "draining in-flight requests"
+        );
+        let _ = shutdown_tx.send(true);
+        let drained = match tokio::time::timeout(shutdown_grace_period, &mut server_task).await {
+            Ok(_) => {
+                info!(status = "done", "in-flight requests drained");
+                true
+            }
+            Err(_) => {
+                warn!("shutdown grace period expired, dropping the in-flight requests");
+                server_task.abort();
+                false
+            }
+        };
+
+        if kube_poller_shutdown_tx.send(()).is_err() {
+            error!("Cannot shut down the kubernetes poller");
+        }
 
         // The evaluation is done, we can shutdown the tokio task that is running
         // the CallbackHandler
@@ -341,11 +511,22 @@ fn main() -> Result<()> {
                 "Error waiting for the CallbackHandler task"
             );
         }
+
+        // Flush the pending traces and metrics. Dropping the meter pushes
+        // the last metrics to the collector
+        drop(meter);
+        shutdown_tracer_provider();
+
+        drained
     });
 
-    if let Err(e) = wasm_thread.join() {
-        fatal_error(format!("error while waiting for worker threads: {:?}", e));
-    };
+    // When the grace period expired some workers may still be busy with
+    // the dropped requests: don't wait for them
+    if drained {
+        if let Err(e) = wasm_thread.join() {
+            fatal_error(format!("error while waiting for worker threads: {:?}", e));
+        };
+    }
 
     if let Err(e) = kube_poller_thread.join() {
         fatal_error(format!("error while waiting for worker threads: {:?}", e));
@@ -0,0 +1,37 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::UpDownCounter, KeyValue};
+
+lazy_static! {
+    static ref EVALUATION_QUEUE_DEPTH: UpDownCounter<i64> =
+        opentelemetry::global::meter(super::METER_NAME)
+            .i64_up_down_counter("kubewarden_evaluation_queue_depth")
+            .init();
+}
+
+/// The queues a request waits in before being evaluated
+#[derive(Clone, Copy, Debug)]
+pub enum Queue {
+    /// Requests received by the API, waiting to be dispatched to the workers
+    Api,
+    /// Requests waiting for an idle worker
+    Workers,
+}
+
+impl Queue {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Queue::Api => "api",
+            Queue::Workers => "workers",
+        }
+    }
+}
+
+pub fn record_enqueued_request(queue: Queue) {
+    EVALUATION_QUEUE_DEPTH.add(1, &[KeyValue::new("queue", queue.as_str())]);
+    super::prometheus_exporter::add_queue_depth(queue.as_str(), 1);
+}
+
+pub fn record_dequeued_request(queue: Queue) {
+    EVALUATION_QUEUE_DEPTH.add(-1, &[KeyValue::new("queue", queue.as_str())]);
+    super::prometheus_exporter::add_queue_depth(queue.as_str(), -1);
+}
@@ -0,0 +1,21 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::Counter, KeyValue};
+
+lazy_static! {
+    static ref EXEMPTIONS_TOTAL: Counter<u64> = opentelemetry::global::meter(super::METER_NAME)
+        .u64_counter("kubewarden_exemptions_total")
+        .init();
+}
+
+/// Records a request accepted because of an exemption. The scope is
+/// `policy` when the exemption is set on the policy, `global` otherwise
+pub fn add_exemption(policy_name: &str, scope: &str) {
+    EXEMPTIONS_TOTAL.add(
+        1,
+        &[
+            KeyValue::new("policy_name", policy_name.to_string()),
+            KeyValue::new("scope", scope.to_string()),
+        ],
+    );
+    super::prometheus_exporter::add_exemption(policy_name, scope);
+}
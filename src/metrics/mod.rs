@@ -7,6 +7,20 @@ mod policy_evaluations_total;
 pub use policy_evaluations_total::add_policy_evaluation;
 mod policy_evaluations_latency;
 pub use policy_evaluations_latency::record_policy_latency;
+mod tls_handshakes_refused_total;
+pub use tls_handshakes_refused_total::add_refused_tls_handshake;
+mod tls_handshake_failures_total;
+pub use tls_handshake_failures_total::add_failed_tls_handshake;
+mod evaluation_queue_depth;
+pub use evaluation_queue_depth::{record_dequeued_request, record_enqueued_request, Queue};
+mod requests_shed_total;
+pub use requests_shed_total::add_shed_request;
+mod exemptions_total;
+pub use exemptions_total::add_exemption;
+mod policy_shadow_disagreements_total;
+pub use policy_shadow_disagreements_total::add_shadow_disagreement;
+mod prometheus_exporter;
+pub(crate) use prometheus_exporter::{gather, init_prometheus_exporter};
 
 const METER_NAME: &str = "kubewarden";
 
@@ -30,7 +44,10 @@ pub struct PolicyEvaluation {
     pub(crate) resource_request_operation: String,
     pub(crate) accepted: bool,
     pub(crate) mutated: bool,
+    pub(crate) timed_out: bool,
     pub(crate) error_code: Option<u16>,
+    /// Whether the request was a dry-run one
+    pub(crate) dry_run: bool,
 }
 
 #[allow(clippy::from_over_into)]
@@ -46,6 +63,8 @@ impl Into<Vec<KeyValue>> for &PolicyEvaluation {
             ),
             KeyValue::new("accepted", self.accepted),
             KeyValue::new("mutated", self.mutated),
+            KeyValue::new("timed_out", self.timed_out),
+            KeyValue::new("dry_run", self.dry_run),
         ];
         if let Some(resource_namespace) = &self.resource_namespace {
             baggage.append(&mut vec![KeyValue::new(
@@ -17,4 +17,5 @@ pub fn record_policy_latency(latency: Duration, policy_evaluation: &PolicyEvalua
         millis_latency,
         &Into::<Vec<KeyValue>>::into(policy_evaluation),
     );
+    super::prometheus_exporter::record_policy_latency(latency, policy_evaluation);
 }
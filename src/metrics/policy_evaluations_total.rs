@@ -11,4 +11,5 @@ lazy_static! {
 
 pub fn add_policy_evaluation(policy_evaluation: &PolicyEvaluation) {
     POLICY_EVALUATIONS_TOTAL.add(1, &Into::<Vec<KeyValue>>::into(policy_evaluation));
+    super::prometheus_exporter::add_policy_evaluation(policy_evaluation);
 }
@@ -0,0 +1,22 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::Counter, KeyValue};
+
+lazy_static! {
+    static ref POLICY_SHADOW_DISAGREEMENTS_TOTAL: Counter<u64> =
+        opentelemetry::global::meter(super::METER_NAME)
+            .u64_counter("kubewarden_policy_shadow_disagreements_total")
+            .init();
+}
+
+/// Records a request on which a policy and its shadow disagree. The
+/// disagreement is either about the `verdict` or about the `patch`
+pub fn add_shadow_disagreement(policy_name: &str, disagreement: &str) {
+    POLICY_SHADOW_DISAGREEMENTS_TOTAL.add(
+        1,
+        &[
+            KeyValue::new("policy_name", policy_name.to_string()),
+            KeyValue::new("disagreement", disagreement.to_string()),
+        ],
+    );
+    super::prometheus_exporter::add_shadow_disagreement(policy_name, disagreement);
+}
@@ -0,0 +1,253 @@
+use crate::metrics::PolicyEvaluation;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const LABELS: [&str; 10] = [
+    "policy_name",
+    "policy_mode",
+    "resource_kind",
+    "resource_namespace",
+    "resource_request_operation",
+    "accepted",
+    "mutated",
+    "timed_out",
+    "error_code",
+    "dry_run",
+];
+
+const LATENCY_BUCKETS_MILLISECONDS: [f64; 12] = [
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0,
+];
+
+/// Metrics are recorded into the registry only once the exporter has been
+/// initialized
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref POLICY_EVALUATIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_policy_evaluations_total",
+            "Number of policy evaluations"
+        ),
+        &LABELS
+    )
+    .unwrap();
+    static ref POLICY_EVALUATION_LATENCY: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "kubewarden_policy_evaluation_latency_milliseconds",
+            "Duration of the policy evaluations in milliseconds"
+        )
+        .buckets(LATENCY_BUCKETS_MILLISECONDS.to_vec()),
+        &LABELS
+    )
+    .unwrap();
+    static ref TLS_HANDSHAKES_REFUSED_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_tls_handshakes_refused_total",
+            "Number of TLS connections refused"
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    static ref TLS_HANDSHAKE_FAILURES_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_tls_handshake_failures_total",
+            "Number of TLS handshakes that failed"
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    static ref EVALUATION_QUEUE_DEPTH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "kubewarden_evaluation_queue_depth",
+            "Number of requests waiting to be evaluated"
+        ),
+        &["queue"]
+    )
+    .unwrap();
+    static ref REQUESTS_SHED_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_requests_shed_total",
+            "Number of requests not evaluated because the evaluation queues are full"
+        ),
+        &["policy_name", "failure_policy"]
+    )
+    .unwrap();
+    static ref EXEMPTIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_exemptions_total",
+            "Number of requests accepted because of an exemption"
+        ),
+        &["policy_name", "scope"]
+    )
+    .unwrap();
+    static ref POLICY_SHADOW_DISAGREEMENTS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "kubewarden_policy_shadow_disagreements_total",
+            "Number of requests on which a policy and its shadow disagree"
+        ),
+        &["policy_name", "disagreement"]
+    )
+    .unwrap();
+}
+
+/// Registers the policy evaluation metrics, they can be scraped
+/// afterwards in the Prometheus text format
+pub(crate) fn init_prometheus_exporter() -> Result<()> {
+    REGISTRY.register(Box::new(POLICY_EVALUATIONS_TOTAL.clone()))?;
+    REGISTRY.register(Box::new(POLICY_EVALUATION_LATENCY.clone()))?;
+    REGISTRY.register(Box::new(TLS_HANDSHAKES_REFUSED_TOTAL.clone()))?;
+    REGISTRY.register(Box::new(TLS_HANDSHAKE_FAILURES_TOTAL.clone()))?;
+    REGISTRY.register(Box::new(EVALUATION_QUEUE_DEPTH.clone()))?;
+    REGISTRY.register(Box::new(REQUESTS_SHED_TOTAL.clone()))?;
+    REGISTRY.register(Box::new(EXEMPTIONS_TOTAL.clone()))?;
+    REGISTRY.register(Box::new(POLICY_SHADOW_DISAGREEMENTS_TOTAL.clone()))?;
+    ENABLED.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Returns the current value of the metrics, using the Prometheus text
+/// format, together with the content type of the response
+pub(crate) fn gather() -> Result<(String, Vec<u8>)> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&REGISTRY.gather(), &mut buffer)?;
+
+    Ok((encoder.format_type().to_string(), buffer))
+}
+
+pub(crate) fn add_policy_evaluation(policy_evaluation: &PolicyEvaluation) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let labels = label_values(policy_evaluation);
+    POLICY_EVALUATIONS_TOTAL
+        .with_label_values(&labels.iter().map(String::as_str).collect::<Vec<&str>>())
+        .inc();
+}
+
+pub(crate) fn record_policy_latency(latency: Duration, policy_evaluation: &PolicyEvaluation) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let labels = label_values(policy_evaluation);
+    POLICY_EVALUATION_LATENCY
+        .with_label_values(&labels.iter().map(String::as_str).collect::<Vec<&str>>())
+        .observe(latency.as_secs_f64() * 1000.0);
+}
+
+pub(crate) fn add_refused_tls_handshake(reason: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    TLS_HANDSHAKES_REFUSED_TOTAL
+        .with_label_values(&[reason])
+        .inc();
+}
+
+pub(crate) fn add_failed_tls_handshake(reason: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    TLS_HANDSHAKE_FAILURES_TOTAL
+        .with_label_values(&[reason])
+        .inc();
+}
+
+pub(crate) fn add_queue_depth(queue: &str, delta: i64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    EVALUATION_QUEUE_DEPTH
+        .with_label_values(&[queue])
+        .add(delta);
+}
+
+pub(crate) fn add_shed_request(policy_name: &str, failure_policy: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    REQUESTS_SHED_TOTAL
+        .with_label_values(&[policy_name, failure_policy])
+        .inc();
+}
+
+pub(crate) fn add_exemption(policy_name: &str, scope: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    EXEMPTIONS_TOTAL
+        .with_label_values(&[policy_name, scope])
+        .inc();
+}
+
+pub(crate) fn add_shadow_disagreement(policy_name: &str, disagreement: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    POLICY_SHADOW_DISAGREEMENTS_TOTAL
+        .with_label_values(&[policy_name, disagreement])
+        .inc();
+}
+
+/// The values of the `LABELS`, the optional ones are left empty when
+/// not set. Prometheus treats empty labels as missing ones
+fn label_values(policy_evaluation: &PolicyEvaluation) -> [String; 10] {
+    [
+        policy_evaluation.policy_name.clone(),
+        policy_evaluation.policy_mode.clone(),
+        policy_evaluation.resource_kind.clone(),
+        policy_evaluation
+            .resource_namespace
+            .clone()
+            .unwrap_or_default(),
+        policy_evaluation.resource_request_operation.clone(),
+        policy_evaluation.accepted.to_string(),
+        policy_evaluation.mutated.to_string(),
+        policy_evaluation.timed_out.to_string(),
+        policy_evaluation
+            .error_code
+            .map(|code| code.to_string())
+            .unwrap_or_default(),
+        policy_evaluation.dry_run.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_of_optional_labels() {
+        let mut policy_evaluation = PolicyEvaluation {
+            policy_name: String::from("psp"),
+            policy_mode: String::from("protect"),
+            resource_kind: String::from("Pod"),
+            resource_namespace: None,
+            resource_request_operation: String::from("CREATE"),
+            accepted: false,
+            mutated: false,
+            timed_out: false,
+            error_code: None,
+            dry_run: false,
+        };
+        let labels = label_values(&policy_evaluation);
+        assert_eq!(labels[3], "");
+        assert_eq!(labels[5], "false");
+        assert_eq!(labels[8], "");
+        assert_eq!(labels[9], "false");
+
+        policy_evaluation.resource_namespace = Some(String::from("default"));
+        policy_evaluation.error_code = Some(500);
+        let labels = label_values(&policy_evaluation);
+        assert_eq!(labels[3], "default");
+        assert_eq!(labels[8], "500");
+    }
+}
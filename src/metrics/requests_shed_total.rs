@@ -0,0 +1,21 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::Counter, KeyValue};
+
+lazy_static! {
+    static ref REQUESTS_SHED_TOTAL: Counter<u64> = opentelemetry::global::meter(super::METER_NAME)
+        .u64_counter("kubewarden_requests_shed_total")
+        .init();
+}
+
+/// Records a request that has not been evaluated because it waited too
+/// long in the evaluation queues
+pub fn add_shed_request(policy_name: &str, failure_policy: &str) {
+    REQUESTS_SHED_TOTAL.add(
+        1,
+        &[
+            KeyValue::new("policy_name", policy_name.to_string()),
+            KeyValue::new("failure_policy", failure_policy.to_string()),
+        ],
+    );
+    super::prometheus_exporter::add_shed_request(policy_name, failure_policy);
+}
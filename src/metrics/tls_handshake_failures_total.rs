@@ -0,0 +1,17 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::Counter, KeyValue};
+
+lazy_static! {
+    static ref TLS_HANDSHAKE_FAILURES_TOTAL: Counter<u64> =
+        opentelemetry::global::meter(super::METER_NAME)
+            .u64_counter("kubewarden_tls_handshake_failures_total")
+            .init();
+}
+
+/// Records a TLS handshake that failed, for example because the client
+/// certificate is not signed by the client CA or because the client went
+/// away
+pub fn add_failed_tls_handshake(reason: &str) {
+    TLS_HANDSHAKE_FAILURES_TOTAL.add(1, &[KeyValue::new("reason", reason.to_string())]);
+    super::prometheus_exporter::add_failed_tls_handshake(reason);
+}
@@ -0,0 +1,16 @@
+use lazy_static::lazy_static;
+use opentelemetry::{metrics::Counter, KeyValue};
+
+lazy_static! {
+    static ref TLS_HANDSHAKES_REFUSED_TOTAL: Counter<u64> =
+        opentelemetry::global::meter(super::METER_NAME)
+            .u64_counter("kubewarden_tls_handshakes_refused_total")
+            .init();
+}
+
+/// Records a TLS connection that has been refused because the client is
+/// not allowed to use the server
+pub fn add_refused_tls_handshake(reason: &str) {
+    TLS_HANDSHAKES_REFUSED_TOTAL.add(1, &[KeyValue::new("reason", reason.to_string())]);
+    super::prometheus_exporter::add_refused_tls_handshake(reason);
+}
@@ -0,0 +1,319 @@
+use anyhow::{anyhow, Result};
+use policy_evaluator::policy_fetcher::verify::config::LatestVerificationConfig;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    fs,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    time::Interval,
+};
+use tracing::{debug, error, info};
+
+use crate::communication::WorkerPoolReloadRequest;
+use crate::policy_downloader::{Downloader, FetchedPolicies};
+use crate::settings::{policies_to_fetch, read_policies_file, Policy, PolicyEntry};
+
+/// Describes how a set of policies differs from another one
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PoliciesDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl PoliciesDiff {
+    pub fn new(
+        old: &HashMap<String, PolicyEntry>,
+        new: &HashMap<String, PolicyEntry>,
+    ) -> PoliciesDiff {
+        let mut diff = PoliciesDiff::default();
+
+        for (id, policy) in new.iter() {
+            match old.get(id) {
+                None => diff.added.push(id.clone()),
+                Some(old_policy) if old_policy != policy => diff.changed.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|id| !new.contains_key(id.as_str()))
+            .cloned()
+            .collect();
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Watches the policies file and reloads the policies of the worker pool
+/// whenever it changes or when a SIGHUP signal is received. All the
+/// WebAssembly modules are downloaded again on SIGHUP, to pick up the
+/// new contents of mutable references like `:latest`
+pub(crate) struct PoliciesWatcher {
+    pub policies_file: PathBuf,
+    pub policies: HashMap<String, PolicyEntry>,
+    pub fetched_policies: FetchedPolicies,
+    pub downloader: Downloader,
+    pub policies_download_dir: String,
+    pub verification_config: Option<LatestVerificationConfig>,
+    pub reload_tx: mpsc::Sender<WorkerPoolReloadRequest>,
+    /// How often the policies file is checked for changes. `None` disables
+    /// the check, policies are then reloaded only on SIGHUP
+    pub watch_interval: Option<Duration>,
+    /// Updated with the new policies once the worker pool loaded them
+    pub loaded_policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+    /// Updated with the WebAssembly modules of the new policies once the
+    /// worker pool loaded them
+    pub loaded_modules: Arc<RwLock<FetchedPolicies>>,
+}
+
+impl PoliciesWatcher {
+    pub async fn run(mut self) {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(error = e.to_string().as_str(), "cannot listen for SIGHUP");
+                return;
+            }
+        };
+        let mut last_contents = fs::read(&self.policies_file).await.ok();
+        let mut interval = self.watch_interval.map(tokio::time::interval);
+
+        loop {
+            let refetch = tokio::select! {
+                _ = sighup.recv() => {
+                    info!("SIGHUP received, reloading policies");
+                    true
+                }
+                _ = tick(&mut interval) => {
+                    let contents = fs::read(&self.policies_file).await.ok();
+                    if contents == last_contents {
+                        continue;
+                    }
+                    info!(policies_file = ?self.policies_file, "policies file changed, reloading policies");
+                    false
+                }
+            };
+
+            last_contents = fs::read(&self.policies_file).await.ok();
+            match self.reload(refetch).await {
+                Ok(()) => info!(status = "done", "policies reload"),
+                Err(e) => error!(
+                    error = e.to_string().as_str(),
+                    "cannot reload policies, the current ones are still in use"
+                ),
+            }
+        }
+    }
+
+    /// Reloads the policies. When `refetch` is set all the WebAssembly
+    /// modules are downloaded again, otherwise only the new ones are
+    async fn reload(&mut self, refetch: bool) -> Result<()> {
+        let policies = read_policies_file(&self.policies_file).map_err(|e| {
+            anyhow!(
+                "error while loading policies from {:?}: {}",
+                self.policies_file,
+                e
+            )
+        })?;
+
+        let diff = PoliciesDiff::new(&self.policies, &policies);
+        if diff.is_empty() && !refetch {
+            debug!("policies did not change");
+            return Ok(());
+        }
+        info!(
+            status = "init",
+            added = ?diff.added,
+            removed = ?diff.removed,
+            changed = ?diff.changed,
+            refetch,
+            "policies reload"
+        );
+
+        // Download only the WebAssembly modules that have not been
+        // fetched yet, unless all of them have to be fetched again
+        let used_policies = policies_to_fetch(&policies);
+        let policies_to_download: HashMap<String, Policy> = used_policies
+            .iter()
+            .filter(|(_, policy)| refetch || !self.fetched_policies.contains_key(&policy.url))
+            .map(|(id, policy)| (id.clone(), policy.clone()))
+            .collect();
+        let mut fetched_policies = self
+            .downloader
+            .download_policies(
+                &policies_to_download,
+                &self.policies_download_dir,
+                self.verification_config.as_ref(),
+            )
+            .await?;
+        for policy in used_policies.values() {
+            if fetched_policies.contains_key(&policy.url) {
+                continue;
+            }
+            if let Some(fetched_policy) = self.fetched_policies.get(&policy.url) {
+                fetched_policies.insert(policy.url.clone(), fetched_policy.clone());
+            }
+        }
+        if diff.is_empty() && !modules_changed(&self.fetched_policies, &fetched_policies) {
+            debug!("neither the policies nor their modules changed");
+            return Ok(());
+        }
+
+        let (resp_tx, resp_rx) = oneshot::channel::<Result<()>>();
+        let reload_request = WorkerPoolReloadRequest {
+            policies: policies.clone(),
+            fetched_policies: fetched_policies.clone(),
+            resp_chan: resp_tx,
+        };
+        if self.reload_tx.send(reload_request).await.is_err() {
+            return Err(anyhow!("cannot send reload request to worker pool"));
+        }
+        resp_rx
+            .await
+            .map_err(|e| anyhow!("cannot receive policies reload result: {}", e))??;
+
+        *self.loaded_policies.write().unwrap() = policies.clone();
+        *self.loaded_modules.write().unwrap() = fetched_policies.clone();
+        self.policies = policies;
+        self.fetched_policies = fetched_policies;
+
+        Ok(())
+    }
+}
+
+/// Returns true when the modules of `new` differ from the ones of `old`,
+/// modules without a digest are considered changed
+fn modules_changed(old: &FetchedPolicies, new: &FetchedPolicies) -> bool {
+    old.len() != new.len()
+        || new.iter().any(|(url, fetched_policy)| {
+            fetched_policy.digest.is_none()
+                || old.get(url).map(|old_policy| &old_policy.digest) != Some(&fetched_policy.digest)
+        })
+}
+
+/// Waits for the next tick of the given interval, never completes when
+/// no interval is provided
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_policies() {
+        let old = r#"
+---
+unchanged:
+  url: file:///tmp/unchanged.wasm
+changed-settings:
+  url: file:///tmp/namespace-validate-policy.wasm
+  settings:
+    valid_namespace: valid
+changed-mode:
+  url: file:///tmp/namespace-validate-policy.wasm
+removed:
+  url: file:///tmp/removed.wasm
+changed-group:
+  expression: "a() && b()"
+  policies:
+    a:
+      url: file:///tmp/a.wasm
+    b:
+      url: file:///tmp/b.wasm
+"#;
+        let new = r#"
+---
+unchanged:
+  url: file:///tmp/unchanged.wasm
+changed-settings:
+  url: file:///tmp/namespace-validate-policy.wasm
+  settings:
+    valid_namespace: another-one
+changed-mode:
+  url: file:///tmp/namespace-validate-policy.wasm
+  policyMode: monitor
+added:
+  url: file:///tmp/added.wasm
+changed-group:
+  expression: "a() || b()"
+  policies:
+    a:
+      url: file:///tmp/a.wasm
+    b:
+      url: file:///tmp/b.wasm
+"#;
+        let old: HashMap<String, PolicyEntry> = serde_yaml::from_str(old).unwrap();
+        let new: HashMap<String, PolicyEntry> = serde_yaml::from_str(new).unwrap();
+
+        let diff = PoliciesDiff::new(&old, &new);
+        assert_eq!(
+            diff,
+            PoliciesDiff {
+                added: vec!["added".to_string()],
+                removed: vec!["removed".to_string()],
+                changed: vec![
+                    "changed-group".to_string(),
+                    "changed-mode".to_string(),
+                    "changed-settings".to_string()
+                ],
+            }
+        );
+        assert!(!diff.is_empty());
+
+        assert!(PoliciesDiff::new(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_modules() {
+        let fetched_policies = |digest: Option<&str>| -> FetchedPolicies {
+            let fetched_policy = crate::policy_downloader::FetchedPolicy {
+                local_path: PathBuf::from("/tmp/latest.wasm"),
+                digest: digest.map(String::from),
+                metadata: None,
+                verification: None,
+            };
+            HashMap::from([(
+                "registry://ghcr.io/kubewarden/policies/latest:latest".to_string(),
+                fetched_policy,
+            )])
+        };
+
+        assert!(!modules_changed(
+            &fetched_policies(Some("abc")),
+            &fetched_policies(Some("abc"))
+        ));
+        assert!(modules_changed(
+            &fetched_policies(Some("abc")),
+            &fetched_policies(Some("def"))
+        ));
+        assert!(modules_changed(
+            &fetched_policies(None),
+            &fetched_policies(None)
+        ));
+        assert!(modules_changed(
+            &FetchedPolicies::new(),
+            &fetched_policies(Some("abc"))
+        ));
+    }
+}
@@ -8,6 +8,7 @@ use policy_evaluator::{
         verify::{config::LatestVerificationConfig, FulcioAndRekorData, Verifier},
     },
 };
+use serde::Serialize;
 use std::{
     collections::{HashMap, HashSet},
     fs,
@@ -19,9 +20,35 @@ use tracing::{debug, info};
 use crate::settings::Policy;
 
 /// A Map with the `policy.url` as key,
-/// and a `PathBuf` as value. The `PathBuf` points to the location where
-/// the WebAssembly module has been downloaded.
-pub(crate) type FetchedPolicies = HashMap<String, PathBuf>;
+/// and a `FetchedPolicy` as value. The `FetchedPolicy` points to the
+/// location where the WebAssembly module has been downloaded.
+pub(crate) type FetchedPolicies = HashMap<String, FetchedPolicy>;
+
+/// A WebAssembly module downloaded by the `Downloader`
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FetchedPolicy {
+    pub local_path: PathBuf,
+    /// The sha256 digest of the module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// The metadata embedded into the module, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    /// Set only when the module has been verified using sigstore
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+}
+
+/// How a WebAssembly module has been verified
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Verification {
+    /// The digest of the manifest whose signatures have been verified
+    pub verified_digest: String,
+    /// The signatures the module has been verified against
+    pub signatures: LatestVerificationConfig,
+}
 
 /// Handles download and verification of policies
 pub(crate) struct Downloader {
@@ -183,30 +210,30 @@ impl Downloader {
                 );
             }
 
-            if let Ok(Some(policy_metadata)) = Metadata::from_path(&fetched_policy.local_path) {
-                info!(
-                    name = name.as_str(),
-                    path = fetched_policy.local_path.clone().into_os_string().to_str(),
-                    sha256sum = fetched_policy
-                        .digest()
-                        .unwrap_or_else(|_| "unknown".to_string())
-                        .as_str(),
-                    mutating = policy_metadata.mutating,
-                    "policy download",
-                );
-            } else {
-                info!(
-                    name = name.as_str(),
-                    path = fetched_policy.local_path.clone().into_os_string().to_str(),
-                    sha256sum = fetched_policy
-                        .digest()
-                        .unwrap_or_else(|_| "unknown".to_string())
-                        .as_str(),
-                    "policy download",
-                );
-            }
+            let digest = fetched_policy.digest().ok();
+            let metadata = Metadata::from_path(&fetched_policy.local_path)
+                .ok()
+                .flatten();
+            info!(
+                name = name.as_str(),
+                path = fetched_policy.local_path.clone().into_os_string().to_str(),
+                sha256sum = digest.as_deref().unwrap_or("unknown"),
+                mutating = metadata.as_ref().map(|metadata| metadata.mutating),
+                "policy download",
+            );
 
-            fetched_policies.insert(policy.url.clone(), fetched_policy.local_path);
+            fetched_policies.insert(
+                policy.url.clone(),
+                FetchedPolicy {
+                    local_path: fetched_policy.local_path,
+                    digest,
+                    metadata,
+                    verification: verified_manifest_digest.map(|verified_digest| Verification {
+                        verified_digest,
+                        signatures: verification_config.clone(),
+                    }),
+                },
+            );
         }
 
         if policy_verification_errors.is_empty() {
@@ -0,0 +1,408 @@
+use anyhow::{anyhow, Result};
+use policy_evaluator::admission_response::AdmissionResponse;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use tokio::runtime::Runtime;
+use tracing::info;
+
+use crate::admission_review::{AdmissionRequest, AdmissionReview};
+use crate::cli;
+use crate::eval::{read_json_documents, EvaluationResult, EvaluatorSettings, OfflineEvaluator};
+use crate::settings::{read_policies_file, PolicyEntry};
+
+/// The responses obtained by replaying a corpus of requests:
+/// * key: the ID of the policy and the UID of the request
+/// * value: the response of the policy
+type Responses = BTreeMap<(String, String), AdmissionResponse>;
+
+/// A value that differs between the baseline and the candidate
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Change<T> {
+    pub baseline: T,
+    pub candidate: T,
+}
+
+impl<T: PartialEq> Change<T> {
+    fn new(baseline: T, candidate: T) -> Option<Change<T>> {
+        if baseline == candidate {
+            None
+        } else {
+            Some(Change {
+                baseline,
+                candidate,
+            })
+        }
+    }
+}
+
+/// How the response of a policy to a request changed
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Difference {
+    pub policy_id: String,
+    pub request_uid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<Change<bool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mutated: Option<Change<bool>>,
+    /// The base64 encoded JSONPatch objects, reported only when both
+    /// responses are mutating the object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<Change<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Change<Option<String>>>,
+}
+
+/// A response that has no counterpart, for example because the policy
+/// is not part of both sets of policies
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Unmatched {
+    pub policy_id: String,
+    pub request_uid: String,
+    pub found_in: Side,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Side {
+    Baseline,
+    Candidate,
+}
+
+/// The report printed by the `replay` subcommand
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplayReport {
+    pub compared: usize,
+    pub differences: Vec<Difference>,
+    pub unmatched: Vec<Unmatched>,
+}
+
+impl ReplayReport {
+    pub fn new(baseline: &Responses, candidate: &Responses) -> ReplayReport {
+        let mut report = ReplayReport::default();
+
+        for ((policy_id, request_uid), baseline_response) in baseline {
+            let candidate_response = match candidate.get(&(policy_id.clone(), request_uid.clone()))
+            {
+                Some(r) => r,
+                None => {
+                    report.unmatched.push(Unmatched {
+                        policy_id: policy_id.clone(),
+                        request_uid: request_uid.clone(),
+                        found_in: Side::Baseline,
+                    });
+                    continue;
+                }
+            };
+
+            report.compared += 1;
+            let mutated = Change::new(
+                baseline_response.patch.is_some(),
+                candidate_response.patch.is_some(),
+            );
+            let patch = if baseline_response.patch.is_some() && candidate_response.patch.is_some() {
+                Change::new(
+                    baseline_response.patch.clone(),
+                    candidate_response.patch.clone(),
+                )
+            } else {
+                None
+            };
+            let difference = Difference {
+                policy_id: policy_id.clone(),
+                request_uid: request_uid.clone(),
+                allowed: Change::new(baseline_response.allowed, candidate_response.allowed),
+                mutated,
+                patch,
+                message: Change::new(message(baseline_response), message(candidate_response)),
+            };
+            if difference.allowed.is_some()
+                || difference.mutated.is_some()
+                || difference.patch.is_some()
+                || difference.message.is_some()
+            {
+                report.differences.push(difference);
+            }
+        }
+
+        report.unmatched.extend(
+            candidate
+                .keys()
+                .filter(|key| !baseline.contains_key(key))
+                .map(|(policy_id, request_uid)| Unmatched {
+                    policy_id: policy_id.clone(),
+                    request_uid: request_uid.clone(),
+                    found_in: Side::Candidate,
+                }),
+        );
+
+        report
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.differences.is_empty() && self.unmatched.is_empty()
+    }
+}
+
+fn message(response: &AdmissionResponse) -> Option<String> {
+    response
+        .status
+        .as_ref()
+        .and_then(|status| status.message.clone())
+}
+
+/// Runs the `replay` subcommand: the requests of the corpus are evaluated
+/// by the policies and the responses are compared against the ones
+/// given by the candidate policies, or against the expected ones. The
+/// report is printed to the standard output.
+///
+/// Returns `true` when no difference has been found.
+pub(crate) fn run(matches: &clap::ArgMatches) -> Result<bool> {
+    cli::setup_stderr_tracing(matches);
+
+    let settings = EvaluatorSettings::from_matches(matches)?;
+    let policies = cli::policies(matches)?;
+    let corpus = read_corpus(matches.get_one::<String>("corpus").unwrap())?;
+
+    let candidate_policies = match matches.get_one::<String>("candidate-policies") {
+        Some(path) => Some(read_policies_file(Path::new(path)).map_err(|e| {
+            anyhow!(
+                "error while loading candidate policies from {}: {}",
+                path,
+                e
+            )
+        })?),
+        None => None,
+    };
+    let expected_responses = match matches.get_one::<String>("expected-responses") {
+        Some(path) => Some(expected_responses(read_json_documents(path)?)?),
+        None => None,
+    };
+
+    let rt = Runtime::new()?;
+    let report = rt.block_on(async {
+        let responses = evaluate_corpus(&settings, policies, &corpus).await?;
+        let report = match (candidate_policies, expected_responses) {
+            (Some(candidate_policies), _) => {
+                let candidate_responses =
+                    evaluate_corpus(&settings, candidate_policies, &corpus).await?;
+                ReplayReport::new(&responses, &candidate_responses)
+            }
+            (None, Some(expected_responses)) => ReplayReport::new(&expected_responses, &responses),
+            (None, None) => {
+                return Err(anyhow!(
+                    "either candidate policies or expected responses must be provided"
+                ))
+            }
+        };
+        Ok::<ReplayReport, anyhow::Error>(report)
+    })?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(report.is_clean())
+}
+
+/// Reads the recorded AdmissionReviews, the UID of their requests must
+/// be unique
+fn read_corpus(path: &str) -> Result<Vec<AdmissionRequest>> {
+    let mut uids = HashSet::new();
+
+    read_json_documents::<AdmissionReview>(path)?
+        .into_iter()
+        .map(|admission_review| {
+            let request = admission_review.request.ok_or_else(|| {
+                anyhow!("No Request object defined inside AdmissionReview object")
+            })?;
+            if !uids.insert(request.uid.clone()) {
+                return Err(anyhow!("Duplicated request UID: {}", request.uid));
+            }
+            Ok(request)
+        })
+        .collect()
+}
+
+fn expected_responses(results: Vec<EvaluationResult>) -> Result<Responses> {
+    results
+        .into_iter()
+        .map(|result| {
+            let policy_id = result.policy_id;
+            let response = result
+                .response
+                .response
+                .ok_or_else(|| anyhow!("No Response object defined for policy {}", policy_id))?;
+            Ok(((policy_id, response.uid.clone()), response))
+        })
+        .collect()
+}
+
+async fn evaluate_corpus(
+    settings: &EvaluatorSettings,
+    policies: HashMap<String, PolicyEntry>,
+    corpus: &[AdmissionRequest],
+) -> Result<Responses> {
+    let policy_ids: Vec<String> = policies.keys().cloned().collect();
+    let evaluator = OfflineEvaluator::new(settings, policies).await?;
+
+    let mut responses = Responses::new();
+    for req in corpus {
+        for policy_id in &policy_ids {
+            let response = evaluator.evaluate(policy_id, req.clone()).await?;
+            responses.insert((policy_id.clone(), req.uid.clone()), response);
+        }
+    }
+    info!(
+        requests = corpus.len(),
+        policies = policy_ids.len(),
+        "corpus replayed"
+    );
+
+    evaluator.shutdown().await?;
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use policy_evaluator::admission_response::AdmissionResponseStatus;
+
+    fn response(allowed: bool, patch: Option<&str>, message: Option<&str>) -> AdmissionResponse {
+        AdmissionResponse {
+            uid: String::from("UID"),
+            allowed,
+            patch: patch.map(String::from),
+            status: message.map(|message| AdmissionResponseStatus {
+                message: Some(message.to_string()),
+                code: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn responses(entries: Vec<(&str, &str, AdmissionResponse)>) -> Responses {
+        entries
+            .into_iter()
+            .map(|(policy_id, uid, response)| ((policy_id.to_string(), uid.to_string()), response))
+            .collect()
+    }
+
+    #[test]
+    fn report_differences() {
+        let baseline = responses(vec![
+            ("unchanged", "1", response(true, None, None)),
+            ("rejecting", "1", response(true, None, None)),
+            ("mutating", "1", response(true, None, None)),
+            ("patch", "1", response(true, Some("old"), None)),
+            ("message", "1", response(false, None, Some("old"))),
+            ("removed", "1", response(true, None, None)),
+        ]);
+        let candidate = responses(vec![
+            ("unchanged", "1", response(true, None, None)),
+            ("rejecting", "1", response(false, None, Some("rejected"))),
+            ("mutating", "1", response(true, Some("patch"), None)),
+            ("patch", "1", response(true, Some("new"), None)),
+            ("message", "1", response(false, None, Some("new"))),
+            ("added", "1", response(true, None, None)),
+        ]);
+
+        let report = ReplayReport::new(&baseline, &candidate);
+        assert_eq!(report.compared, 5);
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.differences,
+            vec![
+                Difference {
+                    policy_id: String::from("message"),
+                    request_uid: String::from("1"),
+                    allowed: None,
+                    mutated: None,
+                    patch: None,
+                    message: Some(Change {
+                        baseline: Some(String::from("old")),
+                        candidate: Some(String::from("new")),
+                    }),
+                },
+                Difference {
+                    policy_id: String::from("mutating"),
+                    request_uid: String::from("1"),
+                    allowed: None,
+                    mutated: Some(Change {
+                        baseline: false,
+                        candidate: true,
+                    }),
+                    patch: None,
+                    message: None,
+                },
+                Difference {
+                    policy_id: String::from("patch"),
+                    request_uid: String::from("1"),
+                    allowed: None,
+                    mutated: None,
+                    patch: Some(Change {
+                        baseline: Some(String::from("old")),
+                        candidate: Some(String::from("new")),
+                    }),
+                    message: None,
+                },
+                Difference {
+                    policy_id: String::from("rejecting"),
+                    request_uid: String::from("1"),
+                    allowed: Some(Change {
+                        baseline: true,
+                        candidate: false,
+                    }),
+                    mutated: None,
+                    patch: None,
+                    message: Some(Change {
+                        baseline: None,
+                        candidate: Some(String::from("rejected")),
+                    }),
+                },
+            ]
+        );
+        assert_eq!(
+            report.unmatched,
+            vec![
+                Unmatched {
+                    policy_id: String::from("removed"),
+                    request_uid: String::from("1"),
+                    found_in: Side::Baseline,
+                },
+                Unmatched {
+                    policy_id: String::from("added"),
+                    request_uid: String::from("1"),
+                    found_in: Side::Candidate,
+                },
+            ]
+        );
+
+        assert!(ReplayReport::new(&baseline, &baseline).is_clean());
+    }
+
+    #[test]
+    fn expected_responses_from_eval_output() {
+        let results: Vec<EvaluationResult> = vec![
+            serde_json::from_str(
+                r#"{"policyId":"psp","response":{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview","response":{"uid":"1","allowed":false,"status":{"message":"nope"}}}}"#,
+            )
+            .unwrap(),
+            serde_json::from_str(
+                r#"{"policyId":"psp","response":{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview","response":{"uid":"2","allowed":true}}}"#,
+            )
+            .unwrap(),
+        ];
+
+        let responses = expected_responses(results).unwrap();
+        let keys: Vec<(String, String)> = responses.keys().cloned().collect();
+        assert_eq!(
+            keys,
+            vec![
+                (String::from("psp"), String::from("1")),
+                (String::from("psp"), String::from("2"))
+            ]
+        );
+        assert!(!responses[&(String::from("psp"), String::from("1"))].allowed);
+    }
+}
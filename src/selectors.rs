@@ -0,0 +1,316 @@
+//! Decides whether a request is in the scope of a policy, before the policy
+//! is evaluated. Requests out of the scope of a policy are accepted without
+//! instantiating its WebAssembly module.
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::admission_review::AdmissionRequest;
+use crate::settings::Policy;
+
+type Labels = BTreeMap<String, String>;
+
+lazy_static! {
+    /// The labels of the namespaces of the cluster, by name. Refreshed by
+    /// the Kubernetes poller together with the cluster context
+    static ref NAMESPACE_LABELS: RwLock<HashMap<String, Labels>> = RwLock::new(HashMap::new());
+}
+
+/// Replaces the labels of the namespaces with the ones of the given list of
+/// namespaces, as serialized by the cluster context
+pub(crate) fn refresh_namespace_labels(namespaces: &str) -> Result<()> {
+    let namespaces: serde_json::Value = serde_json::from_str(namespaces)
+        .map_err(|e| anyhow!("cannot parse the list of namespaces: {}", e))?;
+    let labels = namespaces
+        .get("items")
+        .and_then(|items| items.as_array())
+        .ok_or_else(|| anyhow!("the list of namespaces has no items"))?
+        .iter()
+        .filter_map(|namespace| {
+            let metadata = namespace.get("metadata")?;
+            let name = metadata.get("name")?.as_str()?;
+            Some((name.to_string(), labels_of(metadata)))
+        })
+        .collect();
+    *NAMESPACE_LABELS.write().unwrap() = labels;
+
+    Ok(())
+}
+
+/// Returns true when the policy has to evaluate the request. The matching
+/// rules follow the ones of the Kubernetes admission webhooks
+pub(crate) fn in_scope(policy: &Policy, req: &AdmissionRequest) -> bool {
+    if let Some(operations) = &policy.operations {
+        if !operations
+            .iter()
+            .any(|operation| operation == "*" || *operation == req.operation)
+        {
+            return false;
+        }
+    }
+    if let Some(resources) = &policy.resources {
+        if !resources
+            .iter()
+            .any(|resource| resource_matches(resource, req))
+        {
+            return false;
+        }
+    }
+    if let Some(selector) = &policy.object_selector {
+        let labels = object_labels(req);
+        if !labels
+            .iter()
+            .any(|labels| selector_matches(selector, labels))
+        {
+            return false;
+        }
+    }
+    if let Some(selector) = &policy.namespace_selector {
+        if let Some(labels) = namespace_labels(req) {
+            if !selector_matches(selector, &labels) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Returns an error when the selector cannot be evaluated
+pub(crate) fn validate_selector(selector: &LabelSelector) -> Result<()> {
+    for requirement in selector.match_expressions.iter().flatten() {
+        let has_values = requirement
+            .values
+            .as_ref()
+            .map_or(false, |values| !values.is_empty());
+        match requirement.operator.as_str() {
+            "In" | "NotIn" if !has_values => {
+                return Err(anyhow!(
+                    "the {} operator of the key {} requires values",
+                    requirement.operator,
+                    requirement.key
+                ))
+            }
+            "Exists" | "DoesNotExist" if has_values => {
+                return Err(anyhow!(
+                    "the {} operator of the key {} does not accept values",
+                    requirement.operator,
+                    requirement.key
+                ))
+            }
+            "In" | "NotIn" | "Exists" | "DoesNotExist" => {}
+            operator => {
+                return Err(anyhow!(
+                    "unknown operator {} for the key {}",
+                    operator,
+                    requirement.key
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Matches a rule like `pods`, `pods/exec`, `*/scale` or `*` against the
+/// resource and the subresource of the request. `*` does not match
+/// subresources, `*/*` matches everything
+fn resource_matches(rule: &str, req: &AdmissionRequest) -> bool {
+    let (rule_resource, rule_sub_resource) = match rule.split_once('/') {
+        Some((resource, sub_resource)) => (resource, Some(sub_resource)),
+        None => (rule, None),
+    };
+    let part_matches = |rule: &str, value: &str| rule == "*" || rule == value;
+
+    part_matches(rule_resource, &req.resource.resource)
+        && match (rule_sub_resource, req.sub_resource.as_deref()) {
+            (None, None) => true,
+            (Some(rule), Some(sub_resource)) => part_matches(rule, sub_resource),
+            _ => false,
+        }
+}
+
+fn selector_matches(selector: &LabelSelector, labels: &Labels) -> bool {
+    selector
+        .match_labels
+        .iter()
+        .flatten()
+        .all(|(key, value)| labels.get(key) == Some(value))
+        && selector
+            .match_expressions
+            .iter()
+            .flatten()
+            .all(|requirement| requirement_matches(requirement, labels))
+}
+
+fn requirement_matches(requirement: &LabelSelectorRequirement, labels: &Labels) -> bool {
+    let value = labels.get(&requirement.key);
+    let in_values = |value: &String| {
+        requirement
+            .values
+            .iter()
+            .flatten()
+            .any(|candidate| candidate == value)
+    };
+    match requirement.operator.as_str() {
+        "In" => value.map_or(false, in_values),
+        "NotIn" => !value.map_or(false, in_values),
+        "Exists" => value.is_some(),
+        "DoesNotExist" => value.is_none(),
+        _ => false,
+    }
+}
+
+fn labels_of(metadata: &serde_json::Value) -> Labels {
+    metadata
+        .get("labels")
+        .and_then(|labels| labels.as_object())
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The labels of the object and of the old object of the request, when
+/// they are set
+fn object_labels(req: &AdmissionRequest) -> Vec<Labels> {
+    let labels: Vec<Labels> = [&req.object, &req.old_object]
+        .iter()
+        .filter_map(|object| object.as_ref())
+        .map(|object| object.0.get("metadata").map(labels_of).unwrap_or_default())
+        .collect();
+    if labels.is_empty() {
+        vec![Labels::new()]
+    } else {
+        labels
+    }
+}
+
+/// The labels the namespace selector is matched against. Namespaces are
+/// matched using their own labels. `None` is returned when the labels are
+/// not known, or when the resource is not namespaced: the request is
+/// evaluated in both cases
+fn namespace_labels(req: &AdmissionRequest) -> Option<Labels> {
+    if req.kind.group.is_empty() && req.kind.kind == "Namespace" {
+        return object_labels(req).into_iter().next();
+    }
+    let namespace = req.namespace.as_ref()?;
+    NAMESPACE_LABELS.read().unwrap().get(namespace).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(resource: &str, sub_resource: Option<&str>, labels: &str) -> AdmissionRequest {
+        serde_json::from_value(serde_json::json!({
+            "uid": "uid",
+            "kind": {"group": "", "version": "v1", "kind": "Pod"},
+            "resource": {"group": "", "version": "v1", "resource": resource},
+            "subResource": sub_resource,
+            "namespace": "selectors-test",
+            "operation": "CREATE",
+            "userInfo": {},
+            "object": {
+                "metadata": {
+                    "name": "nginx",
+                    "labels": serde_json::from_str::<serde_json::Value>(labels).unwrap()
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn policy(scope: &str) -> Policy {
+        serde_yaml::from_str(&format!("url: file:///tmp/policy.wasm\n{}", scope)).unwrap()
+    }
+
+    #[test]
+    fn operations_and_resources() {
+        let req = request("pods", None, "{}");
+        assert!(in_scope(&policy(""), &req));
+        assert!(in_scope(&policy("operations: [CREATE, UPDATE]"), &req));
+        assert!(in_scope(&policy("operations: ['*']"), &req));
+        assert!(!in_scope(&policy("operations: [DELETE]"), &req));
+
+        assert!(in_scope(&policy("resources: [deployments, pods]"), &req));
+        assert!(in_scope(&policy("resources: ['*']"), &req));
+        assert!(!in_scope(&policy("resources: [pods/exec]"), &req));
+
+        let req = request("pods", Some("exec"), "{}");
+        assert!(!in_scope(&policy("resources: [pods, '*']"), &req));
+        assert!(in_scope(&policy("resources: [pods/exec]"), &req));
+        assert!(in_scope(&policy("resources: ['pods/*']"), &req));
+        assert!(in_scope(&policy("resources: ['*/*']"), &req));
+    }
+
+    #[test]
+    fn object_selector() {
+        let req = request("pods", None, r#"{"app": "nginx", "tier": "frontend"}"#);
+        assert!(in_scope(
+            &policy("objectSelector:\n  matchLabels:\n    app: nginx"),
+            &req
+        ));
+        assert!(!in_scope(
+            &policy("objectSelector:\n  matchLabels:\n    app: redis"),
+            &req
+        ));
+        assert!(in_scope(
+            &policy("objectSelector:\n  matchExpressions:\n  - {key: tier, operator: In, values: [frontend, backend]}\n  - {key: legacy, operator: DoesNotExist}"),
+            &req
+        ));
+        assert!(!in_scope(
+            &policy("objectSelector:\n  matchExpressions:\n  - {key: tier, operator: NotIn, values: [frontend]}"),
+            &req
+        ));
+        assert!(!in_scope(
+            &policy("objectSelector:\n  matchExpressions:\n  - {key: team, operator: Exists}"),
+            &req
+        ));
+    }
+
+    #[test]
+    fn namespace_selector() {
+        let req = request("pods", None, "{}");
+        let selector = policy("namespaceSelector:\n  matchLabels:\n    environment: production");
+
+        // Labels not known yet, the policy is evaluated
+        assert!(in_scope(&selector, &req));
+
+        refresh_namespace_labels(
+            r#"{"items": [
+                {"metadata": {"name": "selectors-test", "labels": {"environment": "staging"}}},
+                {"metadata": {"name": "kube-system"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(!in_scope(&selector, &req));
+        assert!(in_scope(
+            &policy("namespaceSelector:\n  matchLabels:\n    environment: staging"),
+            &req
+        ));
+
+        assert!(refresh_namespace_labels("").is_err());
+    }
+
+    #[test]
+    fn validate_selectors() {
+        let selector = |expressions: &str| -> LabelSelector {
+            serde_yaml::from_str(&format!("matchExpressions:\n{}", expressions)).unwrap()
+        };
+        assert!(validate_selector(&selector("- {key: tier, operator: In, values: [a]}")).is_ok());
+        assert!(validate_selector(&selector("- {key: tier, operator: In}")).is_err());
+        assert!(
+            validate_selector(&selector("- {key: tier, operator: Exists, values: [a]}")).is_err()
+        );
+        assert!(
+            validate_selector(&selector("- {key: tier, operator: Equals, values: [a]}")).is_err()
+        );
+    }
+}
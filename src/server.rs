@@ -1,61 +1,388 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::SocketAddr;
-use tokio::sync::mpsc::Sender;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, mpsc::Sender, watch};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+use warp::http::{header, Request, Response, StatusCode};
+use warp::hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body,
+};
 
+use crate::cli::LogLevelHandle;
 use crate::communication::EvalRequest;
+use crate::metrics;
+use crate::policy_downloader::FetchedPolicies;
+use crate::settings::PolicyEntry;
+use crate::tls::{self, CertResolver, ClientAuthorizer};
+
+/// The path of the readiness probe, served also to the clients that do
+/// not present a certificate
+const READINESS_PATH: &str = "/readiness";
 
 pub(crate) struct TlsConfig {
     pub cert_file: String,
     pub key_file: String,
+    /// When set, clients must present a certificate signed by one of the
+    /// CAs of this bundle
+    pub client_ca_file: Option<String>,
+    /// When not empty, the subject or one of the alternative names of the
+    /// client certificate must be part of this list
+    pub allowed_client_names: Vec<String>,
+}
+
+/// The state shared with the validation endpoints
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub api_tx: Sender<EvalRequest>,
+    /// Used to find the failure policy of the requests that are shed
+    pub policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+    /// How long a request can wait for a worker before being shed.
+    /// Requests are never shed when not set
+    pub max_queue_wait: Option<Duration>,
+}
+
+/// The state shared with the admin endpoints
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+    /// The WebAssembly modules of the policies, by URL
+    pub modules: Arc<RwLock<FetchedPolicies>>,
+    pub log_level: LogLevelHandle,
+    /// Cleared once the shutdown started
+    pub ready: Arc<AtomicBool>,
 }
 
 pub(crate) async fn run_server(
     addr: &SocketAddr,
     tls_config: Option<TlsConfig>,
-    api_tx: Sender<EvalRequest>,
-) {
-    let ip = addr.ip();
-    let port = addr.port();
-
-    let routes = filters::routes(api_tx);
-
+    state: ApiState,
+    ready: Arc<AtomicBool>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
     match tls_config {
-        None => warp::serve(routes).run((ip, port)).await,
-        Some(cfg) => {
-            warp::serve(routes)
-                .tls()
-                .cert_path(cfg.cert_file)
-                .key_path(cfg.key_file)
-                .run((ip, port))
-                .await
+        None => {
+            let (_, server) = warp::serve(filters::routes(state, ready))
+                .try_bind_with_graceful_shutdown(*addr, shutdown_requested(shutdown_rx))
+                .map_err(|e| anyhow!("cannot bind to {}: {}", addr, e))?;
+            server.await;
+            Ok(())
         }
-    };
+        Some(cfg) => run_tls_server(addr, &cfg, state, ready, shutdown_rx).await,
+    }
+}
+
+/// Completes once the shutdown of the server has been requested
+pub(crate) async fn shutdown_requested(mut shutdown_rx: watch::Receiver<bool>) {
+    while !*shutdown_rx.borrow() {
+        if shutdown_rx.changed().await.is_err() {
+            // The sender is gone, nobody can request the shutdown anymore
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Completes once SIGTERM or SIGINT is received
+pub(crate) async fn shutdown_signal() -> Result<()> {
+    let mut sigterm =
+        signal(SignalKind::terminate()).map_err(|e| anyhow!("cannot listen for SIGTERM: {}", e))?;
+    let mut sigint =
+        signal(SignalKind::interrupt()).map_err(|e| anyhow!("cannot listen for SIGINT: {}", e))?;
+
+    tokio::select! {
+        _ = sigterm.recv() => info!(signal = "SIGTERM", "shutdown signal received"),
+        _ = sigint.recv() => info!(signal = "SIGINT", "shutdown signal received"),
+    }
+    Ok(())
+}
+
+/// Serves the API over HTTPS. Each connection is checked by the
+/// `ClientAuthorizer` once the TLS handshake is done: refused clients
+/// get a `401` response to all their requests.
+///
+/// The certificate is reloaded whenever its files change, without
+/// affecting the connections that are already established.
+///
+/// Once the shutdown is requested no new connection is accepted, the
+/// established ones are closed as soon as their in-flight requests are
+/// done. The function returns when all of them are closed.
+async fn run_tls_server(
+    addr: &SocketAddr,
+    tls_config: &TlsConfig,
+    state: ApiState,
+    ready: Arc<AtomicBool>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let cert_resolver = Arc::new(CertResolver::new(
+        &tls_config.cert_file,
+        &tls_config.key_file,
+    )?);
+    let acceptor = TlsAcceptor::from(tls::server_config(tls_config, cert_resolver.clone())?);
+    tokio::spawn(tls::watch_certificate(
+        cert_resolver,
+        tls_config.cert_file.clone(),
+        tls_config.key_file.clone(),
+    ));
+    let client_authorizer = ClientAuthorizer::new(tls_config);
+    let service = warp::service(filters::routes(state, ready));
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("cannot bind to {}: {}", addr, e))?;
+
+    // Every connection task holds a clone of the sender, the receiver
+    // is notified once all of them are gone
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(shutdown_rx.clone()) => break,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = e.to_string().as_str(), "cannot accept connection");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let client_authorizer = client_authorizer.clone();
+        let service = service.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        let drain_tx = drain_tx.clone();
+
+        tokio::spawn(async move {
+            let _drain_tx = drain_tx;
+            let stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(e) => {
+                    metrics::add_failed_tls_handshake(tls::handshake_failure_reason(&e));
+                    warn!(
+                        peer = %peer_addr,
+                        error = e.to_string().as_str(),
+                        "TLS handshake failed"
+                    );
+                    return;
+                }
+            };
+
+            // The refusal is counted once per connection, when the client
+            // makes its first refused request
+            let refusal = client_authorizer
+                .authorize(stream.get_ref().1.peer_certificates())
+                .err()
+                .map(Arc::new);
+            let refusal_counted = Arc::new(AtomicBool::new(false));
+            let service = service_fn(move |req: Request<Body>| {
+                let mut service = service.clone();
+                let refusal = refusal.clone();
+                let refusal_counted = refusal_counted.clone();
+                async move {
+                    match refusal {
+                        // The kubelet probes do not present a client
+                        // certificate
+                        Some(refusal) if req.uri().path() != READINESS_PATH => {
+                            if !refusal_counted.swap(true, Ordering::SeqCst) {
+                                metrics::add_refused_tls_handshake(refusal.reason());
+                                warn!(peer = %peer_addr, reason = %refusal, "client refused");
+                            }
+                            Ok::<_, Infallible>(unauthorized(&refusal.to_string()))
+                        }
+                        _ => service.call(req).await,
+                    }
+                }
+            });
+
+            let conn = Http::new().serve_connection(stream, service);
+            tokio::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = shutdown_requested(shutdown_rx) => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = res {
+                debug!(
+                    peer = %peer_addr,
+                    error = e.to_string().as_str(),
+                    "connection error"
+                );
+            }
+        });
+    }
+
+    drop(listener);
+    drop(drain_tx);
+    debug!("waiting for the open connections to be closed");
+    let _ = drain_rx.recv().await;
+
+    Ok(())
+}
+
+fn unauthorized(message: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "message": message }).to_string(),
+        ))
+        .unwrap()
+}
+
+/// Serves the metrics using the Prometheus text format
+pub(crate) async fn run_metrics_server(addr: SocketAddr) {
+    warp::serve(filters::metrics()).run(addr).await
+}
+
+/// Serves the operational endpoints over plain HTTP. These must not be
+/// reachable by the Kubernetes API server
+pub(crate) async fn run_admin_server(addr: SocketAddr, state: AdminState) {
+    warp::serve(filters::admin_routes(state)).run(addr).await
 }
 
 mod filters {
-    use super::{EvalRequest, Sender};
+    use super::{AdminState, ApiState};
+    use crate::cli::LogLevelHandle;
+    use crate::policy_downloader::FetchedPolicies;
+    use crate::settings::PolicyEntry;
+    use std::collections::HashMap;
+    use std::sync::{atomic::AtomicBool, Arc, RwLock};
     use warp::Filter;
 
+    /// The maximum size of the body of the batch validation requests
+    const MAX_BATCH_SIZE: u64 = 16 * 1024 * 1024;
+
     pub(crate) fn routes(
-        api_tx: Sender<EvalRequest>,
+        state: ApiState,
+        ready: Arc<AtomicBool>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        validate(api_tx).or(readiness())
+        validate(state.clone())
+            .or(validate_batch(state.clone()))
+            .or(validate_raw(state))
+            .or(readiness(ready))
+    }
+
+    pub(crate) fn admin_routes(
+        state: AdminState,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        readiness(state.ready)
+            .or(liveness())
+            .or(metrics())
+            .or(policies(state.policies, state.modules))
+            .or(log_level(state.log_level))
     }
 
     fn validate(
-        api_tx: Sender<EvalRequest>,
+        state: ApiState,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         // POST /validate/:policy_id with JSON body
         warp::path!("validate" / String)
             .and(warp::post())
             .and(warp::body::json())
-            .and(warp::any().map(move || api_tx.clone()))
+            .and(warp::any().map(move || state.clone()))
             .and_then(crate::api::validation)
     }
 
-    fn readiness() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    fn validate_raw(
+        state: ApiState,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // POST /validate_raw/:policy_id with JSON body
+        warp::path!("validate_raw" / String)
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(warp::any().map(move || state.clone()))
+            .and_then(crate::api::raw_validation)
+    }
+
+    fn validate_batch(
+        state: ApiState,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // POST /validate-batch with a stream of JSON documents or a YAML
+        // manifest as body
+        warp::path!("validate-batch")
+            .and(warp::post())
+            .and(warp::query())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::content_length_limit(MAX_BATCH_SIZE))
+            .and(warp::body::bytes())
+            .and(warp::any().map(move || state.clone()))
+            .and_then(crate::api::batch_validation)
+    }
+
+    pub(crate) fn metrics(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // GET /metrics
+        warp::path!("metrics")
+            .and(warp::get())
+            .and_then(crate::api::prometheus_metrics)
+    }
+
+    fn readiness(
+        ready: Arc<AtomicBool>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         // GET /readiness
         warp::path!("readiness")
             .and(warp::get())
+            .and(warp::any().map(move || ready.clone()))
             .and_then(crate::api::readiness)
     }
+
+    fn liveness() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // GET /liveness
+        warp::path!("liveness")
+            .and(warp::get())
+            .and_then(crate::api::liveness)
+    }
+
+    fn policies(
+        policies: Arc<RwLock<HashMap<String, PolicyEntry>>>,
+        modules: Arc<RwLock<FetchedPolicies>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let with_policies = warp::any().map(move || policies.clone());
+        let with_modules = warp::any().map(move || modules.clone());
+        // GET /policies
+        let list = warp::path!("policies")
+            .and(warp::get())
+            .and(with_policies.clone())
+            .and(with_modules.clone())
+            .and_then(crate::api::policies_inventory);
+        // GET /policies/:policy_id
+        let get = warp::path!("policies" / String)
+            .and(warp::get())
+            .and(with_policies)
+            .and(with_modules)
+            .and_then(crate::api::policy_inventory);
+
+        list.or(get)
+    }
+
+    fn log_level(
+        log_level_handle: LogLevelHandle,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let get_handle = log_level_handle.clone();
+        // GET /log-level
+        let get = warp::path!("log-level")
+            .and(warp::get())
+            .and(warp::any().map(move || get_handle.clone()))
+            .and_then(crate::api::get_log_level);
+        // PUT /log-level with JSON body
+        let put = warp::path!("log-level")
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(warp::any().map(move || log_level_handle.clone()))
+            .and_then(crate::api::set_log_level);
+
+        get.or(put)
+    }
 }
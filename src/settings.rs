@@ -1,18 +1,30 @@
 use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 
-use serde::Deserialize;
+use policy_evaluator::admission_response::AdmissionResponse;
+use serde::{de, Deserialize, Deserializer};
 use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fs::File;
 use std::iter::FromIterator;
 use std::path::Path;
+use std::time::Duration;
 
-#[derive(Deserialize, Debug, Clone)]
+use crate::exemptions::Exemption;
+use crate::expression::Expression;
+use crate::selectors::validate_selector;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub enum PolicyMode {
     #[serde(rename = "monitor")]
     Monitor,
     #[serde(rename = "protect")]
     Protect,
+    /// Like `Protect`, but rejections are turned into warnings sent back
+    /// together with an accepted response
+    #[serde(rename = "warn")]
+    Warn,
 }
 
 impl Default for PolicyMode {
@@ -26,11 +38,110 @@ impl From<PolicyMode> for String {
         match policy_mode {
             PolicyMode::Monitor => String::from("monitor"),
             PolicyMode::Protect => String::from("protect"),
+            PolicyMode::Warn => String::from("warn"),
+        }
+    }
+}
+
+/// How a policy handles the dry-run requests, like the ones made via
+/// `kubectl --dry-run=server`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum DryRunMode {
+    /// The requests are evaluated like the other ones
+    #[serde(rename = "evaluate")]
+    Evaluate,
+    /// The requests are accepted without being evaluated
+    #[serde(rename = "skip")]
+    Skip,
+    /// The requests are evaluated in monitor mode
+    #[serde(rename = "monitor")]
+    Monitor,
+}
+
+impl Default for DryRunMode {
+    fn default() -> DryRunMode {
+        DryRunMode::Evaluate
+    }
+}
+
+impl From<DryRunMode> for String {
+    fn from(dry_run_mode: DryRunMode) -> String {
+        match dry_run_mode {
+            DryRunMode::Evaluate => String::from("evaluate"),
+            DryRunMode::Skip => String::from("skip"),
+            DryRunMode::Monitor => String::from("monitor"),
+        }
+    }
+}
+
+impl DryRunMode {
+    /// Returns the mode a request is evaluated with by a policy running in
+    /// the given mode. `None` is returned when the request has to be
+    /// accepted without being evaluated
+    pub fn policy_mode(&self, policy_mode: &PolicyMode, dry_run: bool) -> Option<PolicyMode> {
+        match (dry_run, self) {
+            (false, _) | (true, DryRunMode::Evaluate) => Some(policy_mode.clone()),
+            (true, DryRunMode::Skip) => None,
+            (true, DryRunMode::Monitor) => Some(PolicyMode::Monitor),
+        }
+    }
+}
+
+/// What to do with the requests that cannot be evaluated by a policy
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum FailurePolicy {
+    /// The request is rejected
+    Fail,
+    /// The request is accepted
+    Ignore,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::Fail
+    }
+}
+
+impl From<FailurePolicy> for String {
+    fn from(failure_policy: FailurePolicy) -> String {
+        match failure_policy {
+            FailurePolicy::Fail => String::from("Fail"),
+            FailurePolicy::Ignore => String::from("Ignore"),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl FailurePolicy {
+    /// Builds the response of a request that could not be evaluated by
+    /// the policy. When the request is accepted, the reason is sent back
+    /// as a warning
+    pub fn response(
+        &self,
+        request_uid: &str,
+        policy_id: &str,
+        reason: &str,
+        code: u16,
+    ) -> AdmissionResponse {
+        match self {
+            FailurePolicy::Fail => AdmissionResponse::reject(
+                request_uid.to_string(),
+                format!("Request rejected by policy {}. {}", policy_id, reason),
+                code,
+            ),
+            FailurePolicy::Ignore => AdmissionResponse {
+                uid: request_uid.to_string(),
+                allowed: true,
+                warnings: Some(vec![format!(
+                    "Request accepted by policy {} because of its failure policy. {}",
+                    policy_id, reason
+                )]),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Policy {
     pub url: String,
     #[serde(default, rename = "policyMode")]
@@ -39,9 +150,108 @@ pub struct Policy {
     pub allowed_to_mutate: Option<bool>,
     #[serde(default)]
     pub settings: Option<HashMap<String, Value>>,
+    #[serde(rename = "timeoutEvalSeconds")]
+    pub timeout_eval_seconds: Option<u64>,
+    #[serde(default, rename = "failurePolicy")]
+    pub failure_policy: FailurePolicy,
+    /// How the dry-run requests are handled
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: DryRunMode,
+    /// Only the requests made inside of the namespaces matching this
+    /// selector are evaluated
+    #[serde(rename = "namespaceSelector")]
+    pub namespace_selector: Option<LabelSelector>,
+    /// Only the requests about objects matching this selector are evaluated
+    #[serde(rename = "objectSelector")]
+    pub object_selector: Option<LabelSelector>,
+    /// Only the requests doing one of these operations are evaluated, `*`
+    /// matches all of them
+    #[serde(default)]
+    pub operations: Option<Vec<String>>,
+    /// Only the requests about these resources are evaluated, e.g. `pods`,
+    /// `pods/exec` or `*`
+    #[serde(default)]
+    pub resources: Option<Vec<String>>,
+    /// The requests matching one of these exemptions are accepted
+    #[serde(default)]
+    pub exemptions: Vec<Exemption>,
+    /// A candidate version of the policy, evaluated after the policy
+    #[serde(default)]
+    pub shadow: Option<Box<ShadowPolicy>>,
+}
+
+/// A policy evaluated alongside the policy it shadows, only the decision of
+/// the latter is sent back. The decisions of the two policies are compared
+/// once the response has been sent
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowPolicy {
+    pub url: String,
+    #[serde(default)]
+    pub settings: Option<HashMap<String, Value>>,
+    /// The time budget, in milliseconds, of the evaluation of the shadow
+    /// policy. This is enforced even when the policy timeout protection
+    /// is disabled
+    #[serde(rename = "timeoutEvalMilliseconds")]
+    pub timeout_eval_milliseconds: Option<u64>,
+}
+
+/// Default time budget, in milliseconds, of the evaluation of a shadow
+/// policy
+const SHADOW_TIMEOUT_EVAL_MILLISECONDS: u64 = 1000;
+
+impl ShadowPolicy {
+    /// Returns the time budget of the evaluation of the shadow policy
+    pub fn time_budget(&self) -> Duration {
+        Duration::from_millis(
+            self.timeout_eval_milliseconds
+                .unwrap_or(SHADOW_TIMEOUT_EVAL_MILLISECONDS),
+        )
+    }
 }
 
 impl Policy {
+    /// Returns the maximum number of seconds the evaluation of this policy
+    /// can take. The value set inside of the policy has precedence over
+    /// the global one.
+    ///
+    /// `None` is returned when timeout protection is disabled, which
+    /// happens when no global limit is provided.
+    pub fn evaluation_limit_seconds(&self, global_limit: Option<u64>) -> Option<u64> {
+        global_limit.map(|limit| self.timeout_eval_seconds.unwrap_or(limit))
+    }
+
+    /// Returns the policy evaluated as the shadow of this one. It has the
+    /// same attributes, except for the Wasm module and the settings. The
+    /// evaluation limit does not apply, the time budget of the shadow
+    /// policy does
+    pub fn shadow_policy(&self) -> Option<Policy> {
+        self.shadow.as_ref().map(|shadow| Policy {
+            url: shadow.url.clone(),
+            settings: shadow.settings.clone(),
+            timeout_eval_seconds: None,
+            exemptions: Vec::new(),
+            shadow: None,
+            ..self.clone()
+        })
+    }
+
+    fn validate(&self, policy_id: &str) -> Result<()> {
+        for (name, selector) in [
+            ("namespaceSelector", &self.namespace_selector),
+            ("objectSelector", &self.object_selector),
+        ] {
+            if let Some(selector) = selector {
+                validate_selector(selector)
+                    .map_err(|e| anyhow!("policy {}: invalid {}: {}", policy_id, name, e))?;
+            }
+        }
+        validate_exemptions(&self.exemptions)
+            .map_err(|e| anyhow!("policy {}: {}", policy_id, e))?;
+
+        Ok(())
+    }
+
     pub fn settings_to_json(&self) -> Result<Option<serde_json::Map<String, serde_json::Value>>> {
         match self.settings.as_ref() {
             None => Ok(None),
@@ -58,6 +268,339 @@ impl Policy {
     }
 }
 
+/// A set of policies exposed behind a single endpoint. The verdicts of the
+/// member policies are combined by a boolean expression, see
+/// [`Expression`].
+///
+/// Errors and timeouts of the members are handled according to the
+/// failure policy of the group, the policy mode and the failure policy
+/// of the members are ignored.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PolicyGroup {
+    pub policies: HashMap<String, Policy>,
+    pub expression: Expression,
+    /// Message of the rejections, the messages of the members rejecting
+    /// the request are appended to it
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default, rename = "policyMode")]
+    pub policy_mode: PolicyMode,
+    #[serde(default, rename = "failurePolicy")]
+    pub failure_policy: FailurePolicy,
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: DryRunMode,
+    #[serde(default)]
+    pub exemptions: Vec<Exemption>,
+}
+
+fn validate_exemptions(exemptions: &[Exemption]) -> Result<()> {
+    exemptions.iter().try_for_each(Exemption::validate)
+}
+
+/// Returns the id used to load the shadow of the policy `policy_id`
+pub fn shadow_id(policy_id: &str) -> String {
+    member_id(policy_id, "shadow")
+}
+
+/// Separates the id of a policy group or pipeline from the names of its
+/// members inside of the ids of the latter
+const MEMBER_ID_SEPARATOR: char = '/';
+
+/// Returns the id used to load the policy `member` of the policy group or
+/// pipeline `parent_id`. Member ids cannot be requested through the
+/// `/validate` endpoint, which takes a single path segment
+pub fn member_id(parent_id: &str, member: &str) -> String {
+    format!("{}{}{}", parent_id, MEMBER_ID_SEPARATOR, member)
+}
+
+/// Ensures the id of a policy, or the name of a member of a policy group
+/// or pipeline, cannot be mistaken for the id of another member
+fn validate_id(id: &str) -> Result<()> {
+    if id.contains(MEMBER_ID_SEPARATOR) {
+        return Err(anyhow!("`{}` is not allowed", MEMBER_ID_SEPARATOR));
+    }
+    Ok(())
+}
+
+impl PolicyGroup {
+    fn validate(&self, group_id: &str) -> Result<()> {
+        for member in self.policies.keys() {
+            validate_id(member).map_err(|e| {
+                anyhow!(
+                    "policy group {}: invalid member name {}: {}",
+                    group_id,
+                    member,
+                    e
+                )
+            })?;
+        }
+        if let Some(member) = self
+            .expression
+            .members()
+            .into_iter()
+            .find(|member| !self.policies.contains_key(*member))
+        {
+            return Err(anyhow!(
+                "policy group {}: the expression `{}` references the policy {}, which is not a member of the group",
+                group_id,
+                self.expression,
+                member
+            ));
+        }
+        if let Some((member, _)) = self
+            .policies
+            .iter()
+            .find(|(_, policy)| policy.allowed_to_mutate.unwrap_or(false))
+        {
+            return Err(anyhow!(
+                "policy group {}: the member {} cannot be allowed to mutate requests",
+                group_id,
+                member
+            ));
+        }
+        if let Some((member, _)) = self
+            .policies
+            .iter()
+            .find(|(_, policy)| !policy.exemptions.is_empty())
+        {
+            return Err(anyhow!(
+                "policy group {}: the member {} cannot have exemptions, they must be set on the group",
+                group_id,
+                member
+            ));
+        }
+        if let Some((member, _)) = self
+            .policies
+            .iter()
+            .find(|(_, policy)| policy.dry_run != DryRunMode::default())
+        {
+            return Err(anyhow!(
+                "policy group {}: the member {} cannot set dryRun, it must be set on the group",
+                group_id,
+                member
+            ));
+        }
+        if let Some((member, _)) = self
+            .policies
+            .iter()
+            .find(|(_, policy)| policy.shadow.is_some())
+        {
+            return Err(anyhow!(
+                "policy group {}: the member {} cannot have a shadow policy",
+                group_id,
+                member
+            ));
+        }
+        validate_exemptions(&self.exemptions)
+            .map_err(|e| anyhow!("policy group {}: {}", group_id, e))?;
+
+        Ok(())
+    }
+}
+
+/// A step of a [`PolicyPipeline`]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PipelineStep {
+    pub name: String,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+/// An ordered list of policies exposed behind a single endpoint. Each step
+/// evaluates the object as mutated by the previous ones, the patches of
+/// all the steps are merged into a single one.
+///
+/// Errors and timeouts of the steps are handled according to the failure
+/// policy of the pipeline, the policy mode and the failure policy of the
+/// steps are ignored.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PolicyPipeline {
+    pub pipeline: Vec<PipelineStep>,
+    #[serde(default, rename = "policyMode")]
+    pub policy_mode: PolicyMode,
+    #[serde(default, rename = "failurePolicy")]
+    pub failure_policy: FailurePolicy,
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: DryRunMode,
+    #[serde(default)]
+    pub exemptions: Vec<Exemption>,
+}
+
+impl PolicyPipeline {
+    fn validate(&self, pipeline_id: &str) -> Result<()> {
+        if self.pipeline.is_empty() {
+            return Err(anyhow!("policy pipeline {}: no step defined", pipeline_id));
+        }
+        for step in &self.pipeline {
+            validate_id(&step.name).map_err(|e| {
+                anyhow!(
+                    "policy pipeline {}: invalid step name {}: {}",
+                    pipeline_id,
+                    step.name,
+                    e
+                )
+            })?;
+        }
+        if let Some(step) = self
+            .pipeline
+            .iter()
+            .map(|step| step.name.as_str())
+            .duplicates()
+            .next()
+        {
+            return Err(anyhow!(
+                "policy pipeline {}: the step name {} is used more than once",
+                pipeline_id,
+                step
+            ));
+        }
+        if let Some(step) = self
+            .pipeline
+            .iter()
+            .find(|step| !step.policy.exemptions.is_empty())
+        {
+            return Err(anyhow!(
+                "policy pipeline {}: the step {} cannot have exemptions, they must be set on the pipeline",
+                pipeline_id,
+                step.name
+            ));
+        }
+        if let Some(step) = self
+            .pipeline
+            .iter()
+            .find(|step| step.policy.dry_run != DryRunMode::default())
+        {
+            return Err(anyhow!(
+                "policy pipeline {}: the step {} cannot set dryRun, it must be set on the pipeline",
+                pipeline_id,
+                step.name
+            ));
+        }
+        if let Some(step) = self
+            .pipeline
+            .iter()
+            .find(|step| step.policy.shadow.is_some())
+        {
+            return Err(anyhow!(
+                "policy pipeline {}: the step {} cannot have a shadow policy",
+                pipeline_id,
+                step.name
+            ));
+        }
+        validate_exemptions(&self.exemptions)
+            .map_err(|e| anyhow!("policy pipeline {}: {}", pipeline_id, e))?;
+
+        Ok(())
+    }
+}
+
+/// An entry of the policies configuration file: a policy, a policy group
+/// or a policy pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyEntry {
+    Policy(Policy),
+    PolicyGroup(PolicyGroup),
+    PolicyPipeline(PolicyPipeline),
+}
+
+impl<'de> Deserialize<'de> for PolicyEntry {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Policy groups are told apart by the keys only they can have, this
+        // provides better error messages than an untagged enum
+        let value = Value::deserialize(deserializer)?;
+        if value.get("pipeline").is_some() {
+            serde_yaml::from_value(value)
+                .map(PolicyEntry::PolicyPipeline)
+                .map_err(de::Error::custom)
+        } else if value.get("policies").is_some() || value.get("expression").is_some() {
+            serde_yaml::from_value(value)
+                .map(PolicyEntry::PolicyGroup)
+                .map_err(de::Error::custom)
+        } else {
+            serde_yaml::from_value(value)
+                .map(PolicyEntry::Policy)
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+impl PolicyEntry {
+    pub fn failure_policy(&self) -> &FailurePolicy {
+        match self {
+            PolicyEntry::Policy(policy) => &policy.failure_policy,
+            PolicyEntry::PolicyGroup(group) => &group.failure_policy,
+            PolicyEntry::PolicyPipeline(pipeline) => &pipeline.failure_policy,
+        }
+    }
+}
+
+/// Returns all the policies to be loaded by the workers, including the
+/// members of the policy groups and the steps of the pipelines. These are
+/// identified by [`member_id`]
+pub fn individual_policies(policies: &HashMap<String, PolicyEntry>) -> HashMap<String, Policy> {
+    policies
+        .iter()
+        .flat_map(|(id, entry)| match entry {
+            PolicyEntry::Policy(policy) => vec![(id.clone(), policy.clone())],
+            PolicyEntry::PolicyGroup(group) => group
+                .policies
+                .iter()
+                .map(|(member, policy)| (member_id(id, member), policy.clone()))
+                .collect(),
+            PolicyEntry::PolicyPipeline(pipeline) => pipeline
+                .pipeline
+                .iter()
+                .map(|step| (member_id(id, &step.name), step.policy.clone()))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Returns the shadows of the given individual policies, identified by
+/// [`shadow_id`]
+pub fn shadow_policies(policies: &HashMap<String, Policy>) -> HashMap<String, Policy> {
+    policies
+        .iter()
+        .filter_map(|(id, policy)| policy.shadow_policy().map(|shadow| (shadow_id(id), shadow)))
+        .collect()
+}
+
+/// Returns all the policies whose WebAssembly module has to be fetched:
+/// the individual policies and their shadows
+pub fn policies_to_fetch(policies: &HashMap<String, PolicyEntry>) -> HashMap<String, Policy> {
+    let mut policies = individual_policies(policies);
+    let shadows = shadow_policies(&policies);
+    policies.extend(shadows);
+    policies
+}
+
+/// Returns the policy groups defined by the configuration
+pub fn policy_groups(policies: &HashMap<String, PolicyEntry>) -> HashMap<String, PolicyGroup> {
+    policies
+        .iter()
+        .filter_map(|(id, entry)| match entry {
+            PolicyEntry::PolicyGroup(group) => Some((id.clone(), group.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the policy pipelines defined by the configuration
+pub fn policy_pipelines(
+    policies: &HashMap<String, PolicyEntry>,
+) -> HashMap<String, PolicyPipeline> {
+    policies
+        .iter()
+        .filter_map(|(id, entry)| match entry {
+            PolicyEntry::PolicyPipeline(pipeline) => Some((id.clone(), pipeline.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
 /// Helper function that takes a YAML map and returns a
 /// JSON object.
 fn convert_yaml_map_to_json(
@@ -87,15 +630,33 @@ fn convert_yaml_map_to_json(
 }
 
 /// Reads the policies configuration file, returns a HashMap with String as value
-/// and Policy as values. The key is the name of the policy as provided by the user
-/// inside of the configuration file. This name is used to build the API path
-/// exposing the policy.
-pub fn read_policies_file(path: &Path) -> Result<HashMap<String, Policy>> {
+/// and PolicyEntry as values. The key is the name of the policy as provided
+/// by the user inside of the configuration file. This name is used to build the API
+/// path exposing the policy.
+pub fn read_policies_file(path: &Path) -> Result<HashMap<String, PolicyEntry>> {
     let settings_file = File::open(path)?;
-    let ps: HashMap<String, Policy> = serde_yaml::from_reader(&settings_file)?;
+    let ps: HashMap<String, PolicyEntry> = serde_yaml::from_reader(&settings_file)?;
+    validate_policies(&ps)?;
     Ok(ps)
 }
 
+/// Validates the entries of the policies configuration file
+fn validate_policies(ps: &HashMap<String, PolicyEntry>) -> Result<()> {
+    for id in ps.keys() {
+        validate_id(id).map_err(|e| anyhow!("invalid policy id {}: {}", id, e))?;
+    }
+    for (id, group) in policy_groups(ps) {
+        group.validate(&id)?;
+    }
+    for (id, pipeline) in policy_pipelines(ps) {
+        pipeline.validate(&id)?;
+    }
+    for (id, policy) in individual_policies(ps) {
+        policy.validate(&id)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -199,6 +760,31 @@ example:
         assert!(policy.settings.is_none());
     }
 
+    #[test]
+    fn evaluation_limit_seconds() {
+        let input = r#"
+---
+default-timeout:
+  url: file:///tmp/namespace-validate-policy.wasm
+custom-timeout:
+  url: file:///tmp/namespace-validate-policy.wasm
+  timeoutEvalSeconds: 10
+"#;
+        let policies: HashMap<String, Policy> = serde_yaml::from_str(input).unwrap();
+
+        let policy = policies.get("default-timeout").unwrap();
+        assert_eq!(policy.evaluation_limit_seconds(Some(2)), Some(2));
+        assert_eq!(policy.evaluation_limit_seconds(None), None);
+
+        let policy = policies.get("custom-timeout").unwrap();
+        assert_eq!(policy.evaluation_limit_seconds(Some(2)), Some(10));
+        assert_eq!(
+            policy.evaluation_limit_seconds(None),
+            None,
+            "timeout protection disabled globally"
+        );
+    }
+
     #[test]
     fn handle_yaml_map_with_data() {
         let input = r#"
@@ -251,4 +837,396 @@ example:
         let settings = json_data.unwrap();
         assert!(settings.is_empty());
     }
+
+    #[test]
+    fn failure_policy() {
+        let input = r#"
+---
+default:
+  url: file:///tmp/namespace-validate-policy.wasm
+ignore:
+  url: file:///tmp/namespace-validate-policy.wasm
+  failurePolicy: Ignore
+"#;
+        let policies: HashMap<String, Policy> = serde_yaml::from_str(input).unwrap();
+        assert_eq!(
+            policies.get("default").unwrap().failure_policy,
+            FailurePolicy::Fail
+        );
+        assert_eq!(
+            policies.get("ignore").unwrap().failure_policy,
+            FailurePolicy::Ignore
+        );
+
+        let response = FailurePolicy::Fail.response("uid", "ignore", "Overloaded.", 503);
+        assert!(!response.allowed);
+        let status = response.status.unwrap();
+        assert_eq!(status.code, Some(503));
+        assert_eq!(
+            status.message.unwrap(),
+            "Request rejected by policy ignore. Overloaded."
+        );
+
+        let response = FailurePolicy::Ignore.response("uid", "ignore", "Overloaded.", 503);
+        assert!(response.allowed);
+        assert!(response.status.is_none());
+        assert_eq!(
+            response.warnings,
+            Some(vec![
+                "Request accepted by policy ignore because of its failure policy. Overloaded."
+                    .to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn policy_groups() {
+        let input = r#"
+---
+namespace-validate:
+  url: file:///tmp/namespace-validate-policy.wasm
+pod-security:
+  expression: "privileged() && (capabilities() || host_namespaces())"
+  message: The pod is not secure
+  policyMode: monitor
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+    capabilities:
+      url: file:///tmp/psp-capabilities.wasm
+      settings:
+        allowed_capabilities: []
+    host_namespaces:
+      url: file:///tmp/host-namespaces-psp.wasm
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(input).unwrap();
+        assert!(matches!(
+            policies.get("namespace-validate").unwrap(),
+            PolicyEntry::Policy(_)
+        ));
+
+        let groups = super::policy_groups(&policies);
+        let group = groups.get("pod-security").unwrap();
+        assert!(group.validate("pod-security").is_ok());
+        assert_eq!(group.policy_mode, PolicyMode::Monitor);
+        assert_eq!(group.failure_policy, FailurePolicy::Fail);
+        assert_eq!(group.message, Some("The pod is not secure".to_string()));
+
+        let mut ids: Vec<String> = individual_policies(&policies).into_keys().collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![
+                "namespace-validate",
+                "pod-security/capabilities",
+                "pod-security/host_namespaces",
+                "pod-security/privileged",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_policy_groups() {
+        let unknown_member = r#"
+expression: "privileged() && capabilities()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+"#;
+        let group: PolicyGroup = serde_yaml::from_str(unknown_member).unwrap();
+        assert!(group
+            .validate("pod-security")
+            .unwrap_err()
+            .to_string()
+            .contains("references the policy capabilities"));
+
+        let mutating_member = r#"
+expression: "privileged()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+    allowedToMutate: true
+"#;
+        let group: PolicyGroup = serde_yaml::from_str(mutating_member).unwrap();
+        assert!(group.validate("pod-security").is_err());
+
+        let invalid_expression = r#"
+pod-security:
+  expression: "privileged() &&"
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+"#;
+        assert!(serde_yaml::from_str::<HashMap<String, PolicyEntry>>(invalid_expression).is_err());
+    }
+
+    #[test]
+    fn policy_ids() {
+        // The policy would be loaded with the same id as the member of
+        // the group
+        let member_id_collision = r#"
+pod-security:
+  expression: "privileged()"
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+pod-security/privileged:
+  url: file:///tmp/pod-privileged.wasm
+"#;
+        let policies: HashMap<String, PolicyEntry> =
+            serde_yaml::from_str(member_id_collision).unwrap();
+        assert!(validate_policies(&policies)
+            .unwrap_err()
+            .to_string()
+            .contains("invalid policy id pod-security/privileged"));
+
+        let member_name = r#"
+pod-security:
+  expression: "privileged()"
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+    host/namespaces:
+      url: file:///tmp/host-namespaces-psp.wasm
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(member_name).unwrap();
+        assert!(validate_policies(&policies)
+            .unwrap_err()
+            .to_string()
+            .contains("invalid member name host/namespaces"));
+
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(
+            r#"
+pod-security:
+  expression: "privileged()"
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+"#,
+        )
+        .unwrap();
+        assert!(validate_policies(&policies).is_ok());
+    }
+
+    #[test]
+    fn policy_pipelines() {
+        let input = r#"
+---
+defaults:
+  policyMode: monitor
+  pipeline:
+    - name: labels
+      url: file:///tmp/safe-labels.wasm
+      allowedToMutate: true
+      settings:
+        mandatory_labels: [team]
+    - name: user-group
+      url: file:///tmp/user-group-psp.wasm
+      allowedToMutate: true
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(input).unwrap();
+        let pipelines = super::policy_pipelines(&policies);
+        let pipeline = pipelines.get("defaults").unwrap();
+        assert!(pipeline.validate("defaults").is_ok());
+        assert_eq!(pipeline.policy_mode, PolicyMode::Monitor);
+        assert_eq!(
+            pipeline
+                .pipeline
+                .iter()
+                .map(|step| step.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["labels", "user-group"]
+        );
+        assert!(pipeline.pipeline[0].policy.settings.is_some());
+
+        let mut ids: Vec<String> = individual_policies(&policies).into_keys().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["defaults/labels", "defaults/user-group"]);
+
+        let duplicated_steps: PolicyPipeline = serde_yaml::from_str(
+            r#"
+pipeline:
+  - name: labels
+    url: file:///tmp/safe-labels.wasm
+  - name: labels
+    url: file:///tmp/user-group-psp.wasm
+"#,
+        )
+        .unwrap();
+        assert!(duplicated_steps.validate("defaults").is_err());
+
+        let no_steps: PolicyPipeline = serde_yaml::from_str("pipeline: []").unwrap();
+        assert!(no_steps.validate("defaults").is_err());
+
+        // The id of the step, `defaults/labels/user-group`, would be
+        // ambiguous
+        let step_name: PolicyPipeline = serde_yaml::from_str(
+            r#"
+pipeline:
+  - name: labels/user-group
+    url: file:///tmp/safe-labels.wasm
+"#,
+        )
+        .unwrap();
+        assert!(step_name
+            .validate("defaults")
+            .unwrap_err()
+            .to_string()
+            .contains("invalid step name labels/user-group"));
+    }
+
+    #[test]
+    fn policy_exemptions() {
+        let input = r#"
+---
+namespace-validate:
+  url: file:///tmp/namespace-validate-policy.wasm
+  exemptions:
+    - namespaces: [kube-system, "/^ci-[0-9]+$/"]
+    - usernames: [admin]
+      groups: ["system:masters"]
+"#;
+        let policies: HashMap<String, Policy> = serde_yaml::from_str(input).unwrap();
+        let policy = policies.get("namespace-validate").unwrap();
+        assert!(policy.validate("namespace-validate").is_ok());
+        assert_eq!(policy.exemptions.len(), 2);
+
+        let empty_exemption: Policy =
+            serde_yaml::from_str("{url: file:///tmp/policy.wasm, exemptions: [{}]}").unwrap();
+        assert!(empty_exemption.validate("namespace-validate").is_err());
+
+        let exempted_member: PolicyGroup = serde_yaml::from_str(
+            r#"
+expression: "privileged()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+    exemptions:
+      - namespaces: [kube-system]
+"#,
+        )
+        .unwrap();
+        assert!(exempted_member.validate("pod-security").is_err());
+    }
+
+    #[test]
+    fn shadow_policies() {
+        let input = r#"
+---
+psp-capabilities:
+  url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.1.7
+  allowedToMutate: true
+  settings:
+    allowed_capabilities: [CHOWN]
+  shadow:
+    url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0
+    settings:
+      allowed_capabilities: []
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(input).unwrap();
+        let individual_policies = individual_policies(&policies);
+        let ids: Vec<&String> = individual_policies.keys().collect();
+        assert_eq!(ids, vec!["psp-capabilities"]);
+        let mut ids: Vec<String> = policies_to_fetch(&policies).into_keys().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["psp-capabilities", "psp-capabilities/shadow"]);
+
+        let shadows = super::shadow_policies(&individual_policies);
+        let shadow = shadows.get("psp-capabilities/shadow").unwrap();
+        assert_eq!(
+            shadow.url,
+            "registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0"
+        );
+        assert_eq!(shadow.allowed_to_mutate, Some(true));
+        assert_eq!(shadow.timeout_eval_seconds, None);
+        assert!(shadow.shadow.is_none());
+        assert_eq!(
+            shadow.settings_to_json().unwrap().unwrap()["allowed_capabilities"],
+            serde_json::json!([])
+        );
+
+        let shadow_policy = individual_policies["psp-capabilities"]
+            .shadow
+            .as_ref()
+            .unwrap();
+        assert_eq!(
+            shadow_policy.time_budget(),
+            Duration::from_millis(SHADOW_TIMEOUT_EVAL_MILLISECONDS)
+        );
+        let shadow_policy: ShadowPolicy = serde_yaml::from_str(
+            r#"
+url: registry://ghcr.io/kubewarden/policies/psp-capabilities:v0.2.0
+timeoutEvalMilliseconds: 250
+"#,
+        )
+        .unwrap();
+        assert_eq!(shadow_policy.time_budget(), Duration::from_millis(250));
+
+        let shadowed_member: PolicyGroup = serde_yaml::from_str(
+            r#"
+expression: "privileged()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+    shadow:
+      url: file:///tmp/pod-privileged-v2.wasm
+"#,
+        )
+        .unwrap();
+        assert!(shadowed_member.validate("pod-security").is_err());
+    }
+
+    #[test]
+    fn dry_run_modes() {
+        let input = r#"
+---
+psp-capabilities:
+  url: file:///tmp/psp-capabilities.wasm
+  dryRun: skip
+pod-security:
+  expression: "privileged()"
+  dryRun: monitor
+  policies:
+    privileged:
+      url: file:///tmp/pod-privileged.wasm
+"#;
+        let policies: HashMap<String, PolicyEntry> = serde_yaml::from_str(input).unwrap();
+        match policies.get("psp-capabilities").unwrap() {
+            PolicyEntry::Policy(policy) => assert_eq!(policy.dry_run, DryRunMode::Skip),
+            _ => panic!("policy expected"),
+        }
+        match policies.get("pod-security").unwrap() {
+            PolicyEntry::PolicyGroup(group) => {
+                assert_eq!(group.dry_run, DryRunMode::Monitor)
+            }
+            _ => panic!("policy group expected"),
+        }
+
+        let protect = PolicyMode::Protect;
+        assert_eq!(
+            DryRunMode::Evaluate.policy_mode(&protect, true),
+            Some(PolicyMode::Protect)
+        );
+        assert_eq!(DryRunMode::Skip.policy_mode(&protect, true), None);
+        assert_eq!(
+            DryRunMode::Monitor.policy_mode(&protect, true),
+            Some(PolicyMode::Monitor)
+        );
+        assert_eq!(
+            DryRunMode::Skip.policy_mode(&protect, false),
+            Some(PolicyMode::Protect)
+        );
+
+        let dry_run_member: PolicyGroup = serde_yaml::from_str(
+            r#"
+expression: "privileged()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+    dryRun: skip
+"#,
+        )
+        .unwrap();
+        assert!(dry_run_member.validate("pod-security").is_err());
+    }
 }
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use crossbeam_channel::Receiver;
+use policy_evaluator::callback_requests::CallbackRequest;
+use policy_evaluator::wasmtime;
+use policy_evaluator::{
+    admission_response::AdmissionResponse,
+    policy_evaluator::{PolicyEvaluator, ValidateRequest},
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, error, info, warn};
+
+use crate::admission_review::AdmissionRequest;
+use crate::audit;
+use crate::metrics;
+use crate::settings::{shadow_id, Policy, PolicyMode};
+use crate::worker::Worker;
+use crate::worker_pool::{
+    build_policy_evaluator, EpochTicker, PoliciesGeneration, PrecompiledPolicies,
+};
+
+/// How often the epoch of the engine of the shadow policies is
+/// incremented. Their time budget is enforced with this precision
+const SHADOW_EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// A request answered by a policy, to be evaluated by the shadow of the
+/// policy
+pub(crate) struct ShadowRequest {
+    /// The id of the policy shadowed
+    pub policy_id: String,
+    pub req: Box<AdmissionRequest>,
+    /// The request, as given to the policy shadowed
+    pub json: serde_json::Value,
+    /// The mode the policy shadowed has been evaluated with
+    pub policy_mode: PolicyMode,
+    /// The response of the policy shadowed
+    pub response: AdmissionResponse,
+}
+
+/// Evaluates the requests against the shadow policies, once the policies
+/// they shadow answered them. The shadow policies run on a thread of their
+/// own, using their own `wasmtime::Engine`: they never hold one of the
+/// workers evaluating the requests, and their time budget is enforced
+/// even when the policy timeout protection is disabled.
+pub(crate) struct ShadowEvaluator {
+    /// The evaluators of the shadow policies, by id of the policy they
+    /// shadow
+    evaluators: HashMap<String, PolicyEvaluator>,
+    requests_rx: Receiver<ShadowRequest>,
+
+    // The generation of policies the evaluators have been built from and
+    // the channel used to receive new ones
+    generation: Arc<PoliciesGeneration>,
+    generations_rx: Receiver<Arc<PoliciesGeneration>>,
+
+    engine: wasmtime::Engine,
+    callback_handler_tx: Sender<CallbackRequest>,
+
+    // Drives the epoch deadlines of the evaluations, running only while
+    // shadow policies are loaded
+    epoch_ticker: Option<EpochTicker>,
+}
+
+impl ShadowEvaluator {
+    pub(crate) fn new(
+        requests_rx: Receiver<ShadowRequest>,
+        generation: Arc<PoliciesGeneration>,
+        generations_rx: Receiver<Arc<PoliciesGeneration>>,
+        engine: wasmtime::Engine,
+        callback_handler_tx: Sender<CallbackRequest>,
+    ) -> ShadowEvaluator {
+        let mut shadow_evaluator = ShadowEvaluator {
+            evaluators: HashMap::new(),
+            requests_rx,
+            generation: generation.clone(),
+            generations_rx,
+            engine,
+            callback_handler_tx,
+            epoch_ticker: None,
+        };
+        shadow_evaluator.switch_generation(generation);
+        shadow_evaluator
+    }
+
+    pub(crate) fn run(mut self) {
+        let requests_rx = self.requests_rx.clone();
+        let mut generations_rx = self.generations_rx.clone();
+        loop {
+            crossbeam_channel::select! {
+                recv(requests_rx) -> req => match req {
+                    Ok(req) => self.evaluate(req),
+                    // All the workers are gone
+                    Err(_) => break,
+                },
+                recv(generations_rx) -> generation => match generation {
+                    Ok(generation) => self.switch_generation(generation),
+                    // The policies cannot be reloaded anymore
+                    Err(_) => generations_rx = crossbeam_channel::never(),
+                },
+            }
+        }
+    }
+
+    // Builds the evaluators of the shadow policies of the given
+    // generation. The settings of the shadow policies have been validated
+    // by the worker pool before, the evaluators that cannot be built are
+    // skipped
+    fn switch_generation(&mut self, generation: Arc<PoliciesGeneration>) {
+        let mut evaluators = HashMap::new();
+        for (policy_id, policy) in generation.policies.iter() {
+            match build_shadow_evaluator(
+                policy_id,
+                policy,
+                &self.engine,
+                &generation.precompiled_shadows,
+                self.callback_handler_tx.clone(),
+            ) {
+                Some(Ok(evaluator)) => {
+                    evaluators.insert(policy_id.clone(), evaluator);
+                }
+                Some(Err(e)) => error!(
+                    policy_id = shadow_id(policy_id).as_str(),
+                    error = e.to_string().as_str(),
+                    "cannot create shadow policy evaluator"
+                ),
+                None => {}
+            }
+        }
+
+        self.generation = generation;
+        self.evaluators = evaluators;
+        if self.evaluators.is_empty() {
+            self.epoch_ticker = None;
+        } else if self.epoch_ticker.is_none() {
+            self.epoch_ticker = Some(EpochTicker::start(self.engine.clone(), SHADOW_EPOCH_TICK));
+        }
+        debug!(
+            shadow_policies_count = self.evaluators.len(),
+            "shadow evaluator switched to new policies"
+        );
+    }
+
+    // Evaluates the request against the shadow of the policy. Disagreements
+    // between the two policies are logged, counted and audited
+    fn evaluate(&mut self, shadow_request: ShadowRequest) {
+        let ShadowRequest {
+            policy_id,
+            req,
+            json,
+            policy_mode,
+            response: primary,
+        } = shadow_request;
+        // The shadow policy could have been removed meanwhile
+        let evaluator = match self.evaluators.get_mut(&policy_id) {
+            Some(evaluator) => evaluator,
+            None => return,
+        };
+
+        let shadow = evaluator.validate(ValidateRequest::new(json));
+        if Worker::evaluation_timed_out(&shadow) {
+            warn!(
+                policy_id = shadow_id(&policy_id).as_str(),
+                "shadow policy evaluation exceeded its time budget"
+            );
+            self.rebuild_evaluator(&policy_id);
+            return;
+        }
+        if Worker::evaluation_failed(&shadow) {
+            warn!(
+                policy_id = shadow_id(&policy_id).as_str(),
+                response = ?shadow,
+                "shadow policy evaluation failed"
+            );
+            return;
+        }
+
+        let disagreement = if primary.allowed != shadow.allowed {
+            "verdict"
+        } else if primary.patch != shadow.patch {
+            "patch"
+        } else {
+            return;
+        };
+        info!(
+            policy_id = policy_id.as_str(),
+            disagreement,
+            allowed = primary.allowed,
+            shadow_allowed = shadow.allowed,
+            "shadow policy disagreement"
+        );
+        metrics::add_shadow_disagreement(&policy_id, disagreement);
+        audit::record_shadow_disagreement(&policy_id, &policy_mode, &req, &primary, &shadow);
+    }
+
+    // Replaces the evaluator of the shadow of the given policy with a
+    // brand new one, after its evaluation has been interrupted
+    fn rebuild_evaluator(&mut self, policy_id: &str) {
+        let policy = match self.generation.policies.get(policy_id) {
+            Some(policy) => policy,
+            None => return,
+        };
+        match build_shadow_evaluator(
+            policy_id,
+            policy,
+            &self.engine,
+            &self.generation.precompiled_shadows,
+            self.callback_handler_tx.clone(),
+        ) {
+            Some(Ok(evaluator)) => {
+                self.evaluators.insert(policy_id.to_string(), evaluator);
+            }
+            Some(Err(e)) => {
+                self.evaluators.remove(policy_id);
+                error!(
+                    policy_id = shadow_id(policy_id).as_str(),
+                    error = e.to_string().as_str(),
+                    "cannot rebuild shadow policy evaluator after timeout"
+                );
+            }
+            None => {}
+        }
+    }
+}
+
+/// Builds the `wasmtime::Engine` of the shadow policies. Epoch
+/// interruption is always enabled, to enforce their time budget
+pub(crate) fn build_engine() -> Result<wasmtime::Engine> {
+    let mut wasmtime_config = wasmtime::Config::new();
+    wasmtime_config.epoch_interruption(true);
+    wasmtime::Engine::new(&wasmtime_config)
+}
+
+/// Builds the evaluator of the shadow of the given policy, which is
+/// interrupted once its time budget elapsed. Returns `None` when the
+/// policy has no shadow
+pub(crate) fn build_shadow_evaluator(
+    policy_id: &str,
+    policy: &Policy,
+    engine: &wasmtime::Engine,
+    precompiled_shadows: &PrecompiledPolicies,
+    callback_handler_tx: Sender<CallbackRequest>,
+) -> Option<Result<PolicyEvaluator>> {
+    let time_budget = policy.shadow.as_ref()?.time_budget();
+    let shadow = policy.shadow_policy()?;
+    Some(build_policy_evaluator(
+        &shadow_id(policy_id),
+        &shadow,
+        engine,
+        precompiled_shadows,
+        callback_handler_tx,
+        Some(epoch_deadline(time_budget)),
+    ))
+}
+
+/// Returns the number of epochs enforcing the given time budget, which is
+/// rounded up to the next tick
+fn epoch_deadline(time_budget: Duration) -> u64 {
+    let epochs = time_budget
+        .as_millis()
+        .div_ceil(SHADOW_EPOCH_TICK.as_millis());
+    epochs.max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_deadline() {
+        assert_eq!(super::epoch_deadline(Duration::from_millis(1000)), 100);
+        assert_eq!(super::epoch_deadline(Duration::from_millis(250)), 25);
+        assert_eq!(super::epoch_deadline(Duration::from_millis(15)), 2);
+        assert_eq!(super::epoch_deadline(Duration::from_millis(1)), 1);
+        assert_eq!(super::epoch_deadline(Duration::ZERO), 1);
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use crossbeam_channel::{Receiver, Sender as WorkerSender};
 use itertools::Itertools;
 use policy_evaluator::callback_requests::CallbackRequest;
 use policy_evaluator::wasmtime;
@@ -6,30 +7,99 @@ use policy_evaluator::{
     admission_response::{AdmissionResponse, AdmissionResponseStatus},
     policy_evaluator::{PolicyEvaluator, ValidateRequest},
 };
-use std::{collections::HashMap, fmt, time::Instant};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{error, info, info_span};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, error, info, info_span, warn};
 
-use crate::communication::EvalRequest;
+use crate::admission_review::AdmissionRequest;
+use crate::audit;
+use crate::communication::{shed_response, EvalRequest, ValidationRequest};
+use crate::exemptions::{self, Exemption};
 use crate::metrics;
-use crate::settings::{Policy, PolicyMode};
-use crate::worker_pool::PrecompiledPolicies;
+use crate::selectors;
+use crate::settings::{
+    self, DryRunMode, FailurePolicy, PipelineStep, Policy, PolicyGroup, PolicyMode, PolicyPipeline,
+};
+use crate::shadow_evaluator::ShadowRequest;
+use crate::worker_pool::{PoliciesGeneration, PrecompiledPolicies};
 
 struct PolicyEvaluatorWithSettings {
     policy_evaluator: PolicyEvaluator,
     policy_mode: PolicyMode,
     allowed_to_mutate: bool,
-    always_accept_admission_reviews_on_namespace: Option<String>,
+    failure_policy: FailurePolicy,
+    dry_run: DryRunMode,
+    exemptions: Vec<Exemption>,
+    /// The sha256 digest of the WebAssembly module
+    digest: Option<String>,
+    policy_evaluation_limit_seconds: Option<u64>,
+}
+
+/// A request sent by the Kubernetes API server, together with the policy
+/// evaluating it
+struct AdmissionEvalRequest<'a> {
+    policy_id: &'a String,
+    req: &'a AdmissionRequest,
+}
+
+impl AdmissionEvalRequest<'_> {
+    fn dry_run(&self) -> bool {
+        self.req.dry_run.unwrap_or(false)
+    }
+}
+
+/// The messages sent by the worker pool to switch the workers to a new
+/// generation of policies. The evaluators of the generation are first
+/// prepared by all the workers, the generation is committed only when all
+/// of them succeeded. Workers handle these messages between two requests
+pub(crate) enum GenerationMessage {
+    /// Build the evaluators of the generation, the outcome is sent back
+    /// through the channel
+    Prepare(Arc<PoliciesGeneration>, WorkerSender<Result<(), String>>),
+    /// Switch to the prepared generation, the channel is notified once
+    /// done
+    Commit(WorkerSender<()>),
+    /// Drop the prepared generation
+    Abort,
 }
 
 pub(crate) struct Worker {
     evaluators: HashMap<String, PolicyEvaluatorWithSettings>,
     channel_rx: Receiver<EvalRequest>,
 
-    // TODO: remove clippy's exception. This is going to be used to
-    // implement the epoch handling
-    #[allow(dead_code)]
+    // The generation of policies the evaluators have been built from,
+    // the channel used to receive new ones and the generation prepared
+    // but not committed yet, with its evaluators
+    generation: Arc<PoliciesGeneration>,
+    generations_rx: Receiver<GenerationMessage>,
+    prepared_generation: Option<(
+        Arc<PoliciesGeneration>,
+        HashMap<String, PolicyEvaluatorWithSettings>,
+    )>,
+
+    // The following fields are required to rebuild the policy evaluators,
+    // either because new policies have been loaded or because an
+    // evaluation has been interrupted. The state of the Wasm module
+    // cannot be trusted after that.
     engine: wasmtime::Engine,
+    callback_handler_tx: Sender<CallbackRequest>,
+    policy_evaluation_limit_seconds: Option<u64>,
+
+    // The requests answered by a policy with a shadow are sent to the
+    // shadow evaluator through this channel
+    shadows_tx: WorkerSender<ShadowRequest>,
+
+    // The exemptions applying to all the policies
+    exemptions: Vec<Exemption>,
+
+    // The prefix of the audit annotations added to the responses, no
+    // annotation is added when not set
+    audit_annotations_prefix: Option<String>,
 }
 
 pub struct PolicyErrors(HashMap<String, String>);
@@ -50,65 +120,37 @@ impl Worker {
         fields(host=crate::cli::HOSTNAME.as_str()),
         skip_all,
     )]
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         rx: Receiver<EvalRequest>,
-        policies: &HashMap<String, Policy>,
-        precompiled_policies: &PrecompiledPolicies,
-        wasmtime_config: &wasmtime::Config,
+        generation: Arc<PoliciesGeneration>,
+        generations_rx: Receiver<GenerationMessage>,
+        engine: wasmtime::Engine,
+        shadows_tx: WorkerSender<ShadowRequest>,
         callback_handler_tx: Sender<CallbackRequest>,
-        always_accept_admission_reviews_on_namespace: Option<String>,
+        exemptions: Vec<Exemption>,
+        audit_annotations_prefix: Option<String>,
+        policy_evaluation_limit_seconds: Option<u64>,
     ) -> Result<Worker, PolicyErrors> {
-        let mut evs_errors = HashMap::new();
-        let mut evs = HashMap::new();
-
-        let engine = wasmtime::Engine::new(wasmtime_config).map_err(|e| {
-            let mut errors = HashMap::new();
-            errors.insert(
-                "*".to_string(),
-                format!("Cannot create wasmtime::Engine: {:?}", e),
-            );
-            PolicyErrors(errors)
-        })?;
-
-        for (id, policy) in policies.iter() {
-            // It's safe to clone the outer engine. This creates a shallow copy
-            let inner_engine = engine.clone();
-            let policy_evaluator = match crate::worker_pool::build_policy_evaluator(
-                id,
-                policy,
-                &inner_engine,
-                precompiled_policies,
-                callback_handler_tx.clone(),
-            ) {
-                Ok(pe) => pe,
-                Err(e) => {
-                    evs_errors.insert(
-                        id.clone(),
-                        format!("[{}] could not create PolicyEvaluator: {:?}", id, e),
-                    );
-                    continue;
-                }
-            };
-
-            let policy_evaluator_with_settings = PolicyEvaluatorWithSettings {
-                policy_evaluator,
-                policy_mode: policy.policy_mode.clone(),
-                allowed_to_mutate: policy.allowed_to_mutate.unwrap_or(false),
-                always_accept_admission_reviews_on_namespace:
-                    always_accept_admission_reviews_on_namespace.clone(),
-            };
-
-            evs.insert(id.to_string(), policy_evaluator_with_settings);
-        }
-
-        if !evs_errors.is_empty() {
-            return Err(PolicyErrors(evs_errors));
-        }
+        let evs = build_policy_evaluators(
+            &generation,
+            &engine,
+            &callback_handler_tx,
+            policy_evaluation_limit_seconds,
+        )?;
 
         Ok(Worker {
             evaluators: evs,
             channel_rx: rx,
+            generation,
+            generations_rx,
+            prepared_generation: None,
             engine,
+            callback_handler_tx,
+            policy_evaluation_limit_seconds,
+            shadows_tx,
+            exemptions,
+            audit_annotations_prefix,
         })
     }
 
@@ -118,6 +160,8 @@ impl Worker {
     //   configuration does not allow it to mutate
     // - A policy might be running in "Monitor" mode, that always
     //   accepts the request (without mutation), logging the answer
+    // - A policy might be running in "Warn" mode, that accepts the
+    //   request it would reject, sending back the rejection as a warning
     fn validation_response_with_constraints(
         policy_id: &str,
         policy_mode: &PolicyMode,
@@ -164,106 +208,1326 @@ impl Worker {
                     ..validation_response
                 }
             }
+            PolicyMode::Warn => {
+                let validation_response = Worker::validation_response_with_constraints(
+                    policy_id,
+                    &PolicyMode::Protect,
+                    allowed_to_mutate,
+                    validation_response,
+                );
+                if validation_response.allowed {
+                    return validation_response;
+                }
+                info!(
+                    policy_id = policy_id,
+                    response = format!("{:?}", validation_response).as_str(),
+                    "policy evaluation (warn mode)",
+                );
+                let warning = match validation_response
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.message.as_ref())
+                {
+                    Some(message) => format!(
+                        "Request would be rejected by policy {}: {}",
+                        policy_id, message
+                    ),
+                    None => format!("Request would be rejected by policy {}", policy_id),
+                };
+                let mut warnings = validation_response.warnings.unwrap_or_default();
+                warnings.push(warning);
+                // Rejected responses do not carry patches, the status is
+                // only taken into account when a request is rejected
+                AdmissionResponse {
+                    allowed: true,
+                    status: None,
+                    warnings: Some(warnings),
+                    ..validation_response
+                }
+            }
+        }
+    }
+
+    // Returns true when the evaluation of the policy has been interrupted
+    // because it reached its epoch deadline. The evaluator reports the
+    // errors of the guest inside of the response, the one of an
+    // interrupted guest is the `wasmtime::Trap::Interrupt` trap
+    pub(crate) fn evaluation_timed_out(validation_response: &AdmissionResponse) -> bool {
+        let interrupt_trap = wasmtime::Trap::Interrupt.to_string();
+        Worker::evaluation_failed(validation_response)
+            && validation_response
+                .status
+                .as_ref()
+                .and_then(|status| status.message.as_ref())
+                .map_or(false, |message| message.contains(&interrupt_trap))
+    }
+
+    // Returns true when the policy could not evaluate the request, for
+    // example because the Wasm guest trapped or a host callback failed
+    pub(crate) fn evaluation_failed(validation_response: &AdmissionResponse) -> bool {
+        !validation_response.allowed
+            && validation_response
+                .status
+                .as_ref()
+                .and_then(|status| status.code)
+                == Some(warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+    }
+
+    // Returns the response sent back when the evaluation of a policy
+    // took longer than allowed
+    fn timeout_response(
+        request_uid: &str,
+        policy_id: &str,
+        policy_evaluation_limit_seconds: u64,
+        failure_policy: &FailurePolicy,
+    ) -> AdmissionResponse {
+        failure_policy.response(
+            request_uid,
+            policy_id,
+            &format!(
+                "The policy evaluation did not complete within {} seconds.",
+                policy_evaluation_limit_seconds
+            ),
+            warp::http::StatusCode::GATEWAY_TIMEOUT.as_u16(),
+        )
+    }
+
+    // Returns the response sent back when the evaluation of a policy failed
+    fn error_response(
+        policy_id: &str,
+        failure_policy: &FailurePolicy,
+        validation_response: &AdmissionResponse,
+    ) -> AdmissionResponse {
+        let error = validation_response
+            .status
+            .as_ref()
+            .and_then(|status| status.message.clone())
+            .unwrap_or_default();
+        failure_policy.response(
+            &validation_response.uid,
+            policy_id,
+            &format!("The policy evaluation failed: {}", error),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        )
+    }
+
+    // Answers a request that waited too long for a worker, according to
+    // the failure policy of the requested policy
+    fn shed(&self, req: EvalRequest) {
+        let failure_policy = if let Some(group) = self.generation.groups.get(&req.policy_id) {
+            Some(&group.failure_policy)
+        } else if let Some(pipeline) = self.generation.pipelines.get(&req.policy_id) {
+            Some(&pipeline.failure_policy)
+        } else {
+            self.evaluators
+                .get(&req.policy_id)
+                .map(|evaluator| &evaluator.failure_policy)
+        };
+        let response = failure_policy.map(|failure_policy| {
+            warn!(
+                policy_id = req.policy_id.as_str(),
+                failure_policy = ?failure_policy,
+                "evaluation queue wait exceeded, request shed"
+            );
+            metrics::add_shed_request(&req.policy_id, &String::from(failure_policy.clone()));
+            shed_response(req.req.uid(), &req.policy_id, failure_policy)
+        });
+        if req.resp_chan.send(response).is_err() {
+            error!("receiver dropped");
+        }
+    }
+
+    // Replaces the evaluator of the given policy with a brand new one.
+    // This has to be done after an evaluation has been interrupted, because
+    // the Wasm guest could have been stopped in the middle of anything.
+    fn rebuild_policy_evaluator(&mut self, policy_id: &str) {
+        let policy = match self.generation.policies.get(policy_id) {
+            Some(policy) => policy,
+            None => return,
+        };
+        match build_policy_evaluator_with_settings(
+            policy_id,
+            policy,
+            &self.engine,
+            &self.generation.precompiled_policies,
+            self.callback_handler_tx.clone(),
+            self.policy_evaluation_limit_seconds,
+        ) {
+            Ok(pe) => {
+                self.evaluators.insert(policy_id.to_string(), pe);
+            }
+            Err(e) => error!(
+                policy_id,
+                error = e.to_string().as_str(),
+                "cannot rebuild policy evaluator after timeout"
+            ),
+        }
+    }
+
+    // Handles a message of the worker pool about a new generation of
+    // policies. The current evaluators are kept until the new generation
+    // is committed
+    fn handle_generation_message(&mut self, message: GenerationMessage) {
+        match message {
+            GenerationMessage::Prepare(generation, prepared_tx) => {
+                let outcome = build_policy_evaluators(
+                    &generation,
+                    &self.engine,
+                    &self.callback_handler_tx,
+                    self.policy_evaluation_limit_seconds,
+                )
+                .map(|evaluators| {
+                    self.prepared_generation = Some((generation, evaluators));
+                })
+                .map_err(|e| e.to_string());
+                if let Err(error) = &outcome {
+                    error!(error = error.as_str(), "cannot prepare new policies");
+                }
+                if prepared_tx.send(outcome).is_err() {
+                    error!("policies reload: cannot send back preparation result");
+                }
+            }
+            GenerationMessage::Commit(committed_tx) => {
+                if let Some((generation, evaluators)) = self.prepared_generation.take() {
+                    self.generation = generation;
+                    self.evaluators = evaluators;
+                    info!(
+                        policies_count = self.evaluators.len(),
+                        "worker switched to new policies"
+                    );
+                }
+                if committed_tx.send(()).is_err() {
+                    error!("policies reload: cannot send back commit result");
+                }
+            }
+            GenerationMessage::Abort => self.prepared_generation = None,
         }
     }
 
     pub(crate) fn run(mut self) {
-        while let Some(req) = self.channel_rx.blocking_recv() {
-            let span = info_span!(parent: &req.parent_span, "policy_eval");
-            let _enter = span.enter();
-
-            let res = match self.evaluators.get_mut(&req.policy_id) {
-                Some(PolicyEvaluatorWithSettings {
-                    policy_evaluator,
-                    policy_mode,
-                    allowed_to_mutate,
-                    always_accept_admission_reviews_on_namespace,
-                }) => match serde_json::to_value(req.req.clone()) {
-                    Ok(json) => {
-                        let policy_name = policy_evaluator.policy.id.clone();
-                        let policy_mode = policy_mode.clone();
-                        let start_time = Instant::now();
-                        let allowed_to_mutate = *allowed_to_mutate;
-                        let vanilla_validation_response =
-                            policy_evaluator.validate(ValidateRequest::new(json));
-                        let policy_evaluation_duration = start_time.elapsed();
-                        let error_code = if let Some(status) = &vanilla_validation_response.status {
-                            status.code
-                        } else {
-                            None
-                        };
-                        let validation_response = Worker::validation_response_with_constraints(
-                            &req.policy_id,
-                            &policy_mode,
-                            allowed_to_mutate,
-                            vanilla_validation_response.clone(),
-                        );
-                        let validation_response =
-                            // If the policy server is configured to
-                            // always accept admission reviews on a
-                            // given namespace, just set the `allowed`
-                            // part of the response to `true` if the
-                            // request matches this namespace. Keep
-                            // the rest of the behaviors unchanged,
-                            // such as checking if the policy is
-                            // allowed to mutate.
-                            if let Some(namespace) = always_accept_admission_reviews_on_namespace {
-                                if req.req.namespace == Some(namespace.to_string()) {
-                                    AdmissionResponse {
-                                        allowed: true,
-                                        ..validation_response
-                                    }
-                                } else {
-                                    validation_response
-                                }
-                            } else {
-                                validation_response
-                            };
-                        let accepted = vanilla_validation_response.allowed;
-                        let mutated = vanilla_validation_response.patch.is_some();
-                        let res = req.resp_chan.send(Some(validation_response));
-                        let policy_evaluation = metrics::PolicyEvaluation {
-                            policy_name,
-                            policy_mode: policy_mode.into(),
-                            resource_namespace: req.req.namespace,
-                            resource_kind: req.req.request_kind.unwrap_or_default().kind,
-                            resource_request_operation: req.req.operation.clone(),
-                            accepted,
-                            mutated,
-                            error_code,
-                        };
-                        metrics::record_policy_latency(
-                            policy_evaluation_duration,
-                            &policy_evaluation,
-                        );
-                        metrics::add_policy_evaluation(&policy_evaluation);
-                        res
-                    }
-                    Err(e) => {
-                        let error_msg = format!("Failed to serialize AdmissionReview: {:?}", e);
-                        error!("{}", error_msg);
-                        req.resp_chan.send(Some(AdmissionResponse::reject(
+        let channel_rx = self.channel_rx.clone();
+        let mut generations_rx = self.generations_rx.clone();
+        loop {
+            crossbeam_channel::select! {
+                recv(channel_rx) -> req => match req {
+                    Ok(req) => self.process(req),
+                    Err(_) => break,
+                },
+                recv(generations_rx) -> message => match message {
+                    Ok(message) => self.handle_generation_message(message),
+                    // The policies cannot be reloaded anymore
+                    Err(_) => generations_rx = crossbeam_channel::never(),
+                },
+            }
+        }
+    }
+
+    // Evaluates a request and sends back the response
+    fn process(&mut self, req: EvalRequest) {
+        metrics::record_dequeued_request(metrics::Queue::Workers);
+        let span = info_span!(parent: &req.parent_span, "policy_eval");
+        let _enter = span.enter();
+
+        // Nobody is waiting for the response anymore, e.g. the client
+        // went away or the request has been shed by the API
+        if req.resp_chan.is_closed() {
+            debug!(
+                policy_id = req.policy_id.as_str(),
+                "request cancelled, skipping evaluation"
+            );
+            return;
+        }
+        if req.expired() {
+            self.shed(req);
+            return;
+        }
+
+        let mut timed_out_policies = Vec::new();
+        let mut pending_shadow = None;
+        let response = match &req.req {
+            ValidationRequest::AdmissionRequest(adm_req) => self.evaluate_admission_request(
+                &AdmissionEvalRequest {
+                    policy_id: &req.policy_id,
+                    req: adm_req,
+                },
+                &mut timed_out_policies,
+                &mut pending_shadow,
+            ),
+            ValidationRequest::Raw(json) => {
+                self.evaluate_raw(&req.policy_id, json.clone(), &mut timed_out_policies)
+            }
+        };
+        if req.resp_chan.send(response).is_err() {
+            error!("receiver dropped");
+        }
+        if let (Some(pending_shadow), ValidationRequest::AdmissionRequest(adm_req)) =
+            (pending_shadow, req.req)
+        {
+            self.submit_shadow(req.policy_id, adm_req, pending_shadow);
+        }
+        for policy_id in timed_out_policies {
+            self.rebuild_policy_evaluator(&policy_id);
+        }
+    }
+
+    // Evaluates a request sent by the Kubernetes API server against a
+    // policy, a policy group or a policy pipeline. Returns `None` when the
+    // policy is not known
+    fn evaluate_admission_request(
+        &mut self,
+        req: &AdmissionEvalRequest,
+        timed_out_policies: &mut Vec<String>,
+        pending_shadow: &mut Option<PendingShadow>,
+    ) -> Option<AdmissionResponse> {
+        match serde_json::to_value(req.req) {
+            Ok(json) => {
+                // The generation is cloned to keep the group or the
+                // pipeline borrowed while their policies are evaluated
+                let generation = self.generation.clone();
+                if let Some(group) = generation.groups.get(req.policy_id) {
+                    Some(self.evaluate_policy_group(req, group, json, timed_out_policies))
+                } else if let Some(pipeline) = generation.pipelines.get(req.policy_id) {
+                    Some(self.evaluate_policy_pipeline(req, pipeline, json, timed_out_policies))
+                } else {
+                    self.evaluate_policy(req, json, timed_out_policies, pending_shadow)
+                }
+            }
+            Err(e) => {
+                let error_msg = format!("Failed to serialize AdmissionReview: {:?}", e);
+                error!("{}", error_msg);
+                Some(AdmissionResponse::reject(
+                    req.policy_id.clone(),
+                    error_msg,
+                    warp::http::StatusCode::BAD_REQUEST.as_u16(),
+                ))
+            }
+        }
+    }
+
+    // Evaluates a raw JSON document against a single policy. The verdict
+    // of the policy is returned as it is: the scope, the exemptions, the
+    // policy mode and the failure policy do not apply. Returns `None` when
+    // the policy is not known
+    fn evaluate_raw(
+        &mut self,
+        policy_id: &str,
+        json: serde_json::Value,
+        timed_out_policies: &mut Vec<String>,
+    ) -> Option<AdmissionResponse> {
+        if self.generation.groups.contains_key(policy_id)
+            || self.generation.pipelines.contains_key(policy_id)
+        {
+            return Some(AdmissionResponse::reject(
+                policy_id.to_string(),
+                String::from("Raw requests can be evaluated only by individual policies."),
+                warp::http::StatusCode::BAD_REQUEST.as_u16(),
+            ));
+        }
+
+        let evaluation = self.validate(policy_id, json)?;
+        if evaluation.timed_out() {
+            timed_out_policies.push(policy_id.to_string());
+        }
+        let policy_mode = &self.evaluators.get(policy_id)?.policy_mode;
+
+        let policy_evaluation = metrics::PolicyEvaluation {
+            policy_name: policy_id.to_string(),
+            policy_mode: policy_mode.clone().into(),
+            resource_namespace: None,
+            resource_kind: String::new(),
+            resource_request_operation: String::new(),
+            accepted: evaluation.response.allowed,
+            mutated: evaluation.response.patch.is_some(),
+            timed_out: evaluation.timed_out(),
+            error_code: evaluation.error_code(),
+            dry_run: false,
+        };
+        metrics::record_policy_latency(evaluation.duration, &policy_evaluation);
+        metrics::add_policy_evaluation(&policy_evaluation);
+
+        Some(evaluation.response)
+    }
+
+    // Evaluates the request using the evaluator of the given policy, the
+    // requests out of the scope of the policy are accepted right away.
+    // Returns `None` when the policy is not known
+    fn evaluate(
+        &mut self,
+        policy_id: &str,
+        req: &AdmissionRequest,
+        json: serde_json::Value,
+    ) -> Option<Evaluation> {
+        if let Some(policy) = self.generation.policies.get(policy_id) {
+            if !selectors::in_scope(policy, req) {
+                debug!(policy_id, "request out of the scope of the policy");
+                return Some(Evaluation {
+                    response: AdmissionResponse {
+                        uid: req.uid.clone(),
+                        allowed: true,
+                        ..Default::default()
+                    },
+                    timed_out_after_seconds: None,
+                    duration: Duration::ZERO,
+                    out_of_scope: true,
+                });
+            }
+        }
+
+        self.validate(policy_id, json)
+    }
+
+    // Gives the JSON document to the evaluator of the given policy.
+    // Returns `None` when the policy is not known
+    fn validate(&mut self, policy_id: &str, json: serde_json::Value) -> Option<Evaluation> {
+        let evaluator = self.evaluators.get_mut(policy_id)?;
+        let start_time = Instant::now();
+        let response = evaluator
+            .policy_evaluator
+            .validate(ValidateRequest::new(json));
+        let duration = start_time.elapsed();
+
+        let timed_out_after_seconds = evaluator
+            .policy_evaluation_limit_seconds
+            .filter(|_| Worker::evaluation_timed_out(&response));
+        if let Some(limit) = timed_out_after_seconds {
+            warn!(
+                policy_id,
+                limit_seconds = limit,
+                "policy evaluation timed out"
+            );
+        } else if Worker::evaluation_failed(&response) {
+            warn!(policy_id, response = ?response, "policy evaluation failed");
+        }
+
+        Some(Evaluation {
+            response,
+            timed_out_after_seconds,
+            duration,
+            out_of_scope: false,
+        })
+    }
+
+    // Evaluates the request against a single policy. Returns `None` when
+    // the policy is not known. When the policy has a shadow, the evaluation
+    // of the latter is left to the caller through `pending_shadow`
+    fn evaluate_policy(
+        &mut self,
+        req: &AdmissionEvalRequest,
+        json: serde_json::Value,
+        timed_out_policies: &mut Vec<String>,
+        pending_shadow: &mut Option<PendingShadow>,
+    ) -> Option<AdmissionResponse> {
+        let evaluator = self.evaluators.get(req.policy_id)?;
+        let effective_mode = match evaluator
+            .dry_run
+            .policy_mode(&evaluator.policy_mode, req.dry_run())
+        {
+            Some(effective_mode) => effective_mode,
+            None => return Some(Worker::dry_run_response(req)),
+        };
+        let shadow_json = self
+            .generation
+            .policies
+            .get(req.policy_id)
+            .map_or(false, |policy| policy.shadow.is_some())
+            .then(|| json.clone());
+        let evaluation = self.evaluate(req.policy_id, req.req, json)?;
+        if evaluation.out_of_scope {
+            return Some(evaluation.response);
+        }
+        if evaluation.timed_out() {
+            timed_out_policies.push(req.policy_id.clone());
+        }
+        let PolicyEvaluatorWithSettings {
+            policy_evaluator,
+            allowed_to_mutate,
+            failure_policy,
+            exemptions,
+            digest,
+            ..
+        } = self.evaluators.get(req.policy_id)?;
+
+        // Errors and timeouts are handled according to the failure policy
+        // of the policy
+        let evaluation_response =
+            evaluation.response_with_failure_policy(&req.req.uid, req.policy_id, failure_policy);
+        audit::record(
+            req.policy_id,
+            &effective_mode,
+            req.req,
+            &evaluation_response,
+        );
+        // Only the verdicts of the policy can be compared with the ones of
+        // its shadow
+        if let Some(json) = shadow_json {
+            if !evaluation.timed_out() && !Worker::evaluation_failed(&evaluation.response) {
+                *pending_shadow = Some(PendingShadow {
+                    json,
+                    policy_mode: effective_mode.clone(),
+                    response: evaluation.response.clone(),
+                });
+            }
+        }
+        let validation_response = Worker::validation_response_with_constraints(
+            req.policy_id,
+            &effective_mode,
+            *allowed_to_mutate,
+            evaluation_response,
+        );
+        let policy_mode_name: String = effective_mode.into();
+        let mut annotations = vec![
+            ("policy-id", req.policy_id.as_str()),
+            ("policy-mode", policy_mode_name.as_str()),
+        ];
+        if let Some(digest) = digest {
+            annotations.push(("policy-digest", digest.as_str()));
+        }
+        let validation_response = self.annotate(validation_response, &annotations);
+        let validation_response =
+            self.apply_exemptions(req.policy_id, exemptions, req.req, validation_response);
+
+        let policy_evaluation = metrics::PolicyEvaluation {
+            policy_name: policy_evaluator.policy.id.clone(),
+            policy_mode: policy_mode_name,
+            resource_namespace: req.req.namespace.clone(),
+            resource_kind: req.req.request_kind.clone().unwrap_or_default().kind,
+            resource_request_operation: req.req.operation.clone(),
+            accepted: evaluation.response.allowed,
+            mutated: evaluation.response.patch.is_some(),
+            timed_out: evaluation.timed_out(),
+            error_code: evaluation.error_code(),
+            dry_run: req.dry_run(),
+        };
+        metrics::record_policy_latency(evaluation.duration, &policy_evaluation);
+        metrics::add_policy_evaluation(&policy_evaluation);
+
+        Some(validation_response)
+    }
+
+    // Hands the request over to the shadow evaluator, once the response of
+    // the policy has been sent. The evaluation by the shadow policy is
+    // skipped when the shadow evaluator is lagging behind, the workers
+    // never wait for it
+    fn submit_shadow(
+        &self,
+        policy_id: String,
+        req: Box<AdmissionRequest>,
+        pending_shadow: PendingShadow,
+    ) {
+        let shadow_request = ShadowRequest {
+            policy_id,
+            req,
+            json: pending_shadow.json,
+            policy_mode: pending_shadow.policy_mode,
+            response: pending_shadow.response,
+        };
+        if let Err(e) = self.shadows_tx.try_send(shadow_request) {
+            debug!(
+                policy_id = e.into_inner().policy_id.as_str(),
+                "shadow evaluator busy, shadow evaluation skipped"
+            );
+        }
+    }
+
+    // Evaluates the request against the members of a policy group, their
+    // verdicts are combined by the expression of the group. The group
+    // fails as soon as one of its members cannot evaluate the request
+    fn evaluate_policy_group(
+        &mut self,
+        req: &AdmissionEvalRequest,
+        group: &PolicyGroup,
+        json: serde_json::Value,
+        timed_out_policies: &mut Vec<String>,
+    ) -> AdmissionResponse {
+        let effective_mode = match group.dry_run.policy_mode(&group.policy_mode, req.dry_run()) {
+            Some(effective_mode) => effective_mode,
+            None => return Worker::dry_run_response(req),
+        };
+        let start_time = Instant::now();
+        let mut rejections = Vec::new();
+        let mut warnings = Vec::new();
+        let mut failure: Option<(AdmissionResponse, Option<u16>)> = None;
+        let mut timed_out = false;
+
+        let allowed = group.expression.evaluate(|member| {
+            if failure.is_some() {
+                return false;
+            }
+            let member_id = settings::member_id(req.policy_id, member);
+            let evaluation = match self.evaluate(&member_id, req.req, json.clone()) {
+                Some(evaluation) => evaluation,
+                None => {
+                    failure = Some((
+                        group.failure_policy.response(
+                            &req.req.uid,
                             req.policy_id,
-                            error_msg,
-                            warp::http::StatusCode::BAD_REQUEST.as_u16(),
-                        )))
-                    }
+                            &format!("The policy {} is not loaded.", member),
+                            warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        ),
+                        Some(warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16()),
+                    ));
+                    return false;
+                }
+            };
+            if evaluation.timed_out() {
+                timed_out = true;
+                timed_out_policies.push(member_id);
+            }
+            if evaluation.timed_out() || Worker::evaluation_failed(&evaluation.response) {
+                failure = Some((
+                    evaluation.response_with_failure_policy(
+                        &req.req.uid,
+                        req.policy_id,
+                        &group.failure_policy,
+                    ),
+                    evaluation.error_code(),
+                ));
+                return false;
+            }
+            append_warnings(&mut warnings, evaluation.response.warnings.clone());
+            if !evaluation.response.allowed {
+                let message = evaluation
+                    .response
+                    .status
+                    .and_then(|status| status.message)
+                    .unwrap_or_default();
+                rejections.push(format!("{}: {}", member, message));
+            }
+            evaluation.response.allowed
+        });
+
+        let (evaluation_response, error_code) = match failure {
+            Some(failure) => failure,
+            None if allowed => (
+                AdmissionResponse {
+                    uid: req.req.uid.clone(),
+                    allowed: true,
+                    warnings: (!warnings.is_empty()).then_some(warnings),
+                    ..Default::default()
                 },
-                None => req.resp_chan.send(None),
+                None,
+            ),
+            None => (
+                AdmissionResponse {
+                    uid: req.req.uid.clone(),
+                    allowed: false,
+                    status: Some(AdmissionResponseStatus {
+                        message: Some(group_rejection_message(req.policy_id, group, &rejections)),
+                        code: None,
+                    }),
+                    warnings: (!warnings.is_empty()).then_some(warnings),
+                    ..Default::default()
+                },
+                None,
+            ),
+        };
+        let accepted = evaluation_response.allowed;
+        audit::record(
+            req.policy_id,
+            &effective_mode,
+            req.req,
+            &evaluation_response,
+        );
+        let validation_response = Worker::validation_response_with_constraints(
+            req.policy_id,
+            &effective_mode,
+            false,
+            evaluation_response,
+        );
+        let policy_mode_name: String = effective_mode.into();
+        let validation_response = self.annotate(
+            validation_response,
+            &[
+                ("policy-id", req.policy_id.as_str()),
+                ("policy-mode", policy_mode_name.as_str()),
+            ],
+        );
+        let validation_response = self.apply_exemptions(
+            req.policy_id,
+            &group.exemptions,
+            req.req,
+            validation_response,
+        );
+
+        let policy_evaluation = metrics::PolicyEvaluation {
+            policy_name: req.policy_id.clone(),
+            policy_mode: policy_mode_name,
+            resource_namespace: req.req.namespace.clone(),
+            resource_kind: req.req.request_kind.clone().unwrap_or_default().kind,
+            resource_request_operation: req.req.operation.clone(),
+            accepted,
+            mutated: false,
+            timed_out,
+            error_code,
+            dry_run: req.dry_run(),
+        };
+        metrics::record_policy_latency(start_time.elapsed(), &policy_evaluation);
+        metrics::add_policy_evaluation(&policy_evaluation);
+
+        validation_response
+    }
+
+    // Evaluates the request against the steps of a policy pipeline. Each
+    // step evaluates the object as mutated by the previous ones, the steps
+    // that evaluated an outdated object are evaluated again once all the
+    // patches have been applied. The pipeline fails as soon as one of its
+    // steps cannot evaluate the request
+    fn evaluate_policy_pipeline(
+        &mut self,
+        req: &AdmissionEvalRequest,
+        pipeline: &PolicyPipeline,
+        mut json: serde_json::Value,
+        timed_out_policies: &mut Vec<String>,
+    ) -> AdmissionResponse {
+        let effective_mode = match pipeline
+            .dry_run
+            .policy_mode(&pipeline.policy_mode, req.dry_run())
+        {
+            Some(effective_mode) => effective_mode,
+            None => return Worker::dry_run_response(req),
+        };
+        let start_time = Instant::now();
+        let original_object = json
+            .get("object")
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+        let mut object = original_object.clone();
+        let mut last_mutating_step = None;
+        let mut warnings = Vec::new();
+        let mut outcome: Result<(), PipelineInterruption> = Ok(());
+
+        for (index, step) in pipeline.pipeline.iter().enumerate() {
+            let patch = match self.evaluate_pipeline_step(
+                req,
+                pipeline,
+                step,
+                json.clone(),
+                &mut warnings,
+                timed_out_policies,
+            ) {
+                Ok(Some(patch)) => patch,
+                Ok(None) => continue,
+                Err(interruption) => {
+                    outcome = Err(interruption);
+                    break;
+                }
             };
-            if res.is_err() {
-                error!("receiver dropped");
+            if let Err(e) = json_patch::patch(&mut object, &patch) {
+                outcome = Err(PipelineInterruption::failed(
+                    req,
+                    pipeline,
+                    &format!(
+                        "The patch of the step {} cannot be applied: {}",
+                        step.name, e
+                    ),
+                ));
+                break;
+            }
+            json["object"] = object.clone();
+            last_mutating_step = Some(index);
+        }
+
+        if let (Ok(()), Some(last_mutating_step)) = (&outcome, last_mutating_step) {
+            for step in &pipeline.pipeline[..=last_mutating_step] {
+                match self.evaluate_pipeline_step(
+                    req,
+                    pipeline,
+                    step,
+                    json.clone(),
+                    &mut warnings,
+                    timed_out_policies,
+                ) {
+                    Ok(Some(_)) => debug!(
+                        step = step.name.as_str(),
+                        "the mutated object would be mutated again, ignoring the patch"
+                    ),
+                    Ok(None) => {}
+                    Err(interruption) => {
+                        outcome = Err(interruption);
+                        break;
+                    }
+                }
             }
         }
+
+        let outcome = outcome.and_then(|()| {
+            merged_patch_response(&req.req.uid, &original_object, &object).map_err(|e| {
+                PipelineInterruption::failed(
+                    req,
+                    pipeline,
+                    &format!("The merged patch cannot be built: {}", e),
+                )
+            })
+        });
+        let (evaluation_response, error_code) = match outcome {
+            Ok(response) => (with_warnings(response, warnings), None),
+            Err(PipelineInterruption::Rejected(response)) => {
+                (with_warnings(*response, warnings), None)
+            }
+            Err(PipelineInterruption::Failed(response, error_code)) => (*response, error_code),
+        };
+        let accepted = evaluation_response.allowed;
+        audit::record(
+            req.policy_id,
+            &effective_mode,
+            req.req,
+            &evaluation_response,
+        );
+        let mutated = evaluation_response.patch.is_some();
+        // The pipeline is allowed to mutate: the steps that are not allowed
+        // to do that have already been checked
+        let validation_response = Worker::validation_response_with_constraints(
+            req.policy_id,
+            &effective_mode,
+            true,
+            evaluation_response,
+        );
+        let policy_mode_name: String = effective_mode.into();
+        let validation_response = self.annotate(
+            validation_response,
+            &[
+                ("policy-id", req.policy_id.as_str()),
+                ("policy-mode", policy_mode_name.as_str()),
+            ],
+        );
+        let validation_response = self.apply_exemptions(
+            req.policy_id,
+            &pipeline.exemptions,
+            req.req,
+            validation_response,
+        );
+
+        let policy_evaluation = metrics::PolicyEvaluation {
+            policy_name: req.policy_id.clone(),
+            policy_mode: policy_mode_name,
+            resource_namespace: req.req.namespace.clone(),
+            resource_kind: req.req.request_kind.clone().unwrap_or_default().kind,
+            resource_request_operation: req.req.operation.clone(),
+            accepted,
+            mutated,
+            timed_out: error_code == Some(warp::http::StatusCode::GATEWAY_TIMEOUT.as_u16()),
+            error_code,
+            dry_run: req.dry_run(),
+        };
+        metrics::record_policy_latency(start_time.elapsed(), &policy_evaluation);
+        metrics::add_policy_evaluation(&policy_evaluation);
+
+        validation_response
     }
+
+    // Evaluates a single step of a policy pipeline, returns the patch of
+    // the step when it mutated the object
+    fn evaluate_pipeline_step(
+        &mut self,
+        req: &AdmissionEvalRequest,
+        pipeline: &PolicyPipeline,
+        step: &PipelineStep,
+        json: serde_json::Value,
+        warnings: &mut Vec<String>,
+        timed_out_policies: &mut Vec<String>,
+    ) -> Result<Option<json_patch::Patch>, PipelineInterruption> {
+        let step_id = settings::member_id(req.policy_id, &step.name);
+        let span = info_span!(
+            "pipeline_step",
+            step = step.name.as_str(),
+            policy_id = step_id.as_str()
+        );
+        let _enter = span.enter();
+
+        let evaluation = self.evaluate(&step_id, req.req, json).ok_or_else(|| {
+            PipelineInterruption::failed(
+                req,
+                pipeline,
+                &format!("The policy {} is not loaded.", step.name),
+            )
+        })?;
+        if evaluation.timed_out() {
+            timed_out_policies.push(step_id.clone());
+        }
+        if evaluation.timed_out() || Worker::evaluation_failed(&evaluation.response) {
+            return Err(PipelineInterruption::Failed(
+                Box::new(evaluation.response_with_failure_policy(
+                    &req.req.uid,
+                    req.policy_id,
+                    &pipeline.failure_policy,
+                )),
+                evaluation.error_code(),
+            ));
+        }
+
+        let response = Worker::validation_response_with_constraints(
+            &step_id,
+            &PolicyMode::Protect,
+            step.policy.allowed_to_mutate.unwrap_or(false),
+            evaluation.response,
+        );
+        append_warnings(warnings, response.warnings.clone());
+        if !response.allowed {
+            let status = response.status.unwrap_or_default();
+            return Err(PipelineInterruption::Rejected(Box::new(
+                AdmissionResponse {
+                    uid: req.req.uid.clone(),
+                    allowed: false,
+                    status: Some(AdmissionResponseStatus {
+                        message: Some(format!(
+                            "Request rejected by step {} of policy pipeline {}: {}",
+                            step.name,
+                            req.policy_id,
+                            status.message.unwrap_or_default()
+                        )),
+                        code: status.code,
+                    }),
+                    ..Default::default()
+                },
+            )));
+        }
+
+        match response.patch {
+            Some(patch) => decode_patch(&patch).map(Some).map_err(|e| {
+                PipelineInterruption::failed(
+                    req,
+                    pipeline,
+                    &format!("The patch of the step {} is not valid: {}", step.name, e),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // The response to the dry-run requests the policy accepts without
+    // evaluating them
+    fn dry_run_response(req: &AdmissionEvalRequest) -> AdmissionResponse {
+        debug!(
+            policy_id = req.policy_id.as_str(),
+            "dry-run request accepted without evaluation"
+        );
+        AdmissionResponse {
+            uid: req.req.uid.clone(),
+            allowed: true,
+            ..Default::default()
+        }
+    }
+
+    // If the request matches one of the exemptions of the policy, or one
+    // of the global ones, just set the `allowed` part of the response to
+    // `true`. Keep the rest of the behaviors unchanged, such as checking if
+    // the policy is allowed to mutate.
+    fn apply_exemptions(
+        &self,
+        policy_id: &str,
+        policy_exemptions: &[Exemption],
+        req: &AdmissionRequest,
+        validation_response: AdmissionResponse,
+    ) -> AdmissionResponse {
+        let (exemption, scope) = match exemptions::matching_exemption(policy_exemptions, req) {
+            Some(exemption) => (exemption, "policy"),
+            None => match exemptions::matching_exemption(&self.exemptions, req) {
+                Some(exemption) => (exemption, "global"),
+                None => return validation_response,
+            },
+        };
+        info!(
+            policy_id,
+            scope,
+            exemption = ?exemption,
+            namespace = req.namespace.as_deref().unwrap_or_default(),
+            username = req.user_info.username.as_deref().unwrap_or_default(),
+            "request exempted"
+        );
+        metrics::add_exemption(policy_id, scope);
+
+        self.annotate(
+            AdmissionResponse {
+                allowed: true,
+                ..validation_response
+            },
+            &[("exemption", scope)],
+        )
+    }
+
+    // Adds to the response the audit annotations telling which policy made
+    // the decision. The API server records them in its audit log, prefixed
+    // with the name of the webhook
+    fn annotate(
+        &self,
+        mut response: AdmissionResponse,
+        annotations: &[(&str, &str)],
+    ) -> AdmissionResponse {
+        if let Some(prefix) = &self.audit_annotations_prefix {
+            let audit_annotations = response.audit_annotations.get_or_insert_with(HashMap::new);
+            for (key, value) in annotations {
+                audit_annotations.insert(format!("{}.{}", prefix, key), value.to_string());
+            }
+        }
+        response
+    }
+}
+
+/// A request whose evaluation by a shadow policy is pending
+struct PendingShadow {
+    json: serde_json::Value,
+    policy_mode: PolicyMode,
+    /// The response of the policy shadowed
+    response: AdmissionResponse,
+}
+
+/// The outcome of the evaluation of a request by a single policy
+struct Evaluation {
+    /// The response given by the policy evaluator
+    response: AdmissionResponse,
+    /// Set to the evaluation limit when the evaluation has been interrupted
+    timed_out_after_seconds: Option<u64>,
+    duration: Duration,
+    /// The request has been accepted without evaluating the policy
+    out_of_scope: bool,
+}
+
+impl Evaluation {
+    fn timed_out(&self) -> bool {
+        self.timed_out_after_seconds.is_some()
+    }
+
+    fn error_code(&self) -> Option<u16> {
+        if self.timed_out() {
+            Some(warp::http::StatusCode::GATEWAY_TIMEOUT.as_u16())
+        } else {
+            self.response.status.as_ref().and_then(|status| status.code)
+        }
+    }
+
+    // Returns the response of the policy, errors and timeouts are
+    // handled according to the given failure policy
+    fn response_with_failure_policy(
+        &self,
+        request_uid: &str,
+        policy_id: &str,
+        failure_policy: &FailurePolicy,
+    ) -> AdmissionResponse {
+        match self.timed_out_after_seconds {
+            Some(limit) => Worker::timeout_response(request_uid, policy_id, limit, failure_policy),
+            None if Worker::evaluation_failed(&self.response) => {
+                Worker::error_response(policy_id, failure_policy, &self.response)
+            }
+            None => self.response.clone(),
+        }
+    }
+}
+
+/// Why a policy pipeline stopped before its last step
+enum PipelineInterruption {
+    /// A step rejected the request
+    Rejected(Box<AdmissionResponse>),
+    /// A step could not evaluate the request, the response is built
+    /// according to the failure policy of the pipeline
+    Failed(Box<AdmissionResponse>, Option<u16>),
+}
+
+impl PipelineInterruption {
+    fn failed(
+        req: &AdmissionEvalRequest,
+        pipeline: &PolicyPipeline,
+        reason: &str,
+    ) -> PipelineInterruption {
+        let code = warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16();
+        PipelineInterruption::Failed(
+            Box::new(
+                pipeline
+                    .failure_policy
+                    .response(&req.req.uid, req.policy_id, reason, code),
+            ),
+            Some(code),
+        )
+    }
+}
+
+/// Appends the warnings of a member of a group or of a step of a pipeline
+/// to the ones of the group or of the pipeline. The steps of the pipelines
+/// can be evaluated twice, their warnings are not repeated
+fn append_warnings(warnings: &mut Vec<String>, new_warnings: Option<Vec<String>>) {
+    for warning in new_warnings.into_iter().flatten() {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+/// Adds the warnings to the ones of the response
+fn with_warnings(mut response: AdmissionResponse, warnings: Vec<String>) -> AdmissionResponse {
+    if !warnings.is_empty() {
+        append_warnings(
+            response.warnings.get_or_insert_with(Vec::new),
+            Some(warnings),
+        );
+    }
+    response
+}
+
+/// Decodes the patch of an `AdmissionResponse`, a base64 encoded JSONPatch
+fn decode_patch(patch: &str) -> Result<json_patch::Patch> {
+    let patch = base64::decode(patch)?;
+    Ok(serde_json::from_slice(&patch)?)
+}
+
+/// Builds the response accepting the request, together with the JSONPatch
+/// turning the original object into the mutated one
+fn merged_patch_response(
+    request_uid: &str,
+    original_object: &serde_json::Value,
+    mutated_object: &serde_json::Value,
+) -> Result<AdmissionResponse> {
+    let patch = json_patch::diff(original_object, mutated_object);
+    if patch.0.is_empty() {
+        return Ok(AdmissionResponse {
+            uid: request_uid.to_string(),
+            allowed: true,
+            ..Default::default()
+        });
+    }
+
+    Ok(AdmissionResponse {
+        uid: request_uid.to_string(),
+        allowed: true,
+        patch_type: Some(String::from("JSONPatch")),
+        patch: Some(base64::encode(serde_json::to_string(&patch)?)),
+        ..Default::default()
+    })
+}
+
+/// Builds the message of a request rejected by a policy group, listing
+/// the members that rejected it
+fn group_rejection_message(group_id: &str, group: &PolicyGroup, rejections: &[String]) -> String {
+    let message = group
+        .message
+        .clone()
+        .unwrap_or_else(|| format!("Request rejected by policy group {}", group_id));
+    if rejections.is_empty() {
+        message
+    } else {
+        format!(
+            "{}. Failing policies: {}",
+            message.trim_end_matches('.'),
+            rejections.join("; ")
+        )
+    }
+}
+
+fn build_policy_evaluators(
+    generation: &PoliciesGeneration,
+    engine: &wasmtime::Engine,
+    callback_handler_tx: &Sender<CallbackRequest>,
+    policy_evaluation_limit_seconds: Option<u64>,
+) -> Result<HashMap<String, PolicyEvaluatorWithSettings>, PolicyErrors> {
+    let mut evs_errors = HashMap::new();
+    let mut evs = HashMap::new();
+
+    for (id, policy) in generation.policies.iter() {
+        let policy_evaluator_with_settings = match build_policy_evaluator_with_settings(
+            id,
+            policy,
+            engine,
+            &generation.precompiled_policies,
+            callback_handler_tx.clone(),
+            policy_evaluation_limit_seconds,
+        ) {
+            Ok(pe) => pe,
+            Err(e) => {
+                evs_errors.insert(
+                    id.clone(),
+                    format!("[{}] could not create PolicyEvaluator: {:?}", id, e),
+                );
+                continue;
+            }
+        };
+
+        evs.insert(id.to_string(), policy_evaluator_with_settings);
+    }
+
+    if !evs_errors.is_empty() {
+        return Err(PolicyErrors(evs_errors));
+    }
+
+    Ok(evs)
+}
+
+fn build_policy_evaluator_with_settings(
+    policy_id: &str,
+    policy: &Policy,
+    engine: &wasmtime::Engine,
+    precompiled_policies: &PrecompiledPolicies,
+    callback_handler_tx: Sender<CallbackRequest>,
+    policy_evaluation_limit_seconds: Option<u64>,
+) -> Result<PolicyEvaluatorWithSettings> {
+    let policy_evaluation_limit_seconds =
+        policy.evaluation_limit_seconds(policy_evaluation_limit_seconds);
+    let policy_evaluator = crate::worker_pool::build_policy_evaluator(
+        policy_id,
+        policy,
+        engine,
+        precompiled_policies,
+        callback_handler_tx,
+        policy_evaluation_limit_seconds,
+    )?;
+
+    Ok(PolicyEvaluatorWithSettings {
+        policy_evaluator,
+        policy_mode: policy.policy_mode.clone(),
+        allowed_to_mutate: policy.allowed_to_mutate.unwrap_or(false),
+        failure_policy: policy.failure_policy.clone(),
+        dry_run: policy.dry_run.clone(),
+        exemptions: policy.exemptions.clone(),
+        digest: precompiled_policies
+            .get(&policy.url)
+            .and_then(|precompiled_policy| precompiled_policy.digest.clone()),
+        policy_evaluation_limit_seconds,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use policy_evaluator::{
+        policy_evaluator::PolicyExecutionMode, policy_evaluator_builder::PolicyEvaluatorBuilder,
+    };
 
     const POLICY_ID: &str = "policy-id";
 
+    /// A policy whose evaluation never completes
+    const LOOPING_POLICY: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "__guest_call") (param i32 i32) (result i32)
+    (loop $forever
+      (br $forever))
+    (i32.const 0)))
+"#;
+
+    /// A policy whose evaluation always fails
+    const FAILING_POLICY: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "__guest_call") (param i32 i32) (result i32)
+    (unreachable)))
+"#;
+
+    // Evaluates a request using the given policy, interrupted once
+    // `deadline` epochs have elapsed. The epoch is incremented every
+    // 100 milliseconds while the evaluation is running
+    fn evaluate_wat_policy(policy: &str, deadline: u64) -> AdmissionResponse {
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(true);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        let module = wasmtime::Module::new(&engine, policy).unwrap();
+        let mut policy_evaluator = PolicyEvaluatorBuilder::new(POLICY_ID.to_string())
+            .engine(engine.clone())
+            .policy_module(module)
+            .execution_mode(PolicyExecutionMode::KubewardenWapc)
+            .enable_epoch_interruptions(deadline)
+            .build()
+            .unwrap();
+
+        let (done_tx, done_rx) = crossbeam_channel::bounded::<()>(0);
+        let ticker = std::thread::spawn(move || {
+            while done_rx.recv_timeout(Duration::from_millis(100)).is_err() {
+                engine.increment_epoch();
+            }
+        });
+        let response = policy_evaluator.validate(ValidateRequest::new(serde_json::json!({
+            "uid": "uid",
+        })));
+        drop(done_tx);
+        ticker.join().unwrap();
+
+        response
+    }
+
+    #[test]
+    fn evaluation_timed_out() {
+        let response = evaluate_wat_policy(LOOPING_POLICY, 2);
+        assert!(Worker::evaluation_failed(&response));
+        assert!(Worker::evaluation_timed_out(&response));
+
+        // The guest traps for another reason
+        let response = evaluate_wat_policy(FAILING_POLICY, 2);
+        assert!(Worker::evaluation_failed(&response));
+        assert!(!Worker::evaluation_timed_out(&response));
+
+        assert!(!Worker::evaluation_timed_out(&AdmissionResponse {
+            allowed: true,
+            ..Default::default()
+        }));
+        assert!(!Worker::evaluation_timed_out(&AdmissionResponse {
+            allowed: false,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn timeout_response() {
+        let response = Worker::timeout_response("uid", POLICY_ID, 2, &FailurePolicy::Fail);
+
+        assert_eq!(response.uid, "uid");
+        assert!(!response.allowed);
+        assert_eq!(
+            response.status,
+            Some(AdmissionResponseStatus {
+                message: Some("Request rejected by policy policy-id. The policy evaluation did not complete within 2 seconds.".to_string()),
+                code: Some(504),
+            })
+        );
+
+        assert_eq!(
+            Worker::validation_response_with_constraints(
+                POLICY_ID,
+                &PolicyMode::Monitor,
+                false,
+                response,
+            ),
+            AdmissionResponse {
+                uid: "uid".to_string(),
+                allowed: true,
+                ..Default::default()
+            },
+            "A timed out evaluation should be accepted in monitor mode"
+        );
+
+        let response = Worker::timeout_response("uid", POLICY_ID, 2, &FailurePolicy::Ignore);
+        assert!(response.allowed);
+        assert_eq!(
+            response.warnings,
+            Some(vec!["Request accepted by policy policy-id because of its failure policy. The policy evaluation did not complete within 2 seconds.".to_string()])
+        );
+    }
+
+    #[test]
+    fn error_response() {
+        let failed_evaluation = AdmissionResponse::reject_internal_server_error(
+            "uid".to_string(),
+            "error while evaluating policy: wasm trap: out of bounds memory access".to_string(),
+        );
+        assert!(Worker::evaluation_failed(&failed_evaluation));
+        assert!(!Worker::evaluation_failed(&AdmissionResponse::reject(
+            "uid".to_string(),
+            "privileged containers are not allowed".to_string(),
+            400,
+        )));
+
+        let response = Worker::error_response(POLICY_ID, &FailurePolicy::Fail, &failed_evaluation);
+        assert_eq!(
+            response,
+            AdmissionResponse::reject_internal_server_error(
+                "uid".to_string(),
+                "Request rejected by policy policy-id. The policy evaluation failed: error while evaluating policy: wasm trap: out of bounds memory access".to_string(),
+            )
+        );
+
+        let response =
+            Worker::error_response(POLICY_ID, &FailurePolicy::Ignore, &failed_evaluation);
+        assert_eq!(response.uid, "uid");
+        assert!(response.allowed);
+        assert!(response.status.is_none());
+        assert_eq!(response.warnings.unwrap().len(), 1);
+    }
+
     #[test]
     fn validation_response_with_constraints_not_allowed_to_mutate() {
         let rejection_response = AdmissionResponse {
@@ -412,6 +1676,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn validation_response_with_constraints_warn_mode() {
+        let accepted = AdmissionResponse {
+            allowed: true,
+            patch: Some("patch".to_string()),
+            patch_type: Some("application/json-patch+json".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Worker::validation_response_with_constraints(
+                POLICY_ID,
+                &PolicyMode::Warn,
+                true,
+                accepted.clone(),
+            ),
+            accepted,
+            "Mutated request from a policy allowed to mutate should be mutated in warn mode"
+        );
+
+        assert_eq!(
+            Worker::validation_response_with_constraints(
+                POLICY_ID,
+                &PolicyMode::Warn,
+                false,
+                AdmissionResponse {
+                    allowed: false,
+                    status: Some(AdmissionResponseStatus {
+                        message: Some("some rejection message".to_string()),
+                        code: Some(400),
+                    }),
+                    warnings: Some(vec!["deprecated setting".to_string()]),
+                    ..Default::default()
+                },
+            ),
+            AdmissionResponse {
+                allowed: true,
+                warnings: Some(vec![
+                    "deprecated setting".to_string(),
+                    "Request would be rejected by policy policy-id: some rejection message"
+                        .to_string()
+                ]),
+                ..Default::default()
+            },
+            "Not accepted request should be accepted with a warning in warn mode"
+        );
+
+        let admission_response = Worker::validation_response_with_constraints(
+            POLICY_ID,
+            &PolicyMode::Warn,
+            false,
+            accepted,
+        );
+        assert!(admission_response.allowed);
+        assert!(admission_response.patch.is_none());
+        assert_eq!(admission_response.warnings.unwrap().len(), 1, "Mutated request from a policy not allowed to mutate should be accepted with a warning in warn mode");
+    }
+
     #[test]
     fn validation_response_with_constraints_protect_mode() {
         let admission_response = AdmissionResponse {
@@ -540,4 +1861,114 @@ mod tests {
                 }, "Not accepted request from a policy not allowed to mutate should be rejected in protect mode"
         );
     }
+
+    #[test]
+    fn group_rejection_message() {
+        let mut group: PolicyGroup = serde_yaml::from_str(
+            r#"
+expression: "privileged() && capabilities()"
+policies:
+  privileged:
+    url: file:///tmp/pod-privileged.wasm
+  capabilities:
+    url: file:///tmp/psp-capabilities.wasm
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            super::group_rejection_message("pod-security", &group, &[]),
+            "Request rejected by policy group pod-security"
+        );
+        assert_eq!(
+            super::group_rejection_message(
+                "pod-security",
+                &group,
+                &[
+                    "privileged: privileged containers are not allowed".to_string(),
+                    "capabilities: SYS_ADMIN cannot be added".to_string(),
+                ]
+            ),
+            "Request rejected by policy group pod-security. Failing policies: privileged: privileged containers are not allowed; capabilities: SYS_ADMIN cannot be added"
+        );
+
+        group.message = Some("The pod is not secure.".to_string());
+        assert_eq!(
+            super::group_rejection_message(
+                "pod-security",
+                &group,
+                &["privileged: privileged containers are not allowed".to_string()]
+            ),
+            "The pod is not secure. Failing policies: privileged: privileged containers are not allowed"
+        );
+    }
+
+    #[test]
+    fn merged_patch_response() {
+        let original_object = serde_json::json!({
+            "metadata": {"name": "nginx"},
+            "spec": {"containers": [{"name": "nginx"}]}
+        });
+        let mut object = original_object.clone();
+
+        // The patches of the steps are applied one after the other
+        let add_label = base64::encode(
+            r#"[{"op": "add", "path": "/metadata/labels", "value": {"team": "web"}}]"#,
+        );
+        json_patch::patch(&mut object, &decode_patch(&add_label).unwrap()).unwrap();
+        let set_user = base64::encode(
+            r#"[{"op": "add", "path": "/spec/containers/0/securityContext", "value": {"runAsUser": 1000}}]"#,
+        );
+        json_patch::patch(&mut object, &decode_patch(&set_user).unwrap()).unwrap();
+        assert!(decode_patch("not a patch").is_err());
+
+        let response = super::merged_patch_response("uid", &original_object, &object).unwrap();
+        assert!(response.allowed);
+        assert_eq!(response.patch_type, Some(String::from("JSONPatch")));
+        let mut patched_object = original_object.clone();
+        json_patch::patch(
+            &mut patched_object,
+            &decode_patch(&response.patch.unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(patched_object, object);
+
+        let response =
+            super::merged_patch_response("uid", &original_object, &original_object).unwrap();
+        assert!(response.allowed);
+        assert!(response.patch.is_none());
+        assert!(response.patch_type.is_none());
+    }
+
+    #[test]
+    fn member_warnings() {
+        let mut warnings = Vec::new();
+        append_warnings(&mut warnings, Some(vec!["deprecated field".to_string()]));
+        append_warnings(&mut warnings, None);
+        // The steps of the pipelines evaluated twice repeat their warnings
+        append_warnings(
+            &mut warnings,
+            Some(vec![
+                "deprecated field".to_string(),
+                "image without digest".to_string(),
+            ]),
+        );
+        assert_eq!(warnings, vec!["deprecated field", "image without digest"]);
+
+        let response = with_warnings(
+            AdmissionResponse {
+                allowed: false,
+                warnings: Some(vec!["deprecated field".to_string()]),
+                ..Default::default()
+            },
+            warnings,
+        );
+        assert_eq!(
+            response.warnings.unwrap(),
+            vec!["deprecated field", "image without digest"]
+        );
+
+        let response = with_warnings(AdmissionResponse::default(), Vec::new());
+        assert!(response.warnings.is_none());
+    }
 }
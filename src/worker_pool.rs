@@ -8,23 +8,30 @@ use policy_evaluator::{
 };
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Barrier,
     },
     thread,
     thread::JoinHandle,
+    time::{Duration, Instant},
     vec::Vec,
 };
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info};
 
-use crate::communication::{EvalRequest, WorkerPoolBootRequest};
-use crate::policy_downloader::FetchedPolicies;
-use crate::worker::Worker;
+use crate::communication::{EvalRequest, WorkerPoolBootRequest, WorkerPoolReloadRequest};
+use crate::exemptions::Exemption;
+use crate::metrics;
+use crate::policy_downloader::{FetchedPolicies, FetchedPolicy};
+use crate::settings::{
+    individual_policies, policy_groups, policy_pipelines, shadow_id, shadow_policies, Policy,
+    PolicyEntry, PolicyGroup, PolicyPipeline,
+};
+use crate::shadow_evaluator::{self, ShadowEvaluator};
+use crate::worker::{GenerationMessage, Worker};
 
 /// This structure holds a precompiled WebAssembly module
 /// representing a policy.
@@ -43,12 +50,15 @@ pub(crate) struct PrecompiledPolicy {
 
     /// The execution mode of the policy
     pub execution_mode: PolicyExecutionMode,
+
+    /// The sha256 digest of the WebAssembly module
+    pub digest: Option<String>,
 }
 
 impl PrecompiledPolicy {
     /// Load a WebAssembly module from the disk and compiles it
-    fn new(engine: &wasmtime::Engine, wasm_module_path: &Path) -> Result<Self> {
-        let policy_contents = fs::read(wasm_module_path)?;
+    fn new(engine: &wasmtime::Engine, fetched_policy: &FetchedPolicy) -> Result<Self> {
+        let policy_contents = fs::read(&fetched_policy.local_path)?;
         let policy_metadata = Metadata::from_contents(&policy_contents)?;
         let execution_mode = policy_metadata.unwrap_or_default().execution_mode;
         let precompiled_module = engine.precompile_module(&policy_contents)?;
@@ -56,6 +66,7 @@ impl PrecompiledPolicy {
         Ok(Self {
             precompiled_module,
             execution_mode,
+            digest: fetched_policy.digest.clone(),
         })
     }
 }
@@ -65,30 +76,105 @@ impl PrecompiledPolicy {
 /// * value: the PrecompiledPolicy
 pub(crate) type PrecompiledPolicies = HashMap<String, PrecompiledPolicy>;
 
+/// The set of policies loaded by the workers, together with the
+/// WebAssembly modules they reference.
+///
+/// A new generation is built by the worker pool each time the policies
+/// are reloaded. Workers build its evaluators between two requests, and
+/// switch to it once all of them succeeded. Requests that are already
+/// being evaluated complete using the previous generation.
+pub(crate) struct PoliciesGeneration {
+    /// All the policies to be evaluated, including the members of the
+    /// policy groups and the steps of the pipelines
+    pub policies: HashMap<String, Policy>,
+    pub groups: HashMap<String, PolicyGroup>,
+    pub pipelines: HashMap<String, PolicyPipeline>,
+    pub precompiled_policies: PrecompiledPolicies,
+    /// The WebAssembly modules of the shadows of the policies, compiled
+    /// for the engine of the [`ShadowEvaluator`]
+    pub precompiled_shadows: PrecompiledPolicies,
+}
+
+/// How often the epoch of the `wasmtime::Engine` is incremented when
+/// policy timeout protection is enabled. Epoch deadlines are expressed
+/// as a number of ticks, hence a tick must last one second.
+const EPOCH_TICK: Duration = Duration::from_secs(1);
+
+/// Increments the epoch of a `wasmtime::Engine` every `tick`. The ticker
+/// thread is stopped when the ticker is dropped
+pub(crate) struct EpochTicker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EpochTicker {
+    pub(crate) fn start(engine: wasmtime::Engine, tick: Duration) -> EpochTicker {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ticker_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut next_tick = Instant::now() + tick;
+            while !ticker_stop.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now < next_tick {
+                    thread::park_timeout(next_tick - now);
+                    continue;
+                }
+                engine.increment_epoch();
+                next_tick += tick;
+            }
+        });
+
+        EpochTicker {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            if handle.join().is_err() {
+                error!("epoch ticker thread panicked");
+            }
+        }
+    }
+}
+
 pub(crate) struct WorkerPool {
     api_rx: mpsc::Receiver<EvalRequest>,
     bootstrap_rx: oneshot::Receiver<WorkerPoolBootRequest>,
+    reload_rx: mpsc::Receiver<WorkerPoolReloadRequest>,
     callback_handler_tx: mpsc::Sender<CallbackRequest>,
-    always_accept_admission_reviews_on_namespace: Option<String>,
+    exemptions: Vec<Exemption>,
+    audit_annotations_prefix: Option<String>,
+    policy_evaluation_limit_seconds: Option<u64>,
 }
 
 impl WorkerPool {
     pub(crate) fn new(
         bootstrap_rx: oneshot::Receiver<WorkerPoolBootRequest>,
+        reload_rx: mpsc::Receiver<WorkerPoolReloadRequest>,
         api_rx: mpsc::Receiver<EvalRequest>,
         callback_handler_tx: mpsc::Sender<CallbackRequest>,
-        always_accept_admission_reviews_on_namespace: Option<String>,
+        exemptions: Vec<Exemption>,
+        audit_annotations_prefix: Option<String>,
+        policy_evaluation_limit_seconds: Option<u64>,
     ) -> WorkerPool {
         WorkerPool {
             api_rx,
             bootstrap_rx,
+            reload_rx,
             callback_handler_tx,
-            always_accept_admission_reviews_on_namespace,
+            exemptions,
+            audit_annotations_prefix,
+            policy_evaluation_limit_seconds,
         }
     }
 
     pub(crate) fn run(mut self) {
-        let mut worker_tx_chans = Vec::<mpsc::Sender<EvalRequest>>::new();
         let mut join_handles = Vec::<JoinHandle<Result<()>>>::new();
 
         // Phase 1: wait for bootstrap data to be received by the main
@@ -108,8 +194,10 @@ impl WorkerPool {
 
         // To reduce bootstrap time, we will precompile all the WebAssembly
         // modules we are going to use.
-        let wasmtime_config = wasmtime::Config::new();
-        // TODO: enable epoch deadlines
+        let mut wasmtime_config = wasmtime::Config::new();
+        if self.policy_evaluation_limit_seconds.is_some() {
+            wasmtime_config.epoch_interruption(true);
+        }
 
         let engine = match wasmtime::Engine::new(&wasmtime_config) {
             Ok(e) => e,
@@ -121,58 +209,108 @@ impl WorkerPool {
                 std::process::exit(1);
             }
         };
+        // The shadow policies are evaluated by an engine of their own,
+        // which always enforces their time budget
+        let shadow_engine = match shadow_evaluator::build_engine() {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!(
+                    "workers pool bootstrap: cannot instantiate `wasmtime::Engine` of the shadow policies: {:?}",
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
 
-        let precompiled_policies =
-            match precompile_policies(&engine, &bootstrap_data.fetched_policies) {
-                Ok(pp) => pp,
-                Err(e) => {
-                    eprintln!("{}", e);
-                    std::process::exit(1);
-                }
-            };
+        // All the workers share the same `wasmtime::Engine`, a single
+        // ticker is enough to drive the epoch deadlines of all the policy
+        // evaluations. The ticker lives as long as the worker pool
+        let _epoch_ticker = self
+            .policy_evaluation_limit_seconds
+            .map(|_| EpochTicker::start(engine.clone(), EPOCH_TICK));
 
-        if let Err(error) = verify_policy_settings(
+        let generation = match load_policies_generation(
             &engine,
-            &bootstrap_data.policies,
-            &precompiled_policies,
+            &shadow_engine,
+            None,
+            bootstrap_data.policies,
+            &bootstrap_data.fetched_policies,
             self.callback_handler_tx.clone(),
+            self.policy_evaluation_limit_seconds,
         ) {
-            error!(?error, "cannot validate policy settings");
-            match bootstrap_data.resp_chan.send(Err(error)) {
-                Ok(_) => return,
-                Err(_) => {
-                    eprint!("worker bootstrap: cannot send back failure through channel");
-                    std::process::exit(1);
-                }
-            };
-        }
+            Ok(generation) => generation,
+            Err(error) => {
+                error!(?error, "cannot load policies");
+                match bootstrap_data.resp_chan.send(Err(error)) {
+                    Ok(_) => return,
+                    Err(_) => {
+                        eprint!("worker bootstrap: cannot send back failure through channel");
+                        std::process::exit(1);
+                    }
+                };
+            }
+        };
 
         let pool_size: usize = bootstrap_data.pool_size;
         let barrier = Arc::new(Barrier::new(pool_size + 1));
         let boot_canary = Arc::new(AtomicBool::new(true));
 
-        for n in 1..=pool_size {
-            let (tx, rx) = mpsc::channel::<EvalRequest>(32);
-            worker_tx_chans.push(tx);
+        let mut generation_txs = Vec::with_capacity(pool_size);
+
+        // All the workers pull requests from the same queue: a request is
+        // evaluated by the first worker that becomes idle, instead of waiting
+        // behind a slow evaluation while other workers have nothing to do
+        let (work_tx, work_rx) = crossbeam_channel::bounded::<EvalRequest>(
+            pool_size * bootstrap_data.queue_size_per_worker,
+        );
+
+        // Once answered, the requests evaluated by a policy with a shadow
+        // are handed over to the shadow evaluator. Its queue is as large as
+        // the one of a worker, further shadow evaluations are skipped
+        let (shadows_tx, shadows_rx) =
+            crossbeam_channel::bounded(bootstrap_data.queue_size_per_worker);
+        let (shadow_generations_tx, shadow_generations_rx) = crossbeam_channel::unbounded();
+        let shadow_generation = generation.clone();
+        let evaluator_engine = shadow_engine.clone();
+        let callback_handler_tx = self.callback_handler_tx.clone();
+        let shadow_join = thread::spawn(move || {
+            ShadowEvaluator::new(
+                shadows_rx,
+                shadow_generation,
+                shadow_generations_rx,
+                evaluator_engine,
+                callback_handler_tx,
+            )
+            .run();
+        });
 
-            let policies = bootstrap_data.policies.clone();
-            let modules = precompiled_policies.clone();
-            let wasmtime_config = wasmtime_config.clone();
+        for n in 1..=pool_size {
+            let rx = work_rx.clone();
+            let worker_generation = generation.clone();
+            let (generation_tx, generations_rx) = crossbeam_channel::unbounded();
+            generation_txs.push(generation_tx);
+            // It's safe to clone the engine. This creates a shallow copy
+            let engine = engine.clone();
+            let shadows_tx = shadows_tx.clone();
             let b = barrier.clone();
             let canary = boot_canary.clone();
             let callback_handler_tx = self.callback_handler_tx.clone();
-            let always_accept_admission_reviews_on_namespace =
-                self.always_accept_admission_reviews_on_namespace.clone();
+            let exemptions = self.exemptions.clone();
+            let audit_annotations_prefix = self.audit_annotations_prefix.clone();
+            let policy_evaluation_limit_seconds = self.policy_evaluation_limit_seconds;
 
             let join = thread::spawn(move || -> Result<()> {
                 info!(spawned = n, total = pool_size, "spawning worker");
                 let worker = match Worker::new(
                     rx,
-                    &policies,
-                    &modules,
-                    &wasmtime_config,
+                    worker_generation,
+                    generations_rx,
+                    engine,
+                    shadows_tx,
                     callback_handler_tx,
-                    always_accept_admission_reviews_on_namespace,
+                    exemptions,
+                    audit_annotations_prefix,
+                    policy_evaluation_limit_seconds,
                 ) {
                     Ok(w) => w,
                     Err(e) => {
@@ -192,6 +330,7 @@ impl WorkerPool {
             });
             join_handles.push(join);
         }
+        drop(shadows_tx);
         barrier.wait();
 
         if !boot_canary.load(Ordering::SeqCst) {
@@ -214,29 +353,127 @@ impl WorkerPool {
         }
 
         // Phase 2: the worker pool has been successfully bootstraped.
-        // We can start waiting for admission review requests to be evaluated
-        let mut next_worker_id = 0;
+        // Spawn the thread that takes care of reloading the policies
+        // when asked to
+        let mut reload_rx = self.reload_rx;
+        let callback_handler_tx = self.callback_handler_tx.clone();
+        let policy_evaluation_limit_seconds = self.policy_evaluation_limit_seconds;
+        thread::spawn(move || {
+            let mut current_generation = generation;
+            while let Some(reload_data) = reload_rx.blocking_recv() {
+                let res = load_policies_generation(
+                    &engine,
+                    &shadow_engine,
+                    Some(&current_generation),
+                    reload_data.policies,
+                    &reload_data.fetched_policies,
+                    callback_handler_tx.clone(),
+                    policy_evaluation_limit_seconds,
+                )
+                .and_then(|new_generation| {
+                    switch_workers_generation(&generation_txs, &new_generation)?;
+                    if shadow_generations_tx.send(new_generation.clone()).is_err() {
+                        error!("policies reload: the shadow evaluator is gone");
+                    }
+                    current_generation = new_generation;
+                    Ok(())
+                });
+                if let Err(error) = &res {
+                    error!(?error, "cannot reload policies");
+                }
+                if reload_data.resp_chan.send(res).is_err() {
+                    error!("policies reload: cannot send back result through channel");
+                }
+            }
+        });
 
+        // Phase 3: start waiting for admission review requests to be evaluated
+        drop(work_rx);
         while let Some(req) = self.api_rx.blocking_recv() {
-            let _ = worker_tx_chans[next_worker_id].blocking_send(req);
-            next_worker_id += 1;
-            if next_worker_id >= pool_size {
-                next_worker_id = 0;
+            metrics::record_dequeued_request(metrics::Queue::Api);
+            metrics::record_enqueued_request(metrics::Queue::Workers);
+            if work_tx.send(req).is_err() {
+                error!("all the workers are gone, cannot evaluate requests");
+                break;
             }
         }
 
+        // The API channel has been closed, let the workers drain the
+        // queue and exit
+        drop(work_tx);
         for handle in join_handles {
             handle.join().unwrap().unwrap();
         }
+        // The shadow evaluator exits once all the workers are gone
+        if shadow_join.join().is_err() {
+            error!("shadow evaluator thread panicked");
+        }
     }
 }
 
+/// Switches all the workers to the given generation of policies. The
+/// workers prepare the generation one after the other, to keep the other
+/// ones evaluating requests meanwhile. The generation is committed only
+/// when all of them succeeded, otherwise they keep using the current one.
+fn switch_workers_generation(
+    generation_txs: &[crossbeam_channel::Sender<GenerationMessage>],
+    generation: &Arc<PoliciesGeneration>,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for (n, generation_tx) in generation_txs.iter().enumerate() {
+        let (prepared_tx, prepared_rx) = crossbeam_channel::bounded(1);
+        let outcome = generation_tx
+            .send(GenerationMessage::Prepare(generation.clone(), prepared_tx))
+            .map_err(|_| String::from("worker is gone"))
+            .and_then(|()| {
+                prepared_rx
+                    .recv()
+                    .map_err(|_| String::from("worker is gone"))?
+            });
+        if let Err(e) = outcome {
+            errors.push(format!("[worker {}: {}]", n + 1, e));
+            break;
+        }
+    }
+    if !errors.is_empty() {
+        for generation_tx in generation_txs {
+            let _ = generation_tx.send(GenerationMessage::Abort);
+        }
+        return Err(anyhow!(
+            "the workers cannot load the new policies: {}",
+            errors.join(", ")
+        ));
+    }
+
+    let (committed_tx, committed_rx) = crossbeam_channel::bounded(generation_txs.len());
+    for generation_tx in generation_txs {
+        if generation_tx
+            .send(GenerationMessage::Commit(committed_tx.clone()))
+            .is_err()
+        {
+            error!("policies reload: a worker is gone, cannot commit new policies");
+        }
+    }
+    drop(committed_tx);
+    // Wait for all the workers to switch, the channel is closed once all
+    // of them handled the message
+    while committed_rx.recv().is_ok() {}
+
+    Ok(())
+}
+
+/// Build a `PolicyEvaluator` from a precompiled module.
+///
+/// When `epoch_deadline` is set, the evaluator is interrupted once the
+/// given number of epochs has elapsed. The `engine` must have been created
+/// with epoch interruption enabled in this case.
 pub(crate) fn build_policy_evaluator(
     policy_id: &str,
-    policy: &crate::settings::Policy,
+    policy: &Policy,
     engine: &wasmtime::Engine,
     policy_modules: &PrecompiledPolicies,
     callback_handler_tx: mpsc::Sender<CallbackRequest>,
+    epoch_deadline: Option<u64>,
 ) -> Result<PolicyEvaluator> {
     let policy_module = policy_modules.get(policy.url.as_str()).ok_or_else(|| {
         anyhow!(
@@ -259,13 +496,18 @@ pub(crate) fn build_policy_evaluator(
                 )
             })?;
 
-    let policy_evaluator_builder = PolicyEvaluatorBuilder::new(policy_id.to_string())
+    let mut policy_evaluator_builder = PolicyEvaluatorBuilder::new(policy_id.to_string())
         .engine(engine.clone())
         .policy_module(module)
         .settings(policy.settings_to_json()?)
         .callback_channel(callback_handler_tx)
         .execution_mode(policy_module.execution_mode);
 
+    if let Some(epoch_deadline) = epoch_deadline {
+        policy_evaluator_builder =
+            policy_evaluator_builder.enable_epoch_interruptions(epoch_deadline);
+    }
+
     policy_evaluator_builder.build()
 }
 
@@ -280,8 +522,8 @@ fn precompile_policies(
 
     let precompiled_policies: HashMap<String, Result<PrecompiledPolicy>> = fetched_policies
         .par_iter()
-        .map(|(policy_url, wasm_module_path)| {
-            let precompiled_policy = PrecompiledPolicy::new(engine, wasm_module_path);
+        .map(|(policy_url, fetched_policy)| {
+            let precompiled_policy = PrecompiledPolicy::new(engine, fetched_policy);
             debug!(?policy_url, "module compiled");
             (policy_url.clone(), precompiled_policy)
         })
@@ -313,21 +555,112 @@ fn precompile_policies(
         .collect())
 }
 
-fn verify_policy_settings(
+/// Compiles the WebAssembly modules used by the given policies. The modules
+/// of `current_modules` are reused, unless their digest changed.
+fn precompile_modules(
+    engine: &wasmtime::Engine,
+    policies: &HashMap<String, Policy>,
+    fetched_policies: &FetchedPolicies,
+    current_modules: Option<&PrecompiledPolicies>,
+) -> Result<PrecompiledPolicies> {
+    let urls: HashSet<&String> = policies.values().map(|policy| &policy.url).collect();
+    let reusable = |url: &String, fetched_policy: &FetchedPolicy| {
+        current_modules
+            .and_then(|modules| modules.get(url))
+            .filter(|precompiled_policy| {
+                precompiled_policy.digest.is_some()
+                    && precompiled_policy.digest == fetched_policy.digest
+            })
+            .cloned()
+    };
+    let used_modules = fetched_policies
+        .iter()
+        .filter(|(url, _)| urls.contains(url));
+    let new_modules: FetchedPolicies = used_modules
+        .clone()
+        .filter(|(url, fetched_policy)| reusable(url, fetched_policy).is_none())
+        .map(|(url, fetched_policy)| (url.clone(), fetched_policy.clone()))
+        .collect();
+
+    let mut precompiled_policies = precompile_policies(engine, &new_modules)?;
+    for (url, fetched_policy) in used_modules {
+        if let Some(precompiled_policy) = reusable(url, fetched_policy) {
+            precompiled_policies.insert(url.clone(), precompiled_policy);
+        }
+    }
+    Ok(precompiled_policies)
+}
+
+/// Build a new generation of policies. Only the WebAssembly modules that are
+/// not part of the current generation, if any, or whose digest changed, are
+/// compiled. The modules of the shadow policies are compiled using
+/// `shadow_engine`. The settings of all the policies are validated before
+/// the new generation is returned.
+fn load_policies_generation(
     engine: &wasmtime::Engine,
-    policies: &HashMap<String, crate::settings::Policy>,
-    policy_modules: &HashMap<String, PrecompiledPolicy>,
+    shadow_engine: &wasmtime::Engine,
+    current_generation: Option<&PoliciesGeneration>,
+    policies: HashMap<String, PolicyEntry>,
+    fetched_policies: &FetchedPolicies,
     callback_handler_tx: mpsc::Sender<CallbackRequest>,
-) -> Result<()> {
-    let mut errors = vec![];
-    for (id, policy) in policies.iter() {
-        let mut policy_evaluator = match build_policy_evaluator(
+    policy_evaluation_limit_seconds: Option<u64>,
+) -> Result<Arc<PoliciesGeneration>> {
+    let groups = policy_groups(&policies);
+    let pipelines = policy_pipelines(&policies);
+    let policies = individual_policies(&policies);
+    let precompiled_policies = precompile_modules(
+        engine,
+        &policies,
+        fetched_policies,
+        current_generation.map(|generation| &generation.precompiled_policies),
+    )?;
+    let precompiled_shadows = precompile_modules(
+        shadow_engine,
+        &shadow_policies(&policies),
+        fetched_policies,
+        current_generation.map(|generation| &generation.precompiled_shadows),
+    )?;
+
+    verify_policy_settings(policies.iter().map(|(id, policy)| {
+        (
+            id.clone(),
+            build_policy_evaluator(
+                id,
+                policy,
+                engine,
+                &precompiled_policies,
+                callback_handler_tx.clone(),
+                policy.evaluation_limit_seconds(policy_evaluation_limit_seconds),
+            ),
+        )
+    }))?;
+    verify_policy_settings(policies.iter().filter_map(|(id, policy)| {
+        shadow_evaluator::build_shadow_evaluator(
             id,
             policy,
-            engine,
-            policy_modules,
+            shadow_engine,
+            &precompiled_shadows,
             callback_handler_tx.clone(),
-        ) {
+        )
+        .map(|policy_evaluator| (shadow_id(id), policy_evaluator))
+    }))?;
+
+    Ok(Arc::new(PoliciesGeneration {
+        policies,
+        groups,
+        pipelines,
+        precompiled_policies,
+        precompiled_shadows,
+    }))
+}
+
+/// Validates the settings of the policies, using their evaluators
+fn verify_policy_settings(
+    policy_evaluators: impl Iterator<Item = (String, Result<PolicyEvaluator>)>,
+) -> Result<()> {
+    let mut errors = vec![];
+    for (id, policy_evaluator) in policy_evaluators {
+        let mut policy_evaluator = match policy_evaluator {
             Ok(pe) => pe,
             Err(e) => {
                 errors.push(format!("[{}] cannot create PolicyEvaluator: {:?}", id, e));